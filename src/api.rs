@@ -0,0 +1,66 @@
+//! The base urls of the twitch apis.
+//!
+//! They can be pointed elsewhere before the first request is made, such as at a mock server in tests.
+
+use once_cell::sync::OnceCell;
+
+static ENDPOINTS: OnceCell<Endpoints> = OnceCell::new();
+
+#[derive(Clone, Debug)]
+pub struct Endpoints {
+    /// The helix api, which `streams`, `users`, `videos` and `eventsub/subscriptions` are below.
+    pub helix: String,
+    /// The oauth2 endpoints, which `token`, `validate` and `device` are below.
+    pub id: String,
+    /// The GQL api the internal extractor requests playback tokens from.
+    pub gql: String,
+    /// The host of the master playlists.
+    pub usher: String,
+    /// The eventsub websocket.
+    pub eventsub_ws: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            helix: "https://api.twitch.tv/helix".to_owned(),
+            id: "https://id.twitch.tv/oauth2".to_owned(),
+            gql: "https://gql.twitch.tv/gql".to_owned(),
+            usher: "http://usher.ttvnw.net".to_owned(),
+            eventsub_ws: "wss://eventsub.wss.twitch.tv/ws".to_owned(),
+        }
+    }
+}
+
+/// Sets the urls every twitch api request is sent to.
+/// Has no effect once a request has been made.
+pub fn set_endpoints(endpoints: Endpoints) {
+    let _ = ENDPOINTS.set(endpoints);
+}
+
+pub fn endpoints() -> &'static Endpoints {
+    ENDPOINTS.get_or_init(Endpoints::default)
+}
+
+/// The url of the helix endpoint at `path`.
+pub(crate) fn helix(path: &str) -> String {
+    format!("{}/{path}", endpoints().helix.trim_end_matches('/'))
+}
+
+/// The url of the oauth2 endpoint at `path`.
+pub(crate) fn id(path: &str) -> String {
+    format!("{}/{path}", endpoints().id.trim_end_matches('/'))
+}
+
+pub(crate) fn gql() -> &'static str {
+    &endpoints().gql
+}
+
+/// The url of the playlist at `path` on the usher host.
+pub(crate) fn usher(path: &str) -> String {
+    format!("{}/{path}", endpoints().usher.trim_end_matches('/'))
+}
+
+pub(crate) fn eventsub_ws() -> &'static str {
+    &endpoints().eventsub_ws
+}
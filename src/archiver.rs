@@ -0,0 +1,1568 @@
+use anyhow::Context;
+use async_lock::{RwLock, Semaphore};
+use async_recursion::async_recursion;
+use async_std::{
+    channel, fs,
+    io::{self, WriteExt},
+    path,
+    sync::Arc,
+    task,
+};
+use chrono::{DateTime, Local};
+use core::time;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either},
+    StreamExt, TryStreamExt,
+};
+use regex::Regex;
+
+use crate::{
+    checksum::{self, Checksums, HashWriter, SUMS_FILE},
+    filename::Formatter,
+    fs_utils::{self, san},
+    helix::{Stream, User},
+    hls,
+    irc::IrcRecv,
+    events::{self, Event},
+    live, lock, metrics, notify,
+    prelude::*,
+    rand, recover, retry, s3,
+};
+
+const CHAT_BUFFER: usize = 16384;
+const CHAT_OPEN_RETRY: usize = 5;
+const CHAT_OPEN_DELAY: time::Duration = time::Duration::from_secs(2);
+/// The file cheers, subscriptions and raids are written to, next to the chat log.
+const EVENTS_FILE: &str = "events.jsonl";
+/// The file chat is written to as WebVTT subtitles, next to the chat log.
+const VTT_FILE: &str = "chat.vtt";
+/// How long each chat message is shown in the WebVTT subtitles.
+const VTT_CUE_DURATION: time::Duration = time::Duration::from_secs(5);
+/// Written to the output when the video could not be fully downloaded, containing the error.
+const INCOMPLETE_FILE: &str = "INCOMPLETE";
+const RAND_DIR_LEN: usize = 12;
+const ASYNC_BUF_FACTOR: usize = 64;
+const SPACE_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(60);
+/// The number of progress reports held for `--events-json`,
+/// after which only the latest report is kept until the events catch up.
+const PROGRESS_BUFFER: usize = 16;
+
+/// Held for reading by each running post-hook.
+static HOOKS: RwLock<()> = RwLock::new(());
+
+#[derive(Debug)]
+pub enum Extractor {
+    Internal,
+    Streamlink
+}
+
+/// How a finished download is stored.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// The download directory is moved to the output path.
+    Dir,
+    /// The download directory is archived to a tar archive.
+    Tar,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The extension of a tar archive compressed with this method.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::None => "tar",
+            Self::Gzip => "tar.gz",
+            Self::Zstd => "tar.zst",
+        }
+    }
+}
+
+/// The metadata files written alongside the stream.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Metadata {
+    /// Only `info.json`.
+    #[default]
+    Json,
+    /// `info.json`, and a `movie.nfo` for media servers such as Kodi, Plex and Jellyfin.
+    Nfo,
+}
+
+/// How each line of `chat.log` is timestamped.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatTimestamp {
+    /// Lines are written as the raw IRC message.
+    #[default]
+    None,
+    /// Lines are prefixed with the local time the message was received.
+    Wallclock,
+    /// Lines are prefixed with the seconds elapsed since the stream started.
+    Relative,
+}
+
+/// The format `chat_log` writes chat in.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatFormat {
+    /// Every raw IRC line, written to `chat.log`.
+    #[default]
+    Raw,
+    /// Chat messages as newline-delimited JSON objects, written to `chat.jsonl`.
+    Json,
+}
+
+impl ChatFormat {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::Raw => "chat.log",
+            Self::Json => "chat.jsonl",
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ChannelSettings {
+    /// The renditions to download, each a comma separated list of formats tried in order.
+    #[serde(deserialize_with = "one_or_many")]
+    pub format: Vec<String>,
+    pub segment_concurrency: usize,
+    pub segment_timeout_secs: u64,
+    pub single_file: bool,
+    pub chat_format: ChatFormat,
+    /// Only applies to the raw chat format.
+    pub chat_timestamp: ChatTimestamp,
+    /// Also writes chat as WebVTT subtitles, timed from the start of the stream.
+    pub chat_vtt: bool,
+    /// Formats the output path of this channel's streams, in place of [`ArchiverSettings::formatter`].
+    pub filename_format: Option<Arc<Formatter>>,
+    /// Stores this channel's streams as given, in place of [`ArchiverSettings::save_to_dir`].
+    pub output: Option<Output>,
+    /// Leaves out the ads stitched into the stream.
+    pub skip_ads: bool,
+    /// Ends the download after this many consecutive empty or failed playlist polls,
+    /// rather than only after the segment timeout.
+    pub max_empty_polls: Option<u32>,
+    /// Only archives streams in one of these games, given by name or id.
+    pub only_games: Vec<String>,
+    /// Only archives streams in one of these languages, given as ISO 639-1 codes.
+    pub only_languages: Vec<String>,
+    /// Only archives streams whose title matches.
+    #[serde(deserialize_with = "title_regex")]
+    pub title_regex: Option<Regex>,
+}
+
+fn title_regex<'de, D: serde::Deserializer<'de>>(de: D) -> core::result::Result<Option<Regex>, D::Error> {
+    let Some(x) = Option::<String>::deserialize(de)? else {
+        return Ok(None);
+    };
+    Regex::new(&x)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("title regex {x:?} is invalid: {e}")))
+}
+
+impl ChannelSettings {
+    /// Checks `stream` against the predicates of this channel,
+    /// returning why it should not be archived.
+    pub fn skip_reason(&self, stream: &Stream) -> Option<String> {
+        let in_games = self.only_games.iter().any(|x| {
+            x.eq_ignore_ascii_case(stream.game_name()) || x == stream.game_id()
+        });
+        if !self.only_games.is_empty() && !in_games {
+            return Some(format!("game {:?} is not in `only_games`", stream.game_name()));
+        }
+
+        let in_languages = self
+            .only_languages
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(stream.language()));
+        if !self.only_languages.is_empty() && !in_languages {
+            return Some(format!("language {:?} is not in `only_languages`", stream.language()));
+        }
+
+        match &self.title_regex {
+            Some(x) if !x.is_match(stream.title()) => {
+                Some(format!("title {:?} does not match `title_regex`", stream.title()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Accepts either a single string, or a list of strings.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(de: D) -> core::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(de)? {
+        OneOrMany::One(x) => vec![x],
+        OneOrMany::Many(x) => x,
+    })
+}
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            format: vec!["best".to_owned()],
+            segment_concurrency: hls::SEGMENT_CONCURRENCY,
+            segment_timeout_secs: hls::SEGMENT_TIMEOUT.as_secs(),
+            single_file: false,
+            chat_format: ChatFormat::Raw,
+            chat_timestamp: ChatTimestamp::None,
+            chat_vtt: false,
+            filename_format: None,
+            output: None,
+            skip_ads: true,
+            max_empty_polls: None,
+            only_games: Vec::new(),
+            only_languages: Vec::new(),
+            title_regex: None,
+        }
+    }
+}
+
+/// Settings for archiving streams, shared by every download of an [`Archiver`].
+#[derive(Debug)]
+pub struct ArchiverSettings {
+    /// Formats the output path of each stream.
+    pub formatter: Formatter,
+    /// The directory streams are downloaded to before being archived.
+    pub temp_dir: path::PathBuf,
+    /// Downloads are not started, and running ones are stopped,
+    /// when less space is free in `temp_dir`.
+    pub min_free_bytes: Option<u64>,
+    /// Saves the output to a directory, instead of a tar archive.
+    pub save_to_dir: bool,
+    pub compression: Compression,
+    pub metadata: Metadata,
+    pub extractor: Extractor,
+    /// The header passed to streamlink's `--twitch-api-header`, such as `Authorization=OAuth <token>`.
+    pub stream_auth: Option<String>,
+    /// The OAuth token the internal extractor sends to the twitch GQL api,
+    /// which allows sub-only streams of the subscribed user to be archived.
+    pub twitch_oauth: Option<String>,
+    /// A user access token, used in place of `twitch_oauth` and `stream_auth` when those are not set.
+    pub user_token: Option<String>,
+    /// The container format downloads are remuxed to with ffmpeg.
+    pub remux: Option<String>,
+    /// Uploads archives to an S3-compatible bucket.
+    pub upload: Option<s3::Destination>,
+    /// Limits the number of streams whose video is being downloaded at once.
+    pub max_concurrent: Option<usize>,
+    /// Limits the number of downloads being finalized at once.
+    pub max_concurrent_finalize: Option<usize>,
+    pub adaptive_concurrency: bool,
+    /// A program run after each download is archived.
+    pub post_hook: Option<String>,
+    /// Archives the chat log of a live stream even when no video could be downloaded.
+    pub keep_chat: bool,
+    /// Continues the download directory a previous run left behind for the same stream.
+    pub resume: bool,
+    /// Appends the stream id to output paths whose format does not contain it.
+    pub unique_names: bool,
+    /// Names segment files by their media sequence number.
+    pub sequence_names: bool,
+}
+
+impl ArchiverSettings {
+    pub fn new(formatter: Formatter) -> Self {
+        Self {
+            formatter,
+            temp_dir: path::PathBuf::from(".download"),
+            min_free_bytes: None,
+            save_to_dir: false,
+            compression: Compression::None,
+            metadata: Metadata::Json,
+            extractor: Extractor::Internal,
+            stream_auth: None,
+            twitch_oauth: None,
+            user_token: None,
+            remux: None,
+            upload: None,
+            max_concurrent: None,
+            max_concurrent_finalize: None,
+            adaptive_concurrency: false,
+            post_hook: None,
+            keep_chat: false,
+            resume: false,
+            unique_names: false,
+            sequence_names: false,
+        }
+    }
+
+    /// Formats the output path of `stream` with `formatter`.
+    pub fn filename(&self, formatter: &Formatter, stream: &Stream) -> String {
+        match self.unique_names {
+            true => formatter.format_unique(stream),
+            false => formatter.format(stream),
+        }
+    }
+}
+
+/// The title and category of a live stream, from the time it changed.
+#[derive(Clone, Debug, Serialize)]
+pub struct StreamUpdate {
+    pub timestamp: DateTime<Local>,
+    pub title: Box<str>,
+    pub game_id: Box<str>,
+    pub game_name: Box<str>,
+}
+
+impl From<&Stream> for StreamUpdate {
+    /// The state of the stream when it started.
+    fn from(value: &Stream) -> Self {
+        Self {
+            timestamp: value.started_at(),
+            title: value.title().into(),
+            game_id: value.game_id().into(),
+            game_name: value.game_name().into(),
+        }
+    }
+}
+
+/// Where a download is archived from.
+pub enum Source {
+    /// A live stream, archived along with its chat.
+    /// Updates received while downloading are recorded in the datafile.
+    Live(IrcRecv, Option<channel::Receiver<StreamUpdate>>),
+    /// A published VOD, identified by the stream's id.
+    Vod,
+}
+
+/// Downloads streams, and finalizes them into archives.
+#[derive(Debug)]
+pub struct Archiver {
+    settings: ArchiverSettings,
+    download_limit: Option<Semaphore>,
+    finalize_limit: Option<Semaphore>,
+}
+
+impl Archiver {
+    pub fn new(settings: ArchiverSettings) -> Self {
+        Self {
+            download_limit: settings.max_concurrent.map(Semaphore::new),
+            finalize_limit: settings.max_concurrent_finalize.map(Semaphore::new),
+            settings,
+        }
+    }
+
+    pub fn settings(&self) -> &ArchiverSettings {
+        &self.settings
+    }
+
+    /// Downloads the stream to the path given by the channel's formatter, or the default one.
+    /// The download stops when `stop` receives a value, or the stream ends.
+    pub async fn download(
+        &self,
+        stream: Stream,
+        source: Source,
+        chn: ChannelSettings,
+        stop: oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let formatter = chn.filename_format.as_deref().unwrap_or(&self.settings.formatter);
+        let filename = self.settings.filename(formatter, &stream);
+        let (user, id) = (stream.user().clone(), stream.id().to_owned());
+        let res = self
+            .download_to(path::Path::new(&filename), stream, source, chn, stop)
+            .await;
+        if let Err(e) = &res {
+            events::emit(&user, Event::Error { stream_id: &id, error: &format!("{e:#}") });
+        }
+        res
+    }
+
+    /// Downloads the stream to `path`.
+    /// If the output is a tar archive, its extension is appended to the path.
+    pub async fn download_to(
+        &self,
+        path: &path::Path,
+        stream: Stream,
+        source: Source,
+        chn: ChannelSettings,
+        stop: oneshot::Receiver<()>,
+    ) -> Result<()> {
+        log::info!(
+            "downloading stream #{} for channel {}",
+            stream.id(),
+            stream.user()
+        );
+
+        let temp_dir = &self.settings.temp_dir;
+        let Some(_lock) = lock::StreamLock::acquire(&temp_dir.join(recover::LOCK_DIR), stream.id())
+            .await
+            .context("cannot acquire stream lock")?
+        else {
+            log::info!("stream #{} is already being archived; skipping", stream.id());
+            return Ok(());
+        };
+        if let Some(min) = self.settings.min_free_bytes {
+            fs::create_dir_all(temp_dir)
+                .await
+                .context("cannot create temporary directory")?;
+            let free = fs_utils::available_space(temp_dir)
+                .await
+                .context("cannot check free disk space")?;
+            if free < min {
+                return Err(anyhow!(
+                    "refusing to download stream #{}: only {free} bytes free in {}, below --min-free-bytes ({min})",
+                    stream.id(),
+                    temp_dir.display()
+                ));
+            }
+        }
+
+        let _active = metrics::ACTIVE_DOWNLOADS.track();
+        events::emit(stream.user(), Event::DownloadStarted { stream_id: stream.id() });
+
+        let resumed = match self.settings.resume {
+            true => recover::find_dir(temp_dir, stream.id())
+                .await
+                .context("cannot search for a previous download directory")?,
+            false => None,
+        };
+        let is_resumed = resumed.is_some();
+
+        let (dl_path, _dir_lock) = match resumed {
+            Some((path, lock)) => {
+                log::info!("resuming download of stream #{} in {}", stream.id(), path.display());
+                (path, lock)
+            }
+            //Create a folder as a temporary download directory
+            None => loop {
+                let name = rand::rand_hex(RAND_DIR_LEN);
+                // the directory is locked before it exists, so that it is never recovered while in use
+                let Some(dir_lock) = recover::lock_dir(temp_dir, &name).await? else {
+                    continue;
+                };
+
+                let new_path = temp_dir.join(&name);
+                if fs_utils::create_new_dir(&new_path)
+                    .await
+                    .context("cannot create temporary directory")?
+                {
+                    break (new_path, dir_lock);
+                }
+            },
+        };
+
+        let sums = Checksums::new(&dl_path);
+
+        // written early, so that the download can be recovered if the process crashes
+        let initial = StreamUpdate::from(&stream);
+        self.datafile(&dl_path, &stream, std::slice::from_ref(&initial), None, &sums)
+            .await
+            .context("could not write datafile")?;
+
+        let stop = match self.settings.min_free_bytes {
+            Some(min) => watch_space(temp_dir.clone(), min, stop),
+            None => stop,
+        };
+
+        // the first entry is the state of the stream when it started
+        let updates = std::sync::Mutex::new(vec![initial]);
+        let record = async {
+            if let Source::Live(_, Some(rx)) = &source {
+                while let Ok(x) = rx.recv().await {
+                    log::info!(
+                        "stream #{} updated: {:?} ({})",
+                        stream.id(),
+                        x.title,
+                        x.game_name
+                    );
+                    let list = {
+                        let mut updates = updates.lock().unwrap();
+                        updates.push(x);
+                        updates.clone()
+                    };
+                    if let Err(e) = self.datafile(&dl_path, &stream, &list, None, &sums).await {
+                        log::warn!("could not write datafile: {e:?}");
+                    }
+                }
+            }
+            future::pending::<core::convert::Infallible>().await
+        };
+
+        let dl = self.dl(dl_path.clone(), &stream, &source, &chn, &sums, stop);
+        let res = match future::select(Box::pin(dl), Box::pin(record)).await {
+            Either::Left((x, _)) => x,
+            Either::Right((x, _)) => match x {},
+        };
+        let updates = updates.into_inner().unwrap();
+
+        let res = match res {
+            Ok(Some(x)) => Ok(x),
+            Ok(None) if self.settings.keep_chat && matches!(source, Source::Live(..)) => {
+                Err(anyhow!("no video was downloaded"))
+            }
+            Ok(None) => {
+                return fs::remove_dir_all(&dl_path)
+                    .await
+                    .context("failed to clean up download directory")
+            }
+            Err(e) => Err(e),
+        };
+
+        // the chat log and any partial segments are archived regardless, so mark the video as incomplete
+        if let Err(e) = &res {
+            let marker = format!("{e:?}\n");
+            match fs::write(dl_path.join(INCOMPLETE_FILE), &marker).await {
+                Ok(()) => sums.insert_bytes(&dl_path.join(INCOMPLETE_FILE), marker.as_bytes()),
+                Err(e) => log::warn!("could not write {INCOMPLETE_FILE} marker: {e:?}"),
+            }
+        }
+
+        let _permit = match &self.finalize_limit {
+            Some(x) => Some(match x.try_acquire() {
+                Some(permit) => permit,
+                None => {
+                    log::info!("waiting for other downloads to finish finalizing");
+                    x.acquire().await
+                }
+            }),
+            None => None,
+        };
+
+        let res = match (res, &self.settings.remux) {
+            (Ok(mut data), Some(container)) => {
+                for x in &mut data {
+                    match remux(&x.0, container, &sums).await {
+                        Ok(path) => x.0 = path,
+                        Err(e) => {
+                            log::error!("failed to remux stream; keeping the original segments: {e:?}")
+                        }
+                    }
+                }
+                Ok(data)
+            }
+            (res, _) => res,
+        };
+
+        self.datafile(&dl_path, &stream, &updates, Some(&res), &sums)
+            .await
+            .context("could not write datafile")?;
+        // the files of the previous run, and those appended to, were only partially hashed by this one
+        if is_resumed {
+            if let Err(e) = sums.rehash().await {
+                log::warn!("could not hash the files of the resumed download: {e:?}");
+            }
+        }
+        if let Err(e) = sums.write().await {
+            log::warn!("could not write {SUMS_FILE}: {e:?}");
+        }
+
+        let to_dir = match chn.output {
+            Some(x) => matches!(x, Output::Dir),
+            None => self.settings.save_to_dir,
+        };
+        let archived = self.archive_dir(&dl_path, path, to_dir).await;
+
+        if let Ok(x) = &archived {
+            log::info!("finished downloading: {}", x.display());
+        }
+
+        // a failed archive is left in the download directory,
+        // and a failed download is not a success even when the partial stream was archived
+        let success = archived.is_ok() && res.is_ok();
+        let archive_path = archived.as_deref().unwrap_or(&dl_path).to_path_buf();
+        notify::finished(&stream, &archive_path, success);
+        events::emit(
+            stream.user(),
+            Event::Finished {
+                stream_id: stream.id(),
+                path: &archive_path.to_string_lossy(),
+                success,
+            },
+        );
+        self.post_hook(success, archive_path, &stream).await;
+
+        res.map(drop).and(archived.map(drop))
+    }
+
+    /// Moves the download directory to `path` if `to_dir` is set,
+    /// or archives it to a tar archive.
+    pub(crate) async fn archive_dir(
+        &self,
+        dl_path: &path::Path,
+        path: &path::Path,
+        to_dir: bool,
+    ) -> Result<Box<path::Path>> {
+        let Some(dest) = &self.settings.upload else {
+            return self.archive_local(dl_path, path, to_dir).await;
+        };
+
+        if dest.keep_local {
+            let archived = self.archive_local(dl_path, path, to_dir).await?;
+            let key = format!("{}{}", dest.prefix, object_key(&archived));
+            let res = if to_dir {
+                dest.bucket.put_dir(&archived, &key).await
+            } else {
+                dest.bucket.put_file(&archived, &key).await
+            };
+            // the archive is still on disk, so a failed upload does not fail the download
+            match res {
+                Ok(()) => log::info!("uploaded {} to {}", archived.display(), dest.bucket.url(&key)),
+                Err(e) => log::error!("could not upload {}: {e:?}", archived.display()),
+            }
+            return Ok(archived);
+        }
+
+        let mut key = format!("{}{}", dest.prefix, object_key(path));
+        if to_dir {
+            dest.bucket
+                .put_dir(dl_path, &key)
+                .await
+                .context("could not upload directory")?;
+        } else {
+            key = format!("{key}.{}", self.settings.compression.extension());
+            let (writer, upload) = dest.bucket.writer(&key);
+            // the download directory is kept until the upload succeeded
+            let (tar, upload) =
+                future::join(tar_into(writer, self.settings.compression, dl_path, false), upload).await;
+            // a failed upload also fails the tar with a broken pipe, so its error is the cause;
+            // otherwise a failed tar stops the upload, and the tar's error is the cause
+            let res = match tar {
+                Err(e) if upload.is_err() && is_broken_pipe(&e) => upload,
+                Err(e) => Err(e),
+                Ok(()) => upload,
+            };
+            res.context("could not upload tar archive")?;
+        }
+        fs::remove_dir_all(dl_path)
+            .await
+            .context("failed to clean up download directory")?;
+
+        let url = dest.bucket.url(&key);
+        Ok(path::PathBuf::from(url).into_boxed_path())
+    }
+
+    /// Moves the download directory to `path` if `to_dir` is set,
+    /// or archives it to a tar archive on local disk.
+    async fn archive_local(
+        &self,
+        dl_path: &path::Path,
+        path: &path::Path,
+        to_dir: bool,
+    ) -> Result<Box<path::Path>> {
+        if to_dir {
+            move_dir(dl_path, path)
+                .await
+                .context("could not move directory")
+        } else {
+            tar(path, self.settings.compression, dl_path)
+                .await
+                .context("could not make tar archive")
+        }
+    }
+
+    /// Writes `info.json`, and the other metadata files enabled in the settings.
+    /// `result` is the result of the download, or `None` while it is still running.
+    async fn datafile(
+        &self,
+        path: &path::Path,
+        stream: &Stream,
+        updates: &[StreamUpdate],
+        result: Option<&Result<Vec<hls::StreamData>>>,
+        sums: &Checksums,
+    ) -> Result<()> {
+        datafile(path, stream, updates, result, sums).await?;
+        match self.settings.metadata {
+            Metadata::Json => Ok(()),
+            Metadata::Nfo => nfo(path, stream, updates, sums).await,
+        }
+    }
+
+    async fn dl(
+        &self,
+        path: path::PathBuf,
+        stream: &Stream,
+        source: &Source,
+        chn: &ChannelSettings,
+        sums: &Checksums,
+        stop: oneshot::Receiver<()>,
+    ) -> Result<Option<Vec<hls::StreamData>>> {
+        let Source::Live(chat, _) = source else {
+            return self.stream(path, stream, source, chn, sums, stop).await;
+        };
+
+        let (tx, rx) = oneshot::channel();
+
+        let chat_handle = task::Builder::new()
+            .name(task::current().name().unwrap_or_default().to_owned())
+            .local(chat_log(
+                chat.clone(),
+                path.join(chn.chat_format.file_name()),
+                chn.chat_format,
+                chn.chat_timestamp,
+                chn.chat_vtt,
+                stream.started_at(),
+                sums.clone(),
+                rx,
+            ))
+            .context("failed to download chat")?;
+        let res = self.stream(path, stream, source, chn, sums, stop).await;
+
+        // a failed chat log does not discard the video, nor the other way around
+        if tx.send(()).is_err() {
+            log::error!("chat log stopped before the download finished");
+        }
+        if let Err(e) = chat_handle.await {
+            log::error!("failed to download chat: {e:?}");
+        }
+        res
+    }
+
+    async fn stream(
+        &self,
+        path: path::PathBuf,
+        stream: &Stream,
+        source: &Source,
+        chn: &ChannelSettings,
+        sums: &Checksums,
+        mut stop: oneshot::Receiver<()>,
+    ) -> Result<Option<Vec<hls::StreamData>>> {
+        log::debug!("download location: {}", path.display());
+
+        // the chat is already being logged, so a queued stream only misses its video until a slot frees up
+        let _permit = match &self.download_limit {
+            Some(x) => Some(match x.try_acquire() {
+                Some(permit) => permit,
+                None => {
+                    log::info!(
+                        "all {} download slots are in use; stream #{} is waiting for one",
+                        self.settings.max_concurrent.unwrap_or_default(),
+                        stream.id()
+                    );
+                    match future::select(Box::pin(x.acquire()), &mut stop).await {
+                        Either::Left((permit, _)) => {
+                            log::info!("stream #{} acquired a download slot", stream.id());
+                            permit
+                        }
+                        // the chat logged in the meantime is archived, with the video marked as missing
+                        Either::Right(_) => {
+                            log::info!("stream #{} ended while waiting for a download slot", stream.id());
+                            return match source {
+                                Source::Live(..) => Err(anyhow!("the stream ended while waiting for a download slot")),
+                                Source::Vod => Ok(None),
+                            };
+                        }
+                    }
+                }
+            }),
+            None => None,
+        };
+
+        let auth = self.gql_auth();
+        let mut n = 0;
+        let url = loop {
+            n += 1;
+            let url = match (source, &self.settings.extractor) {
+                (Source::Vod, _) => live::get_vod_hls(stream.id(), auth).await,
+                (Source::Live(..), Extractor::Internal) => {
+                    live::get_hls(stream.user().login(), auth).await
+                }
+                (Source::Live(..), Extractor::Streamlink) => self.streamlink(stream.user().login()).await
+            }.context("failed to fetch hls playlist url")?;
+
+            if let Some(x) = url {
+                break x;
+            }
+
+            async_std::task::sleep(time::Duration::from_secs(5)).await;
+            if n >= 4 {
+                log::error!("could not find m3u8 url!");
+                return Err(anyhow!("could not find m3u8 url!"));
+            }
+        };
+
+        let opts = hls::DownloadOptions {
+            segment_concurrency: chn.segment_concurrency,
+            adaptive_concurrency: self.settings.adaptive_concurrency,
+            segment_timeout: time::Duration::from_secs(chn.segment_timeout_secs),
+            single_file: chn.single_file,
+            checksums: Some(sums.clone()),
+            skip_ads: chn.skip_ads,
+            sequence_names: self.settings.sequence_names,
+            max_empty_polls: chn.max_empty_polls.filter(|x| *x > 0),
+        };
+
+        let progress = events::enabled().then(|| {
+            let (tx, mut rx) = mpsc::channel::<hls::Progress>(PROGRESS_BUFFER);
+            let (user, id) = (stream.user().clone(), stream.id().to_owned());
+            task::spawn(async move {
+                while let Some(x) = rx.next().await {
+                    let segments = x.index + 1;
+                    events::emit(&user, Event::Progress { stream_id: &id, segments, bytes: x.bytes });
+                }
+            });
+            tx
+        });
+
+        match hls::download(url, &path, &chn.format, &opts, stop, progress).await {
+            Ok(x) => Ok(Some(x)),
+            Err(hls::HlsError::NoMatchingQuality { .. }) => Ok(None),
+            Err(e) => Err(e).context("failed to download hls playlist"),
+        }
+    }
+
+    /// Fetches the master playlist url for the live stream of `login` with the internal extractor,
+    /// returning the reason it could not be obtained as the error.
+    pub async fn check_hls(&self, login: &str) -> Result<String> {
+        live::check_hls(login, self.gql_auth()).await
+    }
+
+    /// The OAuth token sent to the twitch GQL api when fetching playlists.
+    fn gql_auth(&self) -> Option<&str> {
+        self.settings.twitch_oauth.as_deref().or(self.settings.user_token.as_deref())
+    }
+
+    /// Fetches the master playlist url for the live stream of `login` with streamlink.
+    pub async fn streamlink(&self, login: impl AsRef<str>) -> Result<Option<String>> {
+        let link = format!("https://twitch.tv/{}", login.as_ref());
+        let mut args = vec!["--stream-url", &link];
+
+        // an explicitly configured header wins over the tokens it would be built from
+        let header;
+        if let Some(x) = &self.settings.stream_auth {
+            args.insert(0, "--twitch-api-header");
+            args.insert(1, x);
+        } else if let Some(x) = self.gql_auth() {
+            header = format!("Authorization=OAuth {x}");
+            args.insert(0, "--twitch-api-header");
+            args.insert(1, &header);
+        }
+
+        cmd("streamlink", &args, true).await
+    }
+
+    /// Runs the post-hook program for an archived stream, detached from the download.
+    async fn post_hook(&self, success: bool, path: path::PathBuf, stream: &Stream) {
+        let Some(hook) = self.settings.post_hook.clone() else {
+            return;
+        };
+
+        let args = [
+            if success { "success" } else { "failure" }.to_owned(),
+            path.to_string_lossy().into_owned(),
+            stream.id().to_owned(),
+            stream.user().login().to_owned(),
+        ];
+        let id = stream.id().to_owned();
+        let guard = HOOKS.read().await;
+
+        let res = task::Builder::new()
+            .name(task::current().name().unwrap_or_default().to_owned())
+            .spawn(async move {
+                let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+                if let Err(e) = cmd(&hook, &args, false).await {
+                    log::warn!("post-hook failed for stream #{id}: {e:?}");
+                }
+                drop(guard);
+            });
+
+        if let Err(e) = res {
+            log::error!("failed to spawn post-hook: {e:?}");
+        }
+    }
+}
+
+/// Waits for every running post-hook to exit.
+pub async fn wait_hooks() {
+    let _ = HOOKS.write().await;
+}
+
+async fn datafile(
+    path: &path::Path,
+    stream: &Stream,
+    updates: &[StreamUpdate],
+    result: Option<&Result<Vec<hls::StreamData>>>,
+    sums: &Checksums,
+) -> Result<()> {
+    use chrono::SecondsFormat;
+
+    #[derive(Serialize)]
+    struct Data<'a> {
+        version: String,
+        data: StreamSer<'a>,
+        updates: &'a [StreamUpdate],
+        segments: Vec<Segments<'a>>,
+        /// Whether the download finished without errors.
+        complete: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct StreamSer<'a> {
+        id: &'a str,
+        user: &'a User,
+        game: GameDes<'a>,
+        title: &'a str,
+        started_at: String,
+        language: &'a str,
+        tags: &'a [Box<str>],
+        is_mature: bool,
+    }
+
+    #[derive(Serialize)]
+    struct GameDes<'a> {
+        id: &'a str,
+        name: &'a str,
+    }
+
+    #[derive(Serialize)]
+    struct Segments<'a> {
+        path: String,
+        group_id: &'a str,
+        name: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        language: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_bitrate: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bitrate: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        resolution: Option<Resolution>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        frame_rate: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        codecs: Option<&'a str>,
+    }
+
+    #[derive(Serialize)]
+    struct Resolution {
+        width: u64,
+        height: u64,
+    }
+
+    let datapath = path.join("info.json");
+    let completed = match result {
+        Some(Ok(x)) => Some(x),
+        // the renditions which finished are listed, even though another one failed
+        Some(Err(e)) => match e.downcast_ref::<hls::HlsError>() {
+            Some(hls::HlsError::RenditionsFailed { completed, .. }) => Some(completed),
+            _ => None,
+        },
+        None => None,
+    };
+    let segments = if let Some(x) = completed {
+        x.iter().map(|(segpath, alt, var)| Segments {
+            path: segpath.to_string_lossy().into_owned(),
+            group_id: alt.group_id.as_str(),
+            name: alt.name.as_str(),
+            language: alt.language.as_deref(),
+            max_bitrate: var.as_ref().map(|x| x.bandwidth),
+            bitrate: var.as_ref().and_then(|x| x.average_bandwidth),
+            resolution: var.as_ref().and_then(|x| x.resolution).map(|x| Resolution {
+                width: x.width,
+                height: x.height,
+            }),
+            frame_rate: var.as_ref().and_then(|x| x.frame_rate),
+            codecs: var.as_ref().and_then(|x| x.codecs.as_deref()),
+        }).collect()
+    } else {
+        vec![]
+    };
+
+    let data = Data {
+        version: format!("0.1/{}", env!("CARGO_PKG_VERSION")),
+        data: StreamSer {
+            id: stream.id(),
+            user: stream.user(),
+            game: GameDes {
+                id: stream.game_id(),
+                name: stream.game_name(),
+            },
+            title: stream.title(),
+            started_at: stream
+                .started_at()
+                .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            language: stream.language(),
+            tags: stream.tags(),
+            is_mature: stream.is_mature(),
+        },
+        updates,
+        segments,
+        complete: matches!(result, Some(Ok(_))),
+        error: match result {
+            Some(Err(e)) => Some(format!("{e:#}")),
+            _ => None,
+        },
+    };
+
+    let data = serde_json::to_vec(&data)?;
+    fs_utils::write_atomic(&datapath, &data).await?;
+    sums.insert_bytes(&datapath, &data);
+    Ok(())
+}
+
+async fn chat_log(
+    rx: IrcRecv,
+    path: impl AsRef<path::Path>,
+    format: ChatFormat,
+    timestamp: ChatTimestamp,
+    vtt: bool,
+    started_at: chrono::DateTime<chrono::Local>,
+    sums: Checksums,
+    mut noti: oneshot::Receiver<()>,
+) -> Result<()> {
+    use futures::{
+        future::{select, Either},
+        io::BufWriter,
+    };
+
+    /// Opens the chat log file, retrying on failure.
+    /// If the file cannot be opened, returns `None`; chat is then drained and discarded
+    /// so that the shared IRC handler is not blocked by this channel.
+    async fn open(path: &path::Path) -> Option<BufWriter<HashWriter<fs::File>>> {
+        let file = retry::retry(
+            || {
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+            },
+            CHAT_OPEN_DELAY,
+            CHAT_OPEN_RETRY,
+            "opening chat log file",
+        )
+        .await;
+
+        match file {
+            Ok(x) => Some(BufWriter::with_capacity(CHAT_BUFFER, HashWriter::new(x))),
+            Err(e) => {
+                log::error!(
+                    "could not open chat log file {}; discarding its messages: {e:?}",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// A file written next to the chat log, opened on its first write,
+    /// so that streams without anything to write to it do not leave an empty file.
+    struct Sidecar {
+        /// Written when the file is created.
+        header: &'static str,
+        path: path::PathBuf,
+        file: Option<Option<BufWriter<HashWriter<fs::File>>>>,
+    }
+
+    impl Sidecar {
+        fn new(name: &'static str, header: &'static str, log: &path::Path) -> Self {
+            Self {
+                header,
+                path: log.with_file_name(name),
+                file: None,
+            }
+        }
+
+        async fn write(&mut self, data: &[u8]) -> Result<()> {
+            if self.file.is_none() {
+                let mut file = open(&self.path).await;
+                if let Some(x) = &mut file {
+                    if x.get_ref().get_ref().metadata().await?.len() == 0 {
+                        x.write_all(self.header.as_bytes()).await?;
+                    }
+                }
+                self.file = Some(file);
+            }
+
+            if let Some(Some(x)) = &mut self.file {
+                x.write_all(data).await?;
+            }
+            Ok(())
+        }
+
+        /// Flushes the file, recording its checksum.
+        async fn flush(&mut self, sums: &Checksums) -> Result<()> {
+            if let Some(Some(x)) = &mut self.file {
+                x.flush().await?;
+                sums.insert(&self.path, x.get_ref().sha256());
+            }
+            Ok(())
+        }
+    }
+
+    if !rx.open() {
+        return Err(anyhow!("irc channel was unexpectedly open!"));
+    }
+
+    let log_path = path.as_ref().to_owned();
+    let mut file = open(&log_path).await;
+    let mut events = Sidecar::new(EVENTS_FILE, "", path.as_ref());
+    let mut vtt = vtt.then(|| Sidecar::new(VTT_FILE, "WEBVTT\n\n", path.as_ref()));
+    let dropped = rx.dropped();
+
+    loop {
+        let msg = match select(rx.recv(), noti).await {
+            Either::Left((msg, next_noti)) => {
+                noti = next_noti;
+                msg?
+            }
+            Either::Right(_) => {
+                rx.close();
+                if let Some(x) = &mut file {
+                    x.flush().await?;
+                    sums.insert(&log_path, x.get_ref().sha256());
+                }
+                events.flush(&sums).await?;
+                if let Some(x) = &mut vtt {
+                    x.flush(&sums).await?;
+                }
+
+                let n = rx.dropped() - dropped;
+                if n > 0 {
+                    log::warn!(
+                        "{n} chat message(s) were dropped; {} is incomplete",
+                        path.as_ref().display()
+                    );
+                }
+                return Ok(());
+            }
+        };
+
+        if let Some(event) = &msg.event {
+            let mut line = serde_json::to_vec(event)?;
+            line.push(b'\n');
+            events.write(&line).await?;
+        }
+
+        if let (Some(vtt), Some(chat)) = (&mut vtt, &msg.chat) {
+            let start = (chat.ts as i64 - started_at.timestamp_millis()).max(0);
+            let end = start + VTT_CUE_DURATION.as_millis() as i64;
+            let name = chat.display_name.as_deref().unwrap_or(&chat.user);
+            let cue = format!(
+                "{} --> {}\n<v {}>{}\n\n",
+                vtt_time(start),
+                vtt_time(end),
+                vtt_escape(name),
+                vtt_escape(&chat.text)
+            );
+            vtt.write(cue.as_bytes()).await?;
+        }
+
+        let Some(x) = &mut file else { continue };
+
+        if let ChatFormat::Json = format {
+            if let Some(chat) = msg.chat {
+                let mut line = serde_json::to_vec(&chat)?;
+                line.push(b'\n');
+                x.write_all(&line).await?;
+            }
+            continue;
+        }
+
+        let now = chrono::Local::now();
+        match timestamp {
+            ChatTimestamp::None => (),
+            ChatTimestamp::Wallclock => {
+                let ts = now.to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+                x.write_all(format!("[{ts}] ").as_bytes()).await?;
+            }
+            ChatTimestamp::Relative => {
+                let ms = (now - started_at).num_milliseconds().max(0);
+                x.write_all(format!("[{}.{:03}] ", ms / 1000, ms % 1000).as_bytes()).await?;
+            }
+        }
+        x.write_all(msg.raw.as_bytes()).await?;
+    }
+}
+
+/// Formats milliseconds as a WebVTT timestamp.
+fn vtt_time(ms: i64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn vtt_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+async fn cmd(program: &str, args: &[&str], output: bool) -> Result<Option<String>> {
+    use async_std::process::Stdio;
+
+    log::trace!("running command :{program} {args:?}");
+    let out = async_std::process::Command::new(program)
+        .args(args)
+        .stdout(if output {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .output()
+        .await?;
+
+    if out.status.success() {
+        if output {
+            return String::from_utf8(out.stdout).map(Some).map_err(From::from);
+        } else {
+            return Ok(None);
+        }
+    };
+
+    let (logpath, file) = fs_utils::create_dedup_file(
+        path::Path::new(&format!(
+            "{}.{}",
+            task::current().name().unwrap_or("<unknown>"),
+            san(program)
+        )),
+        "log",
+    )
+    .await?;
+
+    log::error!(
+        "{program} exited with status {}: logging at {}",
+        out.status.code().unwrap_or(-1),
+        logpath.display()
+    );
+
+    let mut writer = futures::io::BufWriter::new(file);
+
+    writer.write_all("=== STDOUT ===\n".as_bytes()).await?;
+    writer.write_all(&out.stdout).await?;
+    writer.write_all("\n\n=== STDERR ===\n".as_bytes()).await?;
+    writer.write_all(&out.stderr).await?;
+    writer.flush().await?;
+    Err(anyhow!("program exited abnormally!"))
+}
+
+/// Remuxes the media playlist into a single file of the given container format using ffmpeg.
+/// On success, the playlist and its segments are removed.
+async fn remux(mediapath: &path::Path, container: &str, sums: &Checksums) -> Result<path::PathBuf> {
+    let out = mediapath.with_extension(container);
+    let (input, output) = (mediapath.to_string_lossy(), out.to_string_lossy());
+
+    log::info!("remuxing {input} to {output}");
+    cmd(
+        "ffmpeg",
+        &["-hide_banner", "-loglevel", "error", "-n", "-i", &input, "-c", "copy", &output],
+        false,
+    )
+    .await?;
+
+    // single-file downloads may still have a segment directory for init segments
+    let segments = mediapath.with_extension("");
+    if segments.is_dir().await {
+        fs::remove_dir_all(&segments)
+            .await
+            .context("failed to remove segment directory")?;
+        sums.remove(&segments);
+    }
+    let single = mediapath.with_extension("ts");
+    if single.is_file().await {
+        fs::remove_file(&single)
+            .await
+            .context("failed to remove media file")?;
+        sums.remove(&single);
+    }
+    fs::remove_file(mediapath)
+        .await
+        .context("failed to remove media playlist")?;
+    sums.remove(mediapath);
+
+    // written by ffmpeg, so this is the one file which has to be read back
+    match checksum::hash_file(&out).await {
+        Ok(x) => sums.insert(&out, x),
+        Err(e) => log::warn!("could not hash {output}: {e:?}"),
+    }
+
+    // the timeline pointed at the segments which were just removed
+    if let (Some(dir), Some(file)) = (out.parent(), out.file_name()) {
+        match hls::remux_timeline(dir, &file.to_string_lossy()).await {
+            Ok(x) => sums.insert(&dir.join("timeline.json"), x),
+            Err(e) => log::warn!("could not rewrite the timeline of {output}: {e}"),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Writes a `movie.nfo` describing the stream, mapping:
+/// - `title`: the latest stream title, with the channel name and start date
+/// - `plot`: the latest stream title
+/// - `premiered`: the date the stream started
+/// - `studio`: the channel display name
+/// - `genre`: each game streamed, in order
+/// - `uniqueid` (type `twitch`): the stream id
+async fn nfo(path: &path::Path, stream: &Stream, updates: &[StreamUpdate], sums: &Checksums) -> Result<()> {
+    fn escape(value: &str) -> String {
+        let mut res = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '&' => res.push_str("&amp;"),
+                '<' => res.push_str("&lt;"),
+                '>' => res.push_str("&gt;"),
+                '"' => res.push_str("&quot;"),
+                '\'' => res.push_str("&apos;"),
+                c => res.push(c),
+            }
+        }
+        res
+    }
+
+    let title = updates.last().map_or(stream.title(), |x| &x.title);
+    let date = stream.started_at().format("%Y-%m-%d").to_string();
+
+    let mut games: Vec<&str> = Vec::new();
+    for game in updates.iter().map(|x| &*x.game_name).chain(std::iter::once(stream.game_name())) {
+        if !game.is_empty() && !games.contains(&game) {
+            games.push(game);
+        }
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<movie>\n");
+    xml += &format!(
+        "  <title>{} - {date} - {}</title>\n",
+        escape(stream.user().name()),
+        escape(title)
+    );
+    xml += &format!("  <plot>{}</plot>\n", escape(title));
+    xml += &format!("  <premiered>{date}</premiered>\n");
+    xml += &format!("  <studio>{}</studio>\n", escape(stream.user().name()));
+    for game in games {
+        xml += &format!("  <genre>{}</genre>\n", escape(game));
+    }
+    xml += &format!(
+        "  <uniqueid type=\"twitch\" default=\"true\">{}</uniqueid>\n",
+        escape(stream.id())
+    );
+    xml += "</movie>\n";
+
+    fs_utils::write_atomic(&path.join("movie.nfo"), xml.as_bytes())
+        .await
+        .context("could not write movie.nfo")?;
+    sums.insert_bytes(&path.join("movie.nfo"), xml.as_bytes());
+    Ok(())
+}
+
+/// Forwards `stop`, and also signals the download to stop
+/// once the free space in `dir` falls below `min`.
+fn watch_space(dir: path::PathBuf, min: u64, stop: oneshot::Receiver<()>) -> oneshot::Receiver<()> {
+    let (mut tx, rx) = oneshot::channel();
+
+    task::spawn(async move {
+        let stopped = async {
+            // a dropped sender does not stop the download
+            if stop.await.is_err() {
+                future::pending::<()>().await;
+            }
+        };
+        let low = async {
+            loop {
+                task::sleep(SPACE_CHECK_INTERVAL).await;
+                match fs_utils::available_space(&dir).await {
+                    Ok(x) if x < min => {
+                        log::error!("only {x} bytes free in {}; stopping download", dir.display());
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("cannot check free disk space: {e:?}"),
+                }
+            }
+        };
+
+        let signal = future::select(Box::pin(stopped), Box::pin(low));
+        if matches!(future::select(signal, tx.cancellation()).await, Either::Left(_)) {
+            let _ = tx.send(());
+        }
+    });
+
+    rx
+}
+
+/// Moves the contents of the download directory `orig` to a new directory at `dest`.
+async fn move_dir(orig: &path::Path, dest: &path::Path) -> Result<Box<path::Path>> {
+    let dir = fs_utils::create_dedup_dir(dest).await?;
+    // see async-std issue#1053
+    fs::read_dir(&orig)
+        .await?
+        .map(|entry| {
+            let dir = &dir;
+            async move {
+                let entry = entry?;
+                let to = dir.join(entry.file_name());
+                match fs::rename(entry.path(), &to).await {
+                    // the temporary directory is on another filesystem
+                    Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                        fs_utils::copy_recursive(&entry.path(), &to).await
+                    }
+                    res => res,
+                }
+            }
+        })
+        .buffer_unordered(ASYNC_BUF_FACTOR)
+        .try_collect()
+        .await?;
+
+    fs::remove_dir_all(orig).await?;
+
+    Ok(dir)
+}
+
+/// The object key of an archive at `path`, with the components of the path joined by `/`.
+fn object_key(path: &path::Path) -> String {
+    let parts: Vec<_> = path
+        .components()
+        .filter_map(|x| match x {
+            std::path::Component::Normal(x) => Some(x.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    parts.join("/")
+}
+
+/// Archives the finished download directory `dir` to a new tar archive next to it, removing the directory.
+///
+/// The directory is locked like a download in progress, so that it is not archived while a running instance
+/// is still writing to or recovering it.
+pub async fn tar_dir(dir: &path::Path, compress: Compression) -> Result<Box<path::Path>> {
+    let dir = fs::canonicalize(dir)
+        .await
+        .with_context(|| format!("cannot find {}", dir.display()))?;
+    let (Some(temp_dir), Some(name)) = (dir.parent(), dir.file_name()) else {
+        return Err(anyhow!("{} is not a download directory", dir.display()));
+    };
+    let Some(_lock) = recover::lock_dir(temp_dir, &name.to_string_lossy()).await? else {
+        return Err(anyhow!("{} is in use by another instance", dir.display()));
+    };
+
+    if !dir.join("info.json").is_file().await {
+        return Err(anyhow!("{} is not a download directory, as it has no info.json", dir.display()));
+    }
+
+    tar(&dir, compress, &dir).await
+}
+
+/// Archives the download directory `path` to a new tar archive at `tarpath`.
+async fn tar(tarpath: &path::Path, compress: Compression, path: &path::Path) -> Result<Box<path::Path>> {
+    let (tarpath, tarfile) = fs_utils::create_dedup_file(tarpath, compress.extension()).await?;
+    tar_into(tarfile, compress, path, true).await?;
+    fs::remove_dir_all(path).await?;
+
+    Ok(tarpath)
+}
+
+/// Whether `e` was caused by the reader of a writer going away.
+fn is_broken_pipe(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|x| x.downcast_ref::<io::Error>())
+        .any(|x| x.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// Writes a tar archive of the download directory `path` to `writer`, closing it.
+/// If `remove` is set, files are removed as they are appended.
+async fn tar_into(
+    writer: impl futures::AsyncWrite + Unpin + Send + Sync + 'static,
+    compress: Compression,
+    path: &path::Path,
+    remove: bool,
+) -> Result<()> {
+    use async_compression::futures::write::{GzipEncoder, ZstdEncoder};
+    use async_tar::Builder;
+    use futures::AsyncWriteExt;
+
+    type TarWriter = Box<dyn futures::AsyncWrite + Unpin + Send + Sync>;
+
+    #[async_recursion]
+    async fn put_recursive(
+        builder: &mut Builder<TarWriter>,
+        path: &path::Path,
+        base: &path::Path,
+        uc: &path::Path,
+        remove: bool,
+    ) -> Result<()> {
+        use fs::{DirEntry, FileType};
+
+        async fn entry_check(entry: io::Result<DirEntry>, uc: &path::Path) -> Result<(FileType, DirEntry)> {
+            let entry = entry?;
+            let canon = entry.path()
+                .canonicalize()
+                .await?;
+
+            if !canon.starts_with(uc) {
+                panic!(
+                    "reached unpexpected location while creating tar: {}, bound: {}",
+                    canon.display(),
+                    uc.display()
+                );
+            }
+
+            let file_type = entry.file_type().await?;
+            Ok((file_type, entry))
+        }
+
+        let mut dir_entry = fs::read_dir(&path)
+            .await?
+            .map(|entry| entry_check(entry, uc))
+            .buffer_unordered(ASYNC_BUF_FACTOR);
+
+        while let Some(x) = dir_entry.next().await {
+            let (file_type, entry) = x?;
+
+            if file_type.is_dir() {
+                log::trace!(
+                    "appending directory {}: {}",
+                    entry.path().display(),
+                    entry.file_name().to_string_lossy()
+                );
+                builder.append_dir(base.join(entry.file_name()), &entry.path()).await?;
+
+                put_recursive(builder, &entry.path(), &base.join(entry.file_name()), uc, remove).await?;
+                if remove {
+                    let _ = fs::remove_dir(entry.path()).await;
+                }
+            } else if file_type.is_file() {
+                log::trace!(
+                    "appending file {}: {}",
+                    entry.path().display(),
+                    entry.file_name().to_string_lossy()
+                );
+                builder.append_path_with_name(&entry.path(), base.join(entry.file_name()))
+                    .await?;
+                if remove {
+                    let _ = fs::remove_file(entry.path()).await;
+                }
+            } else {
+                log::warn!("file {} was not a file or a directory", entry.path().display())
+            }
+        }
+
+        Ok(())
+    }
+
+    let writer: TarWriter = match compress {
+        Compression::None => Box::new(writer),
+        Compression::Gzip => Box::new(GzipEncoder::new(writer)),
+        Compression::Zstd => Box::new(ZstdEncoder::new(writer)),
+    };
+    let mut tar = async_tar::Builder::new(writer);
+    let canon = path.canonicalize().await?;
+    
+    put_recursive(&mut tar, path, path::Path::new(""), &canon, remove).await?;
+
+    // closing the writer flushes the trailer of the compressed stream
+    let mut writer = tar.into_inner().await?;
+    writer.close().await?;
+
+    Ok(())
+}
@@ -1,15 +1,17 @@
 use once_cell::sync::OnceCell;
 use std::{env, fs};
 
-use crate::{filename::Formatter, prelude::*};
+use twitch_archive::{filename::Formatter, hls, irc::Overflow, logger, net, prelude::*, s3};
+
+pub use twitch_archive::archiver::{ChannelSettings, Compression, Extractor, Metadata};
 
 static NAME: OnceCell<Box<str>> = OnceCell::new();
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Debug)]
-pub enum Extractor {
-    Internal,
-    Streamlink
+pub enum TransportMode {
+    Webhook,
+    Websocket,
 }
 
 pub enum Tunnel {
@@ -22,31 +24,199 @@ pub struct Argv {
     pub client_id: String,
     pub client_secret: String,
     pub tunnel: Tunnel,
+    pub transport: TransportMode,
+    pub offline_poll: bool,
+    pub poll_interval: u64,
     pub fmt: Formatter,
     pub log_output: String,
     pub log_level: log::LevelFilter,
     pub log_stderr: bool,
+    pub events_json: bool,
+    pub log_max_size: u64,
+    pub log_max_files: u32,
+    pub log_format: logger::Format,
+    pub log_filter: Option<logger::Directives>,
     pub server_port: u16,
     pub save_to_dir: bool,
+    pub compress: Compression,
+    pub metadata: Metadata,
     pub use_extractor: Extractor,
     pub twitch_auth_header: Option<String>,
+    pub twitch_oauth: Option<String>,
+    pub user_token: Option<String>,
+    pub device_login: bool,
+    pub user_token_cache: String,
+    pub irc_nick: Option<String>,
+    pub irc_token: Option<String>,
+    pub chat_overflow: Overflow,
+    pub max_concurrent: Option<usize>,
+    pub max_concurrent_finalize: Option<usize>,
+    pub adaptive_concurrency: bool,
+    pub metrics_file: Option<String>,
+    pub metrics_interval: u64,
+    pub metrics_port: Option<u16>,
+    pub user_cache: Option<String>,
+    pub user_cache_ttl: u64,
+    pub verify_callback: Option<bool>,
+    pub debug_callback: bool,
+    pub remux: Option<String>,
+    pub post_hook: Option<String>,
+    pub notify_url: Option<url::Url>,
+    pub proxy: Option<url::Url>,
+    pub upload: Option<s3::Destination>,
+    pub vod: Option<String>,
+    pub now: Option<String>,
+    pub print_hls: Option<String>,
+    pub list_formats: Option<String>,
+    /// The archive to check against its checksums, instead of running.
+    pub verify: Option<String>,
+    /// The download directory to archive, instead of running.
+    pub tar_existing: Option<String>,
+    /// The subscription list file, if the channels were read from one.
+    pub sub_data: Option<String>,
+    pub single_file: bool,
+    pub sequence_names: bool,
+    pub discard_incomplete: bool,
+    pub resume: bool,
+    pub unique_names: bool,
+    pub keep_chat: bool,
+    pub temp_dir: String,
+    pub min_free_bytes: Option<u64>,
+    pub hls_retry: usize,
+    pub hls_timeout: u64,
     pub channels: Vec<(UserCredentials, ChannelSettings)>,
 }
 
-#[derive(Clone, Deserialize)]
-pub struct ChannelSettings {
-    pub format: String,
+#[derive(Deserialize)]
+struct ChannelDes {
+    #[serde(flatten)]
+    user: UserCredentials,
+    #[serde(flatten)]
+    channel: Option<ChannelSettings>,
+}
+
+/// Every key a subscription list entry may have; the fields of `UserCredentials` and `ChannelSettings`.
+/// `#[serde(flatten)]` cannot deny unknown fields, so entries are checked against this list instead.
+const CHANNEL_KEYS: &[&str] = &[
+    "id",
+    "login",
+    "name",
+    "format",
+    "segment_concurrency",
+    "segment_timeout_secs",
+    "single_file",
+    "chat_format",
+    "chat_timestamp",
+    "chat_vtt",
+    "filename_format",
+    "output",
+    "skip_ads",
+    "max_empty_polls",
+    "only_games",
+    "only_languages",
+    "title_regex",
+];
+
+type ChannelMap = serde_json::Map<String, serde_json::Value>;
+
+/// The contents of the configuration file given by `--config`.
+/// Each field corresponds to the command line argument of the same name.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_secret_file: Option<String>,
+    file_name: Option<String>,
+    log_output: Option<String>,
+    log_level: Option<String>,
+    log_stderr: Option<bool>,
+    events_json: Option<bool>,
+    log_max_size: Option<String>,
+    log_max_files: Option<u32>,
+    log_format: Option<String>,
+    log_filter: Option<String>,
+    server_port: Option<u16>,
+    server_addr: Option<String>,
+    transport: Option<String>,
+    offline_poll: Option<bool>,
+    poll_interval: Option<u64>,
+    sub_data: Option<String>,
+    save_to_dir: Option<bool>,
+    compress: Option<String>,
+    metadata: Option<String>,
+    use_extractor: Option<String>,
+    twitch_auth_header: Option<String>,
+    twitch_oauth: Option<String>,
+    user_token: Option<String>,
+    device_login: Option<bool>,
+    user_token_cache: Option<String>,
+    irc_nick: Option<String>,
+    irc_token: Option<String>,
+    chat_overflow: Option<String>,
+    max_concurrent: Option<usize>,
+    max_concurrent_finalize: Option<usize>,
+    adaptive_concurrency: Option<bool>,
+    single_file: Option<bool>,
+    sequence_names: Option<bool>,
+    discard_incomplete: Option<bool>,
+    resume: Option<bool>,
+    unique_names: Option<bool>,
+    keep_chat: Option<bool>,
+    temp_dir: Option<String>,
+    min_free_bytes: Option<u64>,
+    hls_retry: Option<usize>,
+    hls_timeout: Option<u64>,
+    metrics_file: Option<String>,
+    metrics_interval: Option<u64>,
+    metrics_port: Option<u16>,
+    user_cache: Option<String>,
+    user_cache_ttl: Option<u64>,
+    verify_callback: Option<bool>,
+    debug_callback: Option<bool>,
+    remux: Option<String>,
+    post_hook: Option<String>,
+    notify_url: Option<String>,
+    proxy: Option<String>,
+    s3_endpoint: Option<String>,
+    s3_bucket: Option<String>,
+    s3_region: Option<String>,
+    s3_prefix: Option<String>,
+    s3_keep_local: Option<bool>,
+    s3_access_key: Option<String>,
+    s3_secret_key: Option<String>,
+    channels: Option<Vec<ChannelMap>>,
+}
+
+fn load_config(path: &str) -> Config {
+    let file = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprint_err(&format!("config file is missing or corrupt: {e}"));
+            std::process::exit(2);
+        }
+    };
+
+    match toml::from_str(&file) {
+        Ok(x) => x,
+        Err(e) => {
+            eprint_err(&format!("config file is invalid: {e}"));
+            std::process::exit(2);
+        }
+    }
 }
 
-impl Default for ChannelSettings {
-    fn default() -> Self {
-        Self {
-            format: "best".to_owned(),
+fn read_secret(path: String) -> String {
+    match fs::read_to_string(&path) {
+        Ok(x) => x.trim_end().to_owned(),
+        Err(e) => {
+            eprint_err(&format!("client-secret-file {path:?} could not be read: {e}"));
+            std::process::exit(2);
         }
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[serde(untagged)]
 pub enum UserCredentials {
     Full {
@@ -77,35 +247,176 @@ fn help() -> String {
             \nUSAGE: {} [ARGS]\
             \n\
             \nARGS:\
+            \n  --config             <path> Reads arguments from a toml file, each key named after its argument\
+            \n                              in snake_case, such as `client_id = \"...\"` or `save_to_dir = true`.\
+            \n                              A `[[channels]]` list of channel objects (see below) is used in place\
+            \n                              of `--sub-data` when it is not given, and is not reloaded.\
+            \n                              Arguments given on the command line take precedence over the file,\
+            \n                              as do the `TWITCH_CLIENT_ID`, `TWITCH_CLIENT_SECRET`,\
+            \n                              `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment variables.\
             \n  -C, --client-id      <str>  The client authorization id .\
+            \n                              (Default: the `TWITCH_CLIENT_ID` environment variable)\
             \n  -S, --client-secret  <str>  The client authorization secret .\
+            \n                              (Default: the `TWITCH_CLIENT_SECRET` environment variable)\
+            \n  --client-secret-file <path> Reads the client authorization secret from the given file,\
+            \n                              ignoring trailing whitespace.\
+            \n                              Takes precedence over `TWITCH_CLIENT_SECRET`,\
+            \n                              but not over `--client-secret`.\
             \n  -f, --file-name      <str>  Formats the output file name.\
             \n                              See below for more information.\
             \n                              (Default: \"%Sl/[%si] %st\")\
+            \n  --unique-names              Appends ` [<stream id>]` to file names whose format lacks `%si`,\
+            \n                              instead of a numeric suffix when two streams share a name.\
             \n  --log-output         <path> Write log output to file.\
             \n                              When empty, does not log to file.\
             \n                              (Default: `archive.log`)
             \n  --log-level          <str>  Sets the log level threshold for stdout.\
             \n                              Valid levels are:\
             \n                                `ERROR`, `WARN`, `INFO`, `DEBUG`, `TRACE`, `OFF`\
+            \n  --log-filter         <str>  Sets the log level of each module, in the syntax of `RUST_LOG`,\
+            \n                              such as `hls=trace,surf=warn,info`.\
+            \n                              Modules of twitch-archive may leave out the crate name.\
+            \n                              Overrides `--log-level`, and applies to the log file as well.\
+            \n                              (Default: the `RUST_LOG` environment variable)\
             \n  --log-stderr                Redirects log output to stderr.\
+            \n  --events-json               Writes events, such as streams going online and downloads\
+            \n                              starting, progressing and finishing, to stdout\
+            \n                              as newline-delimited json. Implies `--log-stderr`.\
+            \n  --log-max-size       <size> The size the log file is rolled over at.\
+            \n                              Accepts a number of bytes, optionally suffixed with `K`, `M` or `G`.\
+            \n                              (Default: 1G)\
+            \n  --log-max-files      <u32>  The number of rolled over log files to keep.\
+            \n                              (Default: 16)\
+            \n  --log-format         <str>  The format log output is written in.\
+            \n                              `json` writes a JSON object per line, for log aggregators.\
+            \n                              Valid values are:\
+            \n                                `text`, `json`\
+            \n                              (Default: `text`)\
             \n  -P, --server-port    <u16>  The address for the webhook to listen to.\
             \n                              (Default: 8080)\
             \n  -A, --server-addr    <str>  The host address the server will receive requests to.\
             \n                              If not set, a ngrok tunnel will be set up automatically.\
             \n                              (Default: None)
+            \n  --transport          <str>  The transport events are received with.\
+            \n                              `websocket` does not require a public address,\
+            \n                              but requires `--user-token` or `--device-login` to be set.\
+            \n                              Valid values are:\
+            \n                                `webhook`, `websocket`\
+            \n                              (Default: `webhook`)\
+            \n  --debug-callback            Serves `GET /health`, and reports whether the signature of\
+            \n                              `POST /callback?debug=1` verified instead of handling it,\
+            \n                              logging the received and computed signatures,\
+            \n                              for diagnosing webhook delivery. Do not use in production.\
+            \n  --offline-poll              Polls the streams of every channel instead of subscribing to events,\
+            \n                              for when neither a webhook nor a websocket can be used.\
+            \n                              Streams are picked up up to `--poll-interval` late.\
+            \n  --poll-interval      <u64>  The number of seconds between each poll of `--offline-poll`.\
+            \n                              (Default: 60)\
             \n  -d, --sub-data       <path> The location where the subscription list is saved.\
             \n                              The contents should follow a specific json format;\
             \n                              See below for more information.\
+            \n                              The list is reloaded when the file changes,\
+            \n                              or when SIGHUP is received.\
+            \n                              Reloading adds and removes channels; changed settings\
+            \n                              of a channel already listed apply after a restart.\
             \n                              (Default: `subscriptions.json`)\
             \n  --save-to-dir               Save the output to a directory.\
             \n                              If not set, downloads will be archived to a .tar file.\
+            \n  --compress           <str>  Compresses the .tar archive with the given method.\
+            \n                              Valid values are:\
+            \n                                `none`, `gzip`, `zstd`\
+            \n                              (Default: `none`)\
+            \n  --metadata           <str>  The metadata files written alongside the stream.\
+            \n                              `nfo` also writes a `movie.nfo` for media servers,\
+            \n                              with the stream title as title and plot, the start\
+            \n                              date as premiered, the channel as studio,\
+            \n                              and the games as genres.\
+            \n                              Valid values are:\
+            \n                                `json`, `nfo`\
+            \n                              (Default: `json`)\
+            \n  --single-file               Append segments to a single .ts file per stream,\
+            \n                              instead of writing each segment to its own file.\
+            \n                              Can also be enabled per channel with 'single_file'.\
+            \n  --sequence-names            Name segment files by their media sequence number,\
+            \n                              instead of counting from 0, so that dropped segments are visible.\
+            \n  --discard-incomplete        Delete downloads left incomplete by a previous run,\
+            \n                              instead of archiving them on startup.\
+            \n  --resume                    Continues downloads left incomplete by a previous run\
+            \n                              whose stream is still live, instead of archiving them on startup.\
+            \n  --keep-chat                 Archives the chat log even when no video could be downloaded.\
+            \n                              Downloads with missing video contain an `INCOMPLETE` file.\
+            \n  --temp-dir           <path> Downloads streams to the given directory before archiving.\
+            \n                              (Default: `.download`)\
+            \n  --min-free-bytes     <u64>  Refuses to start a download, and stops running ones,\
+            \n                              when less space is free in the temporary directory.\
+            \n  --max-concurrent   <usize>  The maximum number of streams whose video is downloaded at once.\
+            \n                              Other streams wait for a slot, while still logging chat.\
+            \n  --hls-retry        <usize>  The number of attempts at each playlist and segment request.\
+            \n                              (Default: 10)\
+            \n  --hls-timeout        <u64>  The seconds to wait for a playlist or segment request.\
+            \n                              (Default: 10)\
+            \n  --post-hook          <path> Runs the program after each download is archived, with\
+            \n                              `success` or `failure`, the archive path, the stream id\
+            \n                              and the channel login as arguments.\
+            \n  --notify-url         <url>  Posts a json notification to the url when a stream goes online,\
+            \n                              and when it is archived.\
+            \n  --s3-endpoint        <url>  Uploads archives to an S3-compatible bucket at the endpoint,\
+            \n                              instead of keeping them on local disk.\
+            \n                              Requires `--s3-bucket`, and the `AWS_ACCESS_KEY_ID`\
+            \n                              and `AWS_SECRET_ACCESS_KEY` environment variables.\
+            \n  --s3-bucket          <str>  The bucket archives are uploaded to.\
+            \n  --s3-region          <str>  The region of the bucket.\
+            \n                              (Default: `us-east-1`)\
+            \n  --s3-prefix          <str>  Prepended to the object key of each archive.\
+            \n  --s3-keep-local             Also keeps archives on local disk, uploading them once written.\
+            \n  --proxy              <url>  Tunnels every HTTP request through the given `http` or `https`\
+            \n                              proxy. `NO_PROXY` is not supported.\
+            \n                              (Default: the `HTTPS_PROXY` environment variable)\
             \n  --use-extractor      <str>  Uses the given extractor for extracting m3u8 playlists.\
             \n                              Valid values are:\
             \n                                `internal`, `streamlink`\
-            \n  --twitch-auth-header <str>  Authentication header to pass to streamlink for\
-            \n                              acquiring stream access tokens.\
+            \n  --device-login              Obtains a user access token by authorizing on another device,\
+            \n                              when `--user-token` is not set.\
+            \n  --user-token-cache   <path> Caches the refresh token of `--device-login` to the given file,\
+            \n                              so that authorizing again is not needed on later runs.\
+            \n                              (Default: `user-token.json`)\
+            \n  --irc-nick           <str>  The login to connect to chat as. Requires `--irc-token`.\
+            \n  --irc-token          <str>  The chat OAuth token for `--irc-nick`.\
+            \n                              If not set, chat is read anonymously.\
+            \n  --chat-overflow      <str>  What is done with chat received while the chat log is behind.\
+            \n                              `drop` drops and counts the messages,\
+            \n                              `grow` buffers them in memory without limit.\
+            \n                              Valid values are:\
+            \n                                `drop`, `grow`\
+            \n                              (Default: `drop`)\
+            \n  --metrics-port       <u16>  Serves prometheus metrics at `/metrics` on the given port.\
+            \n  --user-cache         <path> Caches resolved channels to the given file,\
+            \n                              skipping their lookups on startup.\
+            \n  --user-cache-ttl     <u64>  The seconds a cached channel is kept before it is resolved again.\
+            \n                              (Default: 86400)\
+            \n  --vod                <str>  Downloads the VOD with the given video id, then exits.\
+            \n  --now                <str>  Downloads the stream of the given channel if it is live,\
+            \n                              then exits. Does not set up EventSub.\
+            \n  --print-hls          <str>  Prints the master playlist url of the given channel's stream,\
+            \n                              or the reason it could not be obtained, then exits.\
+            \n  --list-formats       <str>  Prints the renditions of the given channel's stream,\
+            \n                              which can be used as its 'format', then exits.\
+            \n  --twitch-auth-header <str>  Authentication header to pass to streamlink's\
+            \n                              `--twitch-api-header` for acquiring stream access tokens,\
+            \n                              such as `Authorization=OAuth <token>`.\
+            \n                              Only used by the `streamlink` extractor,\
+            \n                              in place of `--twitch-oauth` and `--user-token`.\
             \n                              (Default: \"\")\
+            \n  --twitch-oauth       <str>  The OAuth token of a twitch account, sent to the GQL api\
+            \n                              when acquiring stream access tokens, so that sub-only\
+            \n                              streams the account is subscribed to can be archived.\
+            \n                              Used by both extractors, in place of `--user-token`;\
+            \n                              `--twitch-auth-header` is used instead when set.\
+            \n  --tar-existing       <path> Archives a finished download directory, such as one saved\
+            \n                              with `--save-to-dir`, to a .tar file next to it,\
+            \n                              compressed with `--compress`, then removes the directory and exits.\
+            \n  --verify             <path> Checks the files of an archive against its SHA256SUMS,\
+            \n                              then exits.\
             \n  --version                   Prints the program version.\
             \n  -h, --help                  Prints this help message.\
             \n\
@@ -117,8 +428,51 @@ fn help() -> String {
             \n  'id':         <str>     The streamer id to subscribe to.\
             \n  'login':      <str>     The streamer login to subscribe to.\
             \n  'format':     <str>     The download quality the stream should be downloaded at.\
-            \n                          This value should be either 'video' for videos,\
-            \n                          or 'audio' for audios. (Default: 'video')\
+            \n                          A comma separated list of qualities, tried in order:\
+            \n                            'best', 'worst', 'audio_only' or 'audio', a resolution ('720p'),\
+            \n                            a maximum resolution ('<=720p'), or a rendition name.\
+            \n                          A json list of these downloads each as its own rendition,\
+            \n                          in a subdirectory named after the rendition.\
+            \n                          (Default: 'best')\
+            \n  'segment_concurrency':\
+            \n                <usize>   The number of segments downloaded at once, up to 32.\
+            \n                          (Default: 6)\
+            \n  'segment_timeout_secs':\
+            \n                <u64>     The seconds to wait for a new segment before the stream\
+            \n                          is considered ended. Must be greater than 0.\
+            \n                          (Default: 300)\
+            \n  'single_file':\
+            \n                <bool>    Appends segments to a single .ts file, like `--single-file`.\
+            \n  'chat_format':\
+            \n                <str>     The format chat is written in.\
+            \n                          'raw' writes every IRC line to `chat.log`,\
+            \n                          'json' writes messages as json lines to `chat.jsonl`.\
+            \n                          (Default: 'raw')\
+            \n  'chat_timestamp':\
+            \n                <str>     Prefixes each line of the 'raw' chat format with the local time\
+            \n                          ('wallclock'), or the seconds since the stream started ('relative').\
+            \n                          Valid values are:\
+            \n                            'none', 'wallclock', 'relative'\
+            \n                          (Default: 'none')\
+            \n  'filename_format':\
+            \n                <str>     Formats the output file name of this channel's streams,\
+            \n                          in place of `--file-name`.\
+            \n  'output':     <str>     How this channel's streams are stored, in place of `--save-to-dir`.\
+            \n                          Valid values are:\
+            \n                            'dir', 'tar'\
+            \n  'chat_vtt':   <bool>    Also writes chat as WebVTT subtitles to `chat.vtt`,\
+            \n                          timed from the start of the stream.\
+            \n  'skip_ads':   <bool>    Leaves out the ads stitched into the stream.\
+            \n                          (Default: true)\
+            \n  'max_empty_polls':\
+            \n                <u32>     Finishes the download after this many consecutive playlist polls\
+            \n                          returned no segments or failed, for streams which drop out often.\
+            \n                          If not set, only the segment timeout ends the download.\
+            \n  'only_games': <list>    Only archives streams in one of these games, by name or id.\
+            \n  'only_languages':\
+            \n                <list>    Only archives streams in one of these languages, such as 'en'.\
+            \n  'title_regex':\
+            \n                <str>     Only archives streams whose title matches this regular expression.\
             \n\
             \nThe subscription list file is a json list of the above channel object.\
             \n\
@@ -126,7 +480,7 @@ fn help() -> String {
             \n  [\
             \n    {{\"id\": \"0000000\"}},\
             \n    {{\"id\": \"0000001\", \"format\": \"audio\"}},\
-            \n    {{\"login\": \"twitch\"}},\
+            \n    {{\"login\": \"twitch\", \"format\": [\"best\", \"audio_only\"]}},\
             \n  ]\
             \n\
             \nFILE NAME FORMATTING:\
@@ -144,10 +498,15 @@ fn help() -> String {
             \n  %TH: Stream start hour, 2 digits, 24-hours, Local time\
             \n  %Tm: Stream start minute, 2 digits, Local time\
             \n  %TZ: Local date timezone\
+            \n  %T{{...}}: Stream start time, in the strftime format between the braces, Local time,\
+            \n           such as `%T{{%Y-%m-%d %H.%M}}`\
             \n\
             \n  %si: Stream ID\
             \n  %st: Stream Name\
             \n\
+            \n  %gi: Game ID, or `unknown` if the stream has no game\
+            \n  %gn: Game Name, or `unknown` if the stream has no game\
+            \n\
             \n  %%: Escape (\"%\")",
             info(), NAME.get().unwrap()
         )
@@ -165,28 +524,128 @@ fn type_err(t: &str, x: &str) {
     eprint_err(&format!("<{}> expected after {:?}", t, x));
 }
 
+/// Parses a number of bytes, such as `4096`, `100M` or `2G`.
+/// Suffixes are binary, so `1K` is 1024 bytes.
+fn parse_size(x: &str) -> Option<u64> {
+    let x = x.trim();
+    let (num, shift) = match x.char_indices().last()? {
+        (i, 'K' | 'k') => (&x[..i], 10),
+        (i, 'M' | 'm') => (&x[..i], 20),
+        (i, 'G' | 'g') => (&x[..i], 30),
+        _ => (x, 0),
+    };
+    num.trim().parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
 pub fn parse_args() -> Argv {
-    let mut argv = env::args();
+    let args: Vec<String> = env::args().collect();
+    let mut argv = args.iter().cloned();
 
     let name = argv.next().unwrap();
     NAME.set(name.into()).unwrap();
 
+    let config = match args.iter().position(|x| x == "--config") {
+        Some(i) => match args.get(i + 1) {
+            Some(x) => load_config(x),
+            None => {
+                type_err("path", "--config");
+                std::process::exit(1);
+            }
+        },
+        None => Config::default(),
+    };
+
     let mut client_id = None;
     let mut client_secret = None;
+    let mut client_secret_file = None;
 //    let mut ngrok_authtoken = None;
-    let mut file_name = "%Sl/[%si] %st".to_owned();
-    let mut log_output = "archive.log".to_owned();
-    let mut log_level = log::LevelFilter::Info;
-    let mut log_stderr = false;
-    let mut server_port = 8080;
-    let mut server_addr = None;
-    let mut sub_data = "subscriptions.json".to_owned();
-    let mut save_to_dir = false;
-    let mut use_extractor = "internal".to_string();
-    let mut twitch_auth_header = None;
+    let mut file_name = config.file_name.unwrap_or_else(|| "%Sl/[%si] %st".to_owned());
+    let mut log_output = config.log_output.unwrap_or_else(|| "archive.log".to_owned());
+    let mut log_level = match config.log_level {
+        Some(x) => x.parse().unwrap_or_else(|_| {
+            eprint_err(&format!("unexpected value for `log_level`: {x}"));
+            std::process::exit(1);
+        }),
+        None => log::LevelFilter::Info,
+    };
+    let mut log_stderr = config.log_stderr.unwrap_or(false);
+    let mut events_json = config.events_json.unwrap_or(false);
+    let mut log_max_size = match config.log_max_size {
+        Some(x) => parse_size(&x).filter(|x| *x > 0).unwrap_or_else(|| {
+            eprint_err(&format!("unexpected value for `log_max_size`: {x}"));
+            std::process::exit(1);
+        }),
+        None => logger::DEFAULT_MAX_SIZE,
+    };
+    let mut log_max_files = config
+        .log_max_files
+        .filter(|x| *x > 0)
+        .unwrap_or(logger::DEFAULT_MAX_FILES);
+    let mut log_format = config.log_format.unwrap_or_else(|| "text".to_owned());
+    let mut log_filter = config.log_filter.or_else(|| env::var("RUST_LOG").ok());
+    let mut server_port = config.server_port.unwrap_or(8080);
+    let mut server_addr = config.server_addr;
+    let mut transport = config.transport.unwrap_or_else(|| "webhook".to_owned());
+    let mut offline_poll = config.offline_poll.unwrap_or(false);
+    let mut poll_interval = config.poll_interval.filter(|x| *x > 0).unwrap_or(60);
+    let mut sub_data = config.sub_data;
+    let mut save_to_dir = config.save_to_dir.unwrap_or(false);
+    let mut compress = config.compress.unwrap_or_else(|| "none".to_owned());
+    let mut metadata = config.metadata.unwrap_or_else(|| "json".to_owned());
+    let mut use_extractor = config.use_extractor.unwrap_or_else(|| "internal".to_string());
+    let mut twitch_auth_header = config.twitch_auth_header;
+    let mut twitch_oauth = config.twitch_oauth;
+    let mut user_token = config.user_token;
+    let mut device_login = config.device_login.unwrap_or(false);
+    let mut user_token_cache = config.user_token_cache.unwrap_or_else(|| "user-token.json".to_owned());
+    let mut irc_nick = config.irc_nick;
+    let mut irc_token = config.irc_token;
+    let mut chat_overflow = config.chat_overflow.unwrap_or_else(|| "drop".to_owned());
+    let mut max_concurrent = config.max_concurrent.filter(|x| *x > 0);
+    let mut max_concurrent_finalize = config.max_concurrent_finalize.filter(|x| *x > 0);
+    let mut adaptive_concurrency = config.adaptive_concurrency.unwrap_or(false);
+    let mut single_file = config.single_file.unwrap_or(false);
+    let mut sequence_names = config.sequence_names.unwrap_or(false);
+    let mut discard_incomplete = config.discard_incomplete.unwrap_or(false);
+    let mut resume = config.resume.unwrap_or(false);
+    let mut unique_names = config.unique_names.unwrap_or(false);
+    let mut keep_chat = config.keep_chat.unwrap_or(false);
+    let mut temp_dir = config.temp_dir.unwrap_or_else(|| ".download".to_owned());
+    let mut min_free_bytes = config.min_free_bytes.filter(|x| *x > 0);
+    let mut hls_retry = config.hls_retry.filter(|x| *x > 0).unwrap_or(hls::REQUEST_RETRY);
+    let mut hls_timeout = config
+        .hls_timeout
+        .filter(|x| *x > 0)
+        .unwrap_or(hls::REQUEST_TIMEOUT.as_secs());
+    let mut metrics_file = config.metrics_file;
+    let mut metrics_interval = config.metrics_interval.filter(|x| *x > 0).unwrap_or(60);
+    let mut metrics_port = config.metrics_port;
+    let mut user_cache = config.user_cache;
+    let mut user_cache_ttl = config.user_cache_ttl.filter(|x| *x > 0).unwrap_or(86400);
+    let mut verify_callback = config.verify_callback;
+    let mut debug_callback = config.debug_callback.unwrap_or(false);
+    let mut remux = config.remux;
+    let mut post_hook = config.post_hook;
+    let mut notify_url = config.notify_url;
+    let mut proxy = config.proxy;
+    let mut s3_endpoint = config.s3_endpoint;
+    let mut s3_bucket = config.s3_bucket;
+    let mut s3_region = config.s3_region.unwrap_or_else(|| "us-east-1".to_owned());
+    let mut s3_prefix = config.s3_prefix.unwrap_or_default();
+    let mut s3_keep_local = config.s3_keep_local.unwrap_or(false);
+    let mut vod = None;
+    let mut now = None;
+    let mut print_hls = None;
+    let mut list_formats = None;
+    let mut tar_existing = None;
+    let mut verify = None;
 
     while let Some(x) = argv.next() {
         match x.as_str() {
+            "--config" => {
+                // already read before parsing other arguments
+                argv.next();
+            }
             "-C" | "--client-id" => {
                 client_id = if let Some(x) = argv.next() {
                     Some(x)
@@ -203,6 +662,14 @@ pub fn parse_args() -> Argv {
                     std::process::exit(1);
                 }
             }
+            "--client-secret-file" => {
+                client_secret_file = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("path", &x);
+                    std::process::exit(1);
+                }
+            }
 //            "-N" | "--ngrok-authtoken" => {
 //                ngrok_authtoken = if let Some(x) = argv.next() {
 //                    Some(x)
@@ -228,6 +695,79 @@ pub fn parse_args() -> Argv {
                 }
             }
             "--log-stderr" => log_stderr = true,
+            "--events-json" => events_json = true,
+            "--log-max-size" => {
+                log_max_size = if let Some(x) = argv.next().and_then(|x| parse_size(&x)).filter(|x| *x > 0) {
+                    x
+                } else {
+                    type_err("size", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--log-filter" => {
+                log_filter = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--log-format" => {
+                log_format = if let Some(x) = argv.next() {
+                    x
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--log-max-files" => {
+                log_max_files = if let Some(x) = argv.next().and_then(|x| x.parse().ok()).filter(|x| *x > 0) {
+                    x
+                } else {
+                    type_err("u32", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--metrics-file" => {
+                metrics_file = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("path", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--user-cache" => {
+                user_cache = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("path", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--user-cache-ttl" => {
+                user_cache_ttl = if let Some(x) = argv.next().and_then(|x| x.parse().ok()).filter(|x| *x > 0) {
+                    x
+                } else {
+                    type_err("u64", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--metrics-interval" => {
+                metrics_interval = if let Some(x) = argv.next().and_then(|x| x.parse().ok()).filter(|x| *x > 0) {
+                    x
+                } else {
+                    type_err("u64", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--metrics-port" => {
+                metrics_port = if let Some(x) = argv.next().and_then(|x| x.parse().ok()) {
+                    Some(x)
+                } else {
+                    type_err("u16", &x);
+                    std::process::exit(1);
+                }
+            }
             "-P" | "--server-port" => {
                 server_port = if let Some(x) = argv.next().and_then(|x| x.parse().ok()) {
                     x
@@ -244,9 +784,12 @@ pub fn parse_args() -> Argv {
                     std::process::exit(1);
                 }
             }
+            "--verify-callback" => verify_callback = Some(true),
+            "--no-verify-callback" => verify_callback = Some(false),
+            "--debug-callback" => debug_callback = true,
             "-d" | "--sub-data" => {
                 sub_data = if let Some(x) = argv.next() {
-                    x
+                    Some(x)
                 } else {
                     type_err("path", &x);
                     std::process::exit(1);
@@ -261,6 +804,199 @@ pub fn parse_args() -> Argv {
                 }
             }
             "--save-to-dir" => save_to_dir = true,
+            "--compress" => {
+                compress = if let Some(x) = argv.next() {
+                    x
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--chat-overflow" => {
+                chat_overflow = if let Some(x) = argv.next() {
+                    x
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--metadata" => {
+                metadata = if let Some(x) = argv.next() {
+                    x
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--remux" => {
+                remux = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--post-hook" => {
+                post_hook = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("path", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--notify-url" => {
+                notify_url = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("url", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--proxy" => {
+                proxy = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("url", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--s3-endpoint" => {
+                s3_endpoint = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("url", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--s3-bucket" => {
+                s3_bucket = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--s3-region" => {
+                s3_region = if let Some(x) = argv.next() {
+                    x
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--s3-prefix" => {
+                s3_prefix = if let Some(x) = argv.next() {
+                    x
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--s3-keep-local" => s3_keep_local = true,
+            "--max-concurrent" => {
+                max_concurrent = if let Some(x) = argv.next().and_then(|x| x.parse().ok()).filter(|x| *x > 0) {
+                    Some(x)
+                } else {
+                    type_err("usize", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--max-concurrent-finalize" => {
+                max_concurrent_finalize = if let Some(x) = argv.next().and_then(|x| x.parse().ok()).filter(|x| *x > 0) {
+                    Some(x)
+                } else {
+                    type_err("usize", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--adaptive-concurrency" => adaptive_concurrency = true,
+            "--single-file" => single_file = true,
+            "--sequence-names" => sequence_names = true,
+            "--discard-incomplete" => discard_incomplete = true,
+            "--resume" => resume = true,
+            "--unique-names" => unique_names = true,
+            "--keep-chat" => keep_chat = true,
+            "--temp-dir" => {
+                temp_dir = if let Some(x) = argv.next() {
+                    x
+                } else {
+                    type_err("path", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--hls-retry" => {
+                hls_retry = if let Some(x) = argv.next().and_then(|x| x.parse().ok()).filter(|x| *x > 0) {
+                    x
+                } else {
+                    type_err("usize", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--hls-timeout" => {
+                hls_timeout = if let Some(x) = argv.next().and_then(|x| x.parse().ok()).filter(|x| *x > 0) {
+                    x
+                } else {
+                    type_err("u64", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--min-free-bytes" => {
+                min_free_bytes = if let Some(x) = argv.next().and_then(|x| x.parse().ok()).filter(|x| *x > 0) {
+                    Some(x)
+                } else {
+                    type_err("u64", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--vod" => {
+                vod = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--print-hls" => {
+                print_hls = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--list-formats" => {
+                list_formats = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--now" => {
+                now = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--transport" => {
+                transport = if let Some(x) = argv.next() {
+                    x
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--offline-poll" => offline_poll = true,
+            "--poll-interval" => {
+                poll_interval = if let Some(x) = argv.next().and_then(|x| x.parse().ok()).filter(|x| *x > 0) {
+                    x
+                } else {
+                    type_err("u64", &x);
+                    std::process::exit(1);
+                }
+            }
             "--use-extractor" => {
                 use_extractor = if let Some(x) = argv.next() {
                     x
@@ -277,6 +1013,63 @@ pub fn parse_args() -> Argv {
                     std::process::exit(1);
                 }
             }
+            "--twitch-oauth" => {
+                twitch_oauth = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--irc-nick" => {
+                irc_nick = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--irc-token" => {
+                irc_token = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--user-token" => {
+                user_token = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("str", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--device-login" => device_login = true,
+            "--user-token-cache" => {
+                user_token_cache = if let Some(x) = argv.next() {
+                    x
+                } else {
+                    type_err("path", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--tar-existing" => {
+                tar_existing = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("path", &x);
+                    std::process::exit(1);
+                }
+            }
+            "--verify" => {
+                verify = if let Some(x) = argv.next() {
+                    Some(x)
+                } else {
+                    type_err("path", &x);
+                    std::process::exit(1);
+                }
+            }
             "--version" => {
                 println!("{}", VERSION);
                 std::process::exit(0);
@@ -292,11 +1085,35 @@ pub fn parse_args() -> Argv {
         }
     }
 
-    let Some(client_id) = client_id else {
+    let compress = match compress.to_lowercase().as_str() {
+        "none" => Compression::None,
+        "gzip" => Compression::Gzip,
+        "zstd" => Compression::Zstd,
+        x => {
+            eprint_err(&format!("unexpected value for `--compress`: {x}"));
+            std::process::exit(1);
+        }
+    };
+
+    let client_id = client_id
+        .or_else(|| env::var("TWITCH_CLIENT_ID").ok())
+        .or(config.client_id);
+    let client_secret = client_secret
+        .or_else(|| client_secret_file.map(read_secret))
+        .or_else(|| env::var("TWITCH_CLIENT_SECRET").ok())
+        .or(config.client_secret)
+        .or_else(|| config.client_secret_file.map(read_secret));
+
+    // verifying an archive, archiving an existing download or inspecting a playlist does not need credentials
+    let no_auth = || {
+        (verify.is_some() || tar_existing.is_some() || print_hls.is_some() || list_formats.is_some())
+            .then(String::new)
+    };
+    let Some(client_id) = client_id.or_else(no_auth) else {
         eprint_err("client-id missing!");
         std::process::exit(1);
     };
-    let Some(client_secret) = client_secret else {
+    let Some(client_secret) = client_secret.or_else(no_auth) else {
         eprint_err("client-secret missing!");
         std::process::exit(1);
     };
@@ -312,15 +1129,15 @@ pub fn parse_args() -> Argv {
         Some(addr) => Tunnel::Provided(addr),
         None => Tunnel::Wrapper
     };
-    if file_name.is_empty() {
-        eprint_err("File names cannot be an empty string!");
-        std::process::exit(1);
-    };
-    let sub = match fs::read(sub_data) {
+    let fmt = match Formatter::new(&file_name) {
         Ok(x) => x,
         Err(e) => {
-            eprint_err(&format!("sub-data file is missing or corrupt: {e}"));
-            std::process::exit(2);
+            let errors: Vec<String> = e.iter().map(ToString::to_string).collect();
+            eprint_err(&format!(
+                "file name format {file_name:?} is invalid:\n  {}",
+                errors.join("\n  ")
+            ));
+            std::process::exit(1);
         }
     };
     let use_extractor = match use_extractor.to_lowercase().as_str() {
@@ -332,33 +1149,269 @@ pub fn parse_args() -> Argv {
         }
     };
 
-    #[derive(Deserialize)]
-    struct ChannelDes {
-        #[serde(flatten)]
-        user: UserCredentials,
-        #[serde(flatten)]
-        channel: Option<ChannelSettings>,
+    if irc_token.is_some() && irc_nick.is_none() {
+        eprint_err("`--irc-token` requires `--irc-nick` to be set!");
+        std::process::exit(1);
+    }
+
+    let log_format = match log_format.to_lowercase().as_str() {
+        "text" => logger::Format::Text,
+        "json" => logger::Format::Json,
+        x => {
+            eprint_err(&format!("unexpected value for `--log-format`: {x}"));
+            std::process::exit(1);
+        }
+    };
+
+    let log_filter = log_filter.filter(|x| !x.trim().is_empty()).map(|x| {
+        x.parse().unwrap_or_else(|e| {
+            eprint_err(&format!("unexpected value for `--log-filter`: {e}"));
+            std::process::exit(1);
+        })
+    });
+
+    let chat_overflow = match chat_overflow.to_lowercase().as_str() {
+        "drop" => Overflow::Drop,
+        "grow" => Overflow::Grow,
+        x => {
+            eprint_err(&format!("unexpected value for `--chat-overflow`: {x}"));
+            std::process::exit(1);
+        }
+    };
+
+    let metadata = match metadata.to_lowercase().as_str() {
+        "json" => Metadata::Json,
+        "nfo" => Metadata::Nfo,
+        x => {
+            eprint_err(&format!("unexpected value for `--metadata`: {x}"));
+            std::process::exit(1);
+        }
+    };
+
+    let transport = match transport.to_lowercase().as_str() {
+        "webhook" => TransportMode::Webhook,
+        "websocket" => TransportMode::Websocket,
+        x => {
+            eprint_err(&format!("unexpected value for `--transport`: {x}"));
+            std::process::exit(1);
+        }
+    };
+    if matches!(transport, TransportMode::Websocket) && !offline_poll && user_token.is_none() && !device_login {
+        eprint_err("`--transport websocket` requires `--user-token` or `--device-login` to be set!");
+        std::process::exit(1);
+    }
+
+    let notify_url = notify_url.map(|x| match x.parse() {
+        Ok(x) => x,
+        Err(e) => {
+            eprint_err(&format!("unexpected value for `--notify-url`: {e}"));
+            std::process::exit(1);
+        }
+    });
+
+    let proxy = proxy.map(|x| match x.parse::<url::Url>() {
+        Ok(x) if net::PROXY_SCHEMES.contains(&x.scheme()) => x,
+        Ok(x) => {
+            eprint_err(&format!(
+                "unsupported scheme for `--proxy`: {}; expected `http` or `https`",
+                x.scheme()
+            ));
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprint_err(&format!("unexpected value for `--proxy`: {e}"));
+            std::process::exit(1);
+        }
+    });
+
+    let upload = match (s3_endpoint, s3_bucket) {
+        (None, None) => None,
+        (Some(endpoint), Some(bucket)) => {
+            let endpoint = match endpoint.parse() {
+                Ok(x) => x,
+                Err(e) => {
+                    eprint_err(&format!("unexpected value for `--s3-endpoint`: {e}"));
+                    std::process::exit(1);
+                }
+            };
+            let access_key = env::var("AWS_ACCESS_KEY_ID").ok().or(config.s3_access_key);
+            let secret_key = env::var("AWS_SECRET_ACCESS_KEY").ok().or(config.s3_secret_key);
+            let (Some(access_key), Some(secret_key)) = (access_key, secret_key) else {
+                eprint_err("s3 credentials missing! set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.");
+                std::process::exit(1);
+            };
+
+            Some(s3::Destination {
+                bucket: s3::Bucket::new(endpoint, bucket, s3_region, access_key, secret_key),
+                prefix: s3_prefix,
+                keep_local: s3_keep_local,
+            })
+        }
+        _ => {
+            eprint_err("`--s3-endpoint` and `--s3-bucket` must be set together!");
+            std::process::exit(1);
+        }
+    };
+
+    let remux = remux.map(|x| x.to_lowercase());
+    if let Some(x) = remux.as_deref().filter(|x| !matches!(*x, "mp4" | "mkv")) {
+        eprint_err(&format!("unexpected value for `--remux`: {x}"));
+        std::process::exit(1);
     }
 
-    let channels: Vec<ChannelDes> =
-        serde_json::from_slice(&sub).expect("Subscription list data is invalid!");
+    // only a subscription list read from a file can be reloaded
+    let (channels, sub_data) = match (sub_data, config.channels) {
+        // one-shot downloads do not need a subscription list
+        _ if vod.is_some()
+            || now.is_some()
+            || print_hls.is_some()
+            || list_formats.is_some()
+            || verify.is_some()
+            || tar_existing.is_some() =>
+        {
+            (Ok(Vec::new()), None)
+        }
+        (None, Some(x)) => (
+            x.into_iter()
+                .enumerate()
+                .map(|(i, c)| channel_settings(i, c, single_file))
+                .collect(),
+            None,
+        ),
+        (sub_data, _) => {
+            let path = sub_data.unwrap_or_else(|| "subscriptions.json".to_owned());
+            (read_channels(&path, single_file), Some(path))
+        }
+    };
+    let channels = match channels {
+        Ok(x) => x,
+        Err(e) => {
+            eprint_err(&format!("{e:#}"));
+            std::process::exit(2);
+        }
+    };
     log::info!("Retrieved {} subscription target(s)", channels.len());
 
     Argv {
         client_id,
         client_secret,
         tunnel,
+        offline_poll,
+        poll_interval,
+        transport,
         log_output,
         log_level,
-        log_stderr,
+        // stdout is left to the events
+        log_stderr: log_stderr || events_json,
+        events_json,
+        log_max_size,
+        log_max_files,
+        log_format,
+        log_filter,
         server_port,
-        fmt: Formatter::new(&file_name),
+        fmt,
         save_to_dir,
+        compress,
+        metadata,
         use_extractor,
         twitch_auth_header,
-        channels: channels
-            .into_iter()
-            .map(|c| (c.user, c.channel.unwrap_or_default()))
-            .collect(),
+        twitch_oauth,
+        user_token,
+        device_login,
+        user_token_cache,
+        irc_nick,
+        irc_token,
+        chat_overflow,
+        max_concurrent,
+        max_concurrent_finalize,
+        adaptive_concurrency,
+        metrics_file,
+        metrics_interval,
+        metrics_port,
+        user_cache,
+        user_cache_ttl,
+        verify_callback,
+        debug_callback,
+        remux,
+        post_hook,
+        notify_url,
+        proxy,
+        upload,
+        vod,
+        now,
+        print_hls,
+        list_formats,
+        verify,
+        tar_existing,
+        sub_data,
+        single_file,
+        sequence_names,
+        discard_incomplete,
+        resume,
+        unique_names,
+        keep_chat,
+        temp_dir,
+        min_free_bytes,
+        hls_retry,
+        hls_timeout,
+        channels,
+    }
+}
+
+/// Reads the subscription list at `path`.
+/// `single_file` is the value of `--single-file`, which applies to every channel.
+pub fn read_channels(
+    path: &str,
+    single_file: bool,
+) -> Result<Vec<(UserCredentials, ChannelSettings)>> {
+    let sub = fs::read(path).map_err(|e| anyhow!("sub-data file is missing or corrupt: {e}"))?;
+    let channels: Vec<ChannelMap> = serde_json::from_slice(&sub)
+        .map_err(|e| anyhow!("Subscription list data is invalid: {e}"))?;
+
+    channels
+        .into_iter()
+        .enumerate()
+        .map(|(i, c)| channel_settings(i, c, single_file))
+        .collect()
+}
+
+/// Parses the `i`th entry of the subscription list, warning about any keys which would be ignored.
+fn channel_settings(
+    i: usize,
+    c: ChannelMap,
+    single_file: bool,
+) -> Result<(UserCredentials, ChannelSettings)> {
+    let unknown: Vec<&str> = c
+        .keys()
+        .map(String::as_str)
+        .filter(|x| !CHANNEL_KEYS.contains(x))
+        .collect();
+    if !unknown.is_empty() {
+        eprintln!(
+            "WARNING: subscription list entry #{} has unknown field(s), which are ignored: {}",
+            i + 1,
+            unknown.join(", ")
+        );
+    }
+
+    let c: ChannelDes = serde_json::from_value(serde_json::Value::Object(c))
+        .map_err(|e| anyhow!("Subscription list entry #{} is invalid: {e}", i + 1))?;
+
+    let mut settings = c.channel.unwrap_or_default();
+    settings.single_file |= single_file;
+    if settings.segment_concurrency == 0 {
+        return Err(anyhow!("`segment_concurrency` must be greater than 0!"));
+    }
+    if settings.segment_concurrency > hls::MAX_SEGMENT_CONCURRENCY {
+        eprintln!(
+            "WARNING: `segment_concurrency` {} is too large; clamping to {}",
+            settings.segment_concurrency,
+            hls::MAX_SEGMENT_CONCURRENCY
+        );
+        settings.segment_concurrency = hls::MAX_SEGMENT_CONCURRENCY;
+    }
+    if settings.segment_timeout_secs == 0 {
+        return Err(anyhow!("`segment_timeout_secs` must be greater than 0!"));
     }
+    Ok((c.user, settings))
 }
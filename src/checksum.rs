@@ -0,0 +1,284 @@
+//! SHA-256 checksums of the files in a download, computed while they are written.
+
+use anyhow::Context;
+use async_std::{fs, io, path};
+use futures::{AsyncRead, AsyncWrite, StreamExt};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context as TaskContext, Poll},
+};
+
+use crate::{fs_utils, prelude::*};
+
+/// The checksum file written to the root of each archive, in the format of `sha256sum`.
+pub const SUMS_FILE: &str = "SHA256SUMS";
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(Sha256::digest(data))
+}
+
+fn hex(digest: impl AsRef<[u8]>) -> String {
+    use std::fmt::Write;
+
+    let mut hex = String::with_capacity(64);
+    for b in digest.as_ref() {
+        let _ = write!(hex, "{b:02x}");
+    }
+    hex
+}
+
+/// A writer which hashes everything written through it.
+#[derive(Debug)]
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The hex-encoded SHA-256 of everything written so far.
+    pub fn sha256(&self) -> String {
+        hex(self.hasher.clone().finalize())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashWriter<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let n = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.hasher.update(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Hashes the remaining contents of `reader`.
+async fn hash_reader(reader: impl AsyncRead + Unpin) -> io::Result<String> {
+    let mut writer = HashWriter::new(futures::io::sink());
+    futures::io::copy(reader, &mut writer).await?;
+    Ok(writer.sha256())
+}
+
+/// Hashes the file at `path` by reading it back,
+/// for files written by other programs.
+pub async fn hash_file(path: &path::Path) -> io::Result<String> {
+    hash_reader(fs::File::open(path).await?).await
+}
+
+/// The checksums of the files in a download directory, keyed by their path relative to it.
+///
+/// Files are hashed by whoever writes them, and written again replace their previous checksum.
+#[derive(Clone, Debug)]
+pub struct Checksums {
+    base: Arc<path::Path>,
+    sums: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl Checksums {
+    pub fn new(base: &path::Path) -> Self {
+        Self {
+            base: base.into(),
+            sums: Default::default(),
+        }
+    }
+
+    fn key(&self, path: &path::Path) -> Option<String> {
+        let rel = path.strip_prefix(&self.base).ok()?;
+        // the separator is fixed, so that the list is the same on every platform
+        let parts: Vec<_> = rel.iter().map(|x| x.to_string_lossy()).collect();
+        Some(parts.join("/"))
+    }
+
+    pub fn insert(&self, path: &path::Path, sha256: String) {
+        match self.key(path) {
+            Some(x) => {
+                self.sums.lock().unwrap().insert(x, sha256);
+            }
+            None => log::debug!("not recording checksum of {} outside of the download", path.display()),
+        }
+    }
+
+    pub fn insert_bytes(&self, path: &path::Path, data: &[u8]) {
+        self.insert(path, sha256_hex(data));
+    }
+
+    /// Removes the checksum of the file at `path`, or of every file below it.
+    pub fn remove(&self, path: &path::Path) {
+        let Some(key) = self.key(path) else { return };
+        let dir = format!("{key}/");
+        self.sums
+            .lock()
+            .unwrap()
+            .retain(|x, _| *x != key && !x.starts_with(&dir));
+    }
+
+    /// Hashes every file in the download directory again, replacing the recorded checksums,
+    /// for a download continued from a previous run, whose earlier files were hashed by that run.
+    pub async fn rehash(&self) -> io::Result<()> {
+        let mut sums = BTreeMap::new();
+        let mut stack = vec![self.base.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    stack.push(path);
+                    continue;
+                }
+
+                if let Some(key) = self.key(&path).filter(|x| x != SUMS_FILE) {
+                    sums.insert(key, hash_file(&path).await?);
+                }
+            }
+        }
+
+        *self.sums.lock().unwrap() = sums;
+        Ok(())
+    }
+
+    /// Writes the checksum file to the download directory.
+    pub async fn write(&self) -> io::Result<()> {
+        let mut data = String::new();
+        for (path, sha256) in self.sums.lock().unwrap().iter() {
+            data += &format!("{sha256}  {path}\n");
+        }
+
+        fs_utils::write_atomic(&self.base.join(SUMS_FILE), data.as_bytes()).await
+    }
+}
+
+/// The result of checking an archive against its checksum file.
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// The number of files whose checksum matched.
+    pub ok: usize,
+    /// Files whose contents do not match their checksum.
+    pub mismatched: Vec<String>,
+    /// Files listed in the checksum file, but missing from the archive.
+    pub missing: Vec<String>,
+}
+
+impl Verification {
+    pub fn passed(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty()
+    }
+}
+
+fn parse_sums(data: &str) -> Result<BTreeMap<String, String>> {
+    data.lines()
+        .filter(|x| !x.is_empty())
+        .map(|line| {
+            let (sha256, path) = line
+                .split_once("  ")
+                .ok_or_else(|| anyhow!("malformed line in {SUMS_FILE}: {line:?}"))?;
+            Ok((path.to_owned(), sha256.to_owned()))
+        })
+        .collect()
+}
+
+fn compare(expected: BTreeMap<String, String>, actual: &BTreeMap<String, String>) -> Verification {
+    let mut res = Verification::default();
+    for (path, sha256) in expected {
+        match actual.get(&path) {
+            Some(x) if *x == sha256 => res.ok += 1,
+            Some(_) => res.mismatched.push(path),
+            None => res.missing.push(path),
+        }
+    }
+    res
+}
+
+/// Checks the files of an archive against its checksum file.
+/// The archive is either a directory, or a tar archive which may be compressed.
+pub async fn verify(archive: &path::Path) -> Result<Verification> {
+    if archive.is_dir().await {
+        return verify_dir(archive).await;
+    }
+
+    let file = fs::File::open(archive)
+        .await
+        .with_context(|| format!("could not open {}", archive.display()))?;
+    let name = archive.to_string_lossy();
+    if name.ends_with(".tar.gz") {
+        let reader = async_compression::futures::bufread::GzipDecoder::new(io::BufReader::new(file));
+        verify_tar(reader).await
+    } else if name.ends_with(".tar.zst") {
+        let reader = async_compression::futures::bufread::ZstdDecoder::new(io::BufReader::new(file));
+        verify_tar(reader).await
+    } else {
+        verify_tar(file).await
+    }
+}
+
+async fn verify_dir(dir: &path::Path) -> Result<Verification> {
+    let data = fs::read_to_string(dir.join(SUMS_FILE))
+        .await
+        .with_context(|| format!("could not read {SUMS_FILE}"))?;
+    let expected = parse_sums(&data)?;
+
+    let mut actual = BTreeMap::new();
+    for path in expected.keys() {
+        match hash_file(&dir.join(path)).await {
+            Ok(x) => {
+                actual.insert(path.clone(), x);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| format!("could not read {path}")),
+        }
+    }
+
+    Ok(compare(expected, &actual))
+}
+
+/// Hashes every file in the tar archive, as the checksum file may come after the files it lists.
+async fn verify_tar(reader: impl AsyncRead + Unpin + Send + Sync + 'static) -> Result<Verification> {
+    let mut entries = async_tar::Archive::new(reader)
+        .entries()
+        .context("could not read tar archive")?;
+
+    let mut sums = None;
+    let mut actual = BTreeMap::new();
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.context("could not read tar archive")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if path == SUMS_FILE {
+            let mut data = String::new();
+            futures::AsyncReadExt::read_to_string(&mut entry, &mut data)
+                .await
+                .with_context(|| format!("could not read {SUMS_FILE}"))?;
+            sums = Some(data);
+        } else {
+            let sha256 = hash_reader(&mut entry)
+                .await
+                .with_context(|| format!("could not read {path}"))?;
+            actual.insert(path, sha256);
+        }
+    }
+
+    let sums = sums.ok_or_else(|| anyhow!("archive has no {SUMS_FILE}"))?;
+    Ok(compare(parse_sums(&sums)?, &actual))
+}
@@ -0,0 +1,71 @@
+//! A log of what the archiver is doing, written to stdout as newline-delimited json,
+//! for programs supervising it.
+
+use chrono::{SecondsFormat, Utc};
+use once_cell::sync::OnceCell;
+use std::io::Write;
+
+use crate::{helix::User, prelude::*};
+
+static ENABLED: OnceCell<()> = OnceCell::new();
+
+/// Enables `--events-json`; events are not written until this is called.
+pub fn init() {
+    let _ = ENABLED.set(());
+}
+
+pub fn enabled() -> bool {
+    ENABLED.get().is_some()
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A subscription to `event` was created for the channel.
+    Subscribed { subscription_id: &'a str, event: &'a str },
+    /// The channel went online.
+    Online { stream_id: &'a str, title: &'a str, game: &'a str },
+    /// The download of a stream started.
+    DownloadStarted { stream_id: &'a str },
+    /// A segment of the stream was written.
+    Progress { stream_id: &'a str, segments: usize, bytes: u64 },
+    /// The download finished, and was archived to `path`.
+    Finished { stream_id: &'a str, path: &'a str, success: bool },
+    /// The download failed.
+    Error { stream_id: &'a str, error: &'a str },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    channel: &'a str,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+/// Writes `event` of the channel `user` to stdout, if enabled.
+pub fn emit(user: &User, event: Event) {
+    if !enabled() {
+        return;
+    }
+
+    let line = Line {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        channel: user.login(),
+        event,
+    };
+    let mut line = match serde_json::to_vec(&line) {
+        Ok(x) => x,
+        Err(e) => {
+            log::warn!("could not serialize event: {e:?}");
+            return;
+        }
+    };
+    line.push(b'\n');
+
+    // the lock keeps lines of concurrent downloads from interleaving
+    let mut stdout = std::io::stdout().lock();
+    if let Err(e) = stdout.write_all(&line).and_then(|_| stdout.flush()) {
+        log::warn!("could not write event: {e}");
+    }
+}
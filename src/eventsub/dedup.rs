@@ -0,0 +1,52 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A time-windowed set of recently seen message ids.
+///
+/// Ids are forgotten after `window` has passed, or once more than `capacity` ids are held.
+#[derive(Debug)]
+pub struct SeenIds {
+    window: Duration,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    ids: HashSet<Box<str>>,
+    order: VecDeque<(Instant, Box<str>)>,
+}
+
+impl SeenIds {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Records the id as seen, returning `false` if it was already seen within the window.
+    pub fn insert(&self, id: &str) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        let inner = &mut *lock;
+        let now = Instant::now();
+
+        while let Some((t, _)) = inner.order.front() {
+            if now.duration_since(*t) < self.window && inner.order.len() < self.capacity {
+                break;
+            }
+            let (_, id) = inner.order.pop_front().unwrap();
+            inner.ids.remove(&id);
+        }
+
+        if !inner.ids.insert(id.into()) {
+            return false;
+        }
+        inner.order.push_back((now, id.into()));
+        true
+    }
+}
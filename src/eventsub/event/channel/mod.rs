@@ -0,0 +1,2 @@
+mod update;
+pub use update::*;
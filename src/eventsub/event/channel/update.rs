@@ -0,0 +1,88 @@
+use super::super::SubscriptionType;
+use crate::{eventsub::event::Version, helix::User, prelude::*};
+
+pub struct Update;
+
+impl SubscriptionType for Update {
+    type Cond = UpdateCond;
+    type Event = UpdateEvent;
+
+    const NAME: &'static str = "channel.update";
+    const VERSION: Version = Version::new("2");
+}
+
+#[derive(Serialize)]
+pub struct UpdateCond {
+    #[serde(rename = "broadcaster_user_id")]
+    user_id: Box<str>,
+}
+
+impl UpdateCond {
+    pub fn from_id(id: impl ToString) -> Self {
+        UpdateCond {
+            user_id: id.to_string().into(),
+        }
+    }
+}
+
+impl From<&User> for UpdateCond {
+    fn from(value: &User) -> Self {
+        Self::from_id(value.id())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(from = "UpdateEventDes")]
+pub struct UpdateEvent {
+    user: User,
+    title: Box<str>,
+    language: Box<str>,
+    game_id: Box<str>,
+    game_name: Box<str>,
+}
+
+impl UpdateEvent {
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+    pub fn game_id(&self) -> &str {
+        &self.game_id
+    }
+    pub fn game_name(&self) -> &str {
+        &self.game_name
+    }
+}
+
+#[derive(Deserialize)]
+struct UpdateEventDes {
+    #[serde(rename = "broadcaster_user_id")]
+    user_id: Box<str>,
+    #[serde(rename = "broadcaster_user_login")]
+    user_login: Box<str>,
+    #[serde(rename = "broadcaster_user_name")]
+    user_name: Box<str>,
+    title: Box<str>,
+    language: Box<str>,
+    #[serde(rename = "category_id")]
+    game_id: Box<str>,
+    #[serde(rename = "category_name")]
+    game_name: Box<str>,
+}
+
+impl From<UpdateEventDes> for UpdateEvent {
+    fn from(value: UpdateEventDes) -> Self {
+        Self {
+            user: User::new(value.user_id, value.user_login, value.user_name),
+            title: value.title,
+            language: value.language,
+            game_id: value.game_id,
+            game_name: value.game_name,
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{de::DeserializeOwned, Serialize};
 
+pub mod channel;
 pub mod stream;
 
 pub trait SubscriptionType {
@@ -12,8 +13,8 @@ pub trait SubscriptionType {
 
 
 #[derive(Debug, Serialize)]
+#[serde(transparent)]
 pub struct Version {
-    #[serde(flatten)]
     inner: &'static str
 }
 
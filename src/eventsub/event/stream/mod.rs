@@ -1,2 +1,4 @@
+mod offline;
 mod online;
+pub use offline::*;
 pub use online::*;
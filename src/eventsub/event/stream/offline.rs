@@ -0,0 +1,62 @@
+use super::super::SubscriptionType;
+use crate::{eventsub::event::Version, helix::User, prelude::*};
+
+pub struct Offline;
+
+impl SubscriptionType for Offline {
+    type Cond = OfflineCond;
+    type Event = OfflineEvent;
+
+    const NAME: &'static str = "stream.offline";
+    const VERSION: Version = Version::new("1");
+}
+
+#[derive(Serialize)]
+pub struct OfflineCond {
+    #[serde(rename = "broadcaster_user_id")]
+    user_id: Box<str>,
+}
+
+impl OfflineCond {
+    pub fn from_id(id: impl ToString) -> Self {
+        OfflineCond {
+            user_id: id.to_string().into(),
+        }
+    }
+}
+
+impl From<&User> for OfflineCond {
+    fn from(value: &User) -> Self {
+        Self::from_id(value.id())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(from = "OfflineEventDes")]
+pub struct OfflineEvent {
+    user: User,
+}
+
+impl OfflineEvent {
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+}
+
+#[derive(Deserialize)]
+struct OfflineEventDes {
+    #[serde(rename = "broadcaster_user_id")]
+    user_id: Box<str>,
+    #[serde(rename = "broadcaster_user_login")]
+    user_login: Box<str>,
+    #[serde(rename = "broadcaster_user_name")]
+    user_name: Box<str>,
+}
+
+impl From<OfflineEventDes> for OfflineEvent {
+    fn from(value: OfflineEventDes) -> Self {
+        Self {
+            user: User::new(value.user_id, value.user_login, value.user_name),
+        }
+    }
+}
@@ -2,40 +2,146 @@ use anyhow::Context;
 use async_std::{channel::Sender, sync::Arc};
 use atomic::{Atomic, Ordering};
 use dashmap::DashMap;
+use hmac::{Hmac, Mac};
 use serde_json::value::RawValue;
+use sha2::Sha256;
 use tide::{Request, Response};
 
 use super::HelixAuth;
-use crate::{prelude::*, rand, eventsub::event::Version};
+use crate::{api, prelude::*, metrics, net, rand, retry::retry, eventsub::event::Version};
 
 use event::SubscriptionType;
 pub use subscription::*;
 
+mod dedup;
 pub mod event;
 mod subscription;
+mod websocket;
 
-const EVENTSUB_API: &str = "https://api.twitch.tv/helix/eventsub/subscriptions";
 
-#[allow(unused)]
 const MSG_ID: &str = "Twitch-Eventsub-Message-Id";
-#[allow(unused)]
 const MSG_RETRY: &str = "Twitch-Eventsub-Message-Retry";
 const MSG_TYPE: &str = "Twitch-Eventsub-Message-Type";
-#[allow(unused)]
 const MSG_SIG: &str = "Twitch-Eventsub-Message-Signature";
-#[allow(unused)]
 const MSG_TIME: &str = "Twitch-Eventsub-Message-Timestamp";
-#[allow(unused)]
 const SUB_TYPE: &str = "Twitch-Eventsub-Subscription-Type";
-#[allow(unused)]
 const SUB_VER: &str = "Twitch-Eventsub-Subscription-Version";
 
+/// Every header twitch sends with a webhook request.
+const MSG_HEADERS: [&str; 7] = [MSG_ID, MSG_RETRY, MSG_TYPE, MSG_SIG, MSG_TIME, SUB_TYPE, SUB_VER];
+
+/// Twitch may redeliver a message for up to 10 minutes; remember ids for at least as long.
+const MSG_DEDUP_WINDOW: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+const MSG_DEDUP_CAPACITY: usize = 16384;
+
+/// Notifications whose timestamp is further than this from now are rejected as replays.
+const MSG_MAX_AGE: chrono::Duration = chrono::Duration::minutes(10);
+
 const MSG_NOTIFICATION: &str = "notification";
 const MSG_VERIFICATION: &str = "webhook_callback_verification";
 const MSG_REVOCATION: &str = "revocation";
 
+const SESSION_WAIT_RETRY: usize = 12;
+const SESSION_WAIT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+const SELF_TEST_RETRY: usize = 3;
+const SELF_TEST_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
 type Secret = Box<str>;
-type State = Arc<DashMap<SubUnique, (Arc<Atomic<SubStatus>>, Secret, Sender<Box<RawValue>>)>>;
+type Subs = DashMap<SubUnique, (Arc<Atomic<SubStatus>>, Secret, Sender<Received>)>;
+type State = Arc<Shared>;
+
+/// The state shared between the [`EventSub`] and the tasks receiving its events.
+#[derive(Debug)]
+struct Shared {
+    subs: Subs,
+    seen: dedup::SeenIds,
+}
+
+impl Shared {
+    fn new() -> Self {
+        Self {
+            subs: DashMap::new(),
+            seen: dedup::SeenIds::new(MSG_DEDUP_WINDOW, MSG_DEDUP_CAPACITY),
+        }
+    }
+
+    /// Updates the subscription gauge; called whenever `subs` is modified.
+    fn count_subs(&self) {
+        metrics::EVENTSUB_SUBSCRIPTIONS.set(self.subs.len() as u64);
+    }
+}
+
+/// Computes the hmac of a message, as used for the message signature.
+fn message_hmac(secret: &str, id: &str, timestamp: &str, body: &[u8]) -> Option<Hmac<Sha256>> {
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+    else {
+        log::warn!("unexpected error: could not initialize hmac!");
+        return None;
+    };
+
+    mac.update(id.as_bytes());
+    mac.update(timestamp.as_bytes());
+    mac.update(body);
+    Some(mac)
+}
+
+/// Checks that a `Twitch-Eventsub-Message-Timestamp` value is within [`MSG_MAX_AGE`] of now.
+fn is_recent(timestamp: &str) -> bool {
+    let Ok(t) = chrono::DateTime::parse_from_rfc3339(timestamp) else {
+        log::warn!("message timestamp {timestamp:?} is not a valid rfc3339 timestamp");
+        return false;
+    };
+
+    let diff = chrono::Utc::now().signed_duration_since(t);
+    if diff > MSG_MAX_AGE || -diff > MSG_MAX_AGE {
+        log::warn!("message timestamp {timestamp:?} is too far from now; rejecting as replay");
+        return false;
+    }
+    true
+}
+
+/// Creates the `Twitch-Eventsub-Message-Signature` header value for a message.
+fn sign_msg(secret: &str, id: &str, timestamp: &str, body: &[u8]) -> Option<String> {
+    use std::fmt::Write;
+
+    let mac = message_hmac(secret, id, timestamp, body)?;
+    let mut sig = "sha256=".to_owned();
+    for b in mac.finalize().into_bytes() {
+        let _ = write!(sig, "{b:02x}");
+    }
+    Some(sig)
+}
+
+fn verify_msg(secret: &str, req: &Request<State>, body: &[u8]) -> bool {
+    let (Some(v1), Some(v2), Some(sig)) = (
+        req.header(MSG_ID),
+        req.header(MSG_TIME),
+        req.header(MSG_SIG)
+    ) else {
+        log::warn!("required header for hmac verification was empty");
+        return false;
+    };
+
+    let Some(mac) = message_hmac(secret, v1.as_str(), v2.as_str(), body) else {
+        return false;
+    };
+
+    if sig.as_str().len() < 7 {
+        return false;
+    };
+    let sig = &sig.as_str()[7..];
+
+    if sig.len() % 2 != 0 {
+        return false;
+    };
+    let Ok(sig): Result<Vec<u8>, _> = (0..sig.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&sig[i..i + 2], 16))
+        .collect() else { return false };
+
+    mac.verify_slice(&sig).is_ok()
+}
 
 async fn callback(mut req: Request<State>) -> tide::Result {
     fn err_state(state: SubStatus) -> tide::Result {
@@ -55,44 +161,6 @@ async fn callback(mut req: Request<State>) -> tide::Result {
             .build())
     }
 
-    use hmac::{Hmac, Mac};
-    use sha2::Sha256;
-    fn verify_msg(secret: &str, req: &Request<State>, body: &[u8]) -> bool {
-        let (Some(v1), Some(v2), Some(sig)) = (
-            req.header(MSG_ID),
-            req.header(MSG_TIME),
-            req.header(MSG_SIG)
-        ) else {
-            log::warn!("required header for hmac verification was empty");
-            return false;
-        };
-
-        let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
-        else {
-            log::warn!("unexpected error: could not initialize hmac!");
-            return false;
-        };
-
-        mac.update(v1.as_str().as_bytes());
-        mac.update(v2.as_str().as_bytes());
-        mac.update(body);
-
-        if sig.as_str().len() < 7 {
-            return false;
-        };
-        let sig = &sig.as_str()[7..];
-
-        if sig.len() % 2 != 0 {
-            return false;
-        };
-        let Ok(sig): Result<Vec<u8>, _> = (0..sig.len())
-            .step_by(2)
-            .map(|i| u8::from_str_radix(&sig[i..i + 2], 16))
-            .collect() else { return false };
-
-        mac.verify_slice(&sig).is_ok()
-    }
-
     log::trace!("recieved webhook request: {:?}", req);
     let body = req.body_bytes().await?;
     let Some(msg_type) = req.header(MSG_TYPE) else {
@@ -109,8 +177,9 @@ async fn callback(mut req: Request<State>) -> tide::Result {
             }
 
             let msg: RawEvent = serde_json::from_slice(&body)?;
+            metrics::NOTIFICATIONS.inc();
 
-            let e = req.state().get(&msg.subscription);
+            let e = req.state().subs.get(&msg.subscription);
             let Some((status, secret, tx)) = e.as_deref() else {
                 log::warn!("subscription #{} not found", msg.subscription.id());
                 return Ok(Response::builder(404).build());
@@ -127,10 +196,22 @@ async fn callback(mut req: Request<State>) -> tide::Result {
                 return err_state(s);
             }
 
-            match tx.send(msg.event).await {
+            if !req.header(MSG_TIME).is_some_and(|t| is_recent(t.as_str())) {
+                return Ok(Response::builder(400).build());
+            }
+
+            if let Some(id) = req.header(MSG_ID) {
+                if !req.state().seen.insert(id.as_str()) {
+                    log::debug!("ignoring duplicate message #{id}");
+                    return Ok(Response::builder(200).build());
+                }
+            }
+
+            match tx.send((chrono::Local::now(), msg.event)).await {
                 Ok(_) => Ok(Response::builder(200).build()),
                 Err(_) => {
-                    req.state().remove(&msg.subscription);
+                    req.state().subs.remove(&msg.subscription);
+                    req.state().count_subs();
                     Ok(Response::builder(410).build())
                 }
             }
@@ -144,7 +225,7 @@ async fn callback(mut req: Request<State>) -> tide::Result {
 
             let challenge: ChallengeReq = serde_json::from_slice(&body)?;
 
-            let e = req.state().get(&challenge.subscription);
+            let e = req.state().subs.get(&challenge.subscription);
             let Some((status, secret, _)) = e.as_deref() else {
                 log::warn!("subscription #{} not found", challenge.subscription.id());
                 return Ok(Response::builder(404).build());
@@ -183,17 +264,20 @@ async fn callback(mut req: Request<State>) -> tide::Result {
 
             let rev: RevokeReq = serde_json::from_slice(&body)?;
 
-            let Some((_, (status, secret, _))) = (*req.state()).remove(&rev.subscription.unique) else {
+            let Some((_, (status, secret, tx))) = req.state().subs.remove(&rev.subscription.unique) else {
                 log::warn!("subscription #{} not found", rev.subscription.unique.id());
                 return Ok(Response::builder(404).build());
             };
+            req.state().count_subs();
 
             if !verify_msg(&secret, &req, &body) {
                 log::warn!("verification failed!");
                 return Ok(Response::builder(401).build());
             }
 
+            // the status is set before the channel closes, so that the receiver sees why
             status.swap(rev.subscription.status, Ordering::Relaxed);
+            drop(tx);
             Ok(Response::builder(200).build())
         }
         unknown => {
@@ -203,6 +287,99 @@ async fn callback(mut req: Request<State>) -> tide::Result {
     }
 }
 
+async fn health(req: Request<State>) -> tide::Result {
+    #[derive(Serialize)]
+    struct Health {
+        status: &'static str,
+        subscriptions: usize,
+    }
+
+    Ok(Response::builder(200)
+        .body(tide::Body::from_json(&Health {
+            status: "ok",
+            subscriptions: req.state().subs.len(),
+        })?)
+        .build())
+}
+
+/// Handles `POST /callback`, passing requests with `?debug=1` to [`debug_callback`].
+async fn callback_or_debug(req: Request<State>) -> tide::Result {
+    if req.url().query_pairs().any(|(k, v)| k == "debug" && v == "1") {
+        debug_callback(req).await
+    } else {
+        callback(req).await
+    }
+}
+
+/// Reports how a webhook request would be verified, without acting on it.
+///
+/// The signature is checked against the secret of the subscription in the body, if it is known;
+/// nothing is delivered. The computed signature is only logged;
+/// the request is answered with whether a signature was received and whether it verified.
+async fn debug_callback(mut req: Request<State>) -> tide::Result {
+    #[derive(Deserialize)]
+    struct DebugReq {
+        subscription: Option<SubUnique>,
+    }
+
+    #[derive(Serialize)]
+    struct Report {
+        headers: std::collections::BTreeMap<&'static str, bool>,
+        subscription: Option<String>,
+        received: bool,
+        verified: bool,
+    }
+
+    let body = req.body_bytes().await?;
+    let headers = MSG_HEADERS
+        .into_iter()
+        .map(|x| (x, req.header(x).is_some()))
+        .collect();
+    let sub = serde_json::from_slice::<DebugReq>(&body)
+        .ok()
+        .and_then(|x| x.subscription);
+
+    let secret = sub
+        .as_ref()
+        .and_then(|x| req.state().subs.get(x).map(|e| e.1.clone()));
+    let computed = secret.as_deref().and_then(|secret| {
+        sign_msg(
+            secret,
+            req.header(MSG_ID)?.as_str(),
+            req.header(MSG_TIME)?.as_str(),
+            &body,
+        )
+    });
+    let verified = secret.as_deref().is_some_and(|x| verify_msg(x, &req, &body));
+
+    let report = Report {
+        headers,
+        subscription: sub.map(|x| x.id().to_owned()),
+        received: req.header(MSG_SIG).is_some(),
+        verified,
+    };
+
+    log::info!(
+        "debug callback from {}: headers present: {:?}",
+        req.remote().unwrap_or("unknown"),
+        report.headers
+    );
+    match (&report.subscription, &secret) {
+        (None, _) => log::info!("debug callback: body has no subscription; cannot compute hmac"),
+        (Some(id), None) => log::info!("debug callback: subscription #{id} not found; cannot compute hmac"),
+        (Some(id), Some(_)) => log::info!(
+            "debug callback: subscription #{id}: received {:?}, computed {:?}, verified: {}",
+            req.header(MSG_SIG).map(|x| x.as_str()),
+            computed,
+            report.verified
+        ),
+    }
+
+    Ok(Response::builder(200)
+        .body(tide::Body::from_json(&report)?)
+        .build())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "method", rename_all = "snake_case")]
 pub enum Transport<'a> {
@@ -211,21 +388,52 @@ pub enum Transport<'a> {
     },
     Websocket {
         session_id: &'a str,
-        connected_at: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        connected_at: Option<&'a str>,
     },
 }
 
+/// How events are delivered to an [`EventSub`].
+enum Mode {
+    Webhook { v_addr: url::Url },
+    Websocket { session: websocket::SessionState },
+}
+
+/// The subscription cost budget, as last reported by twitch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Budget {
+    pub total_cost: u64,
+    pub max_total_cost: u64,
+}
+
+impl Budget {
+    pub fn remaining(&self) -> u64 {
+        self.max_total_cost.saturating_sub(self.total_cost)
+    }
+}
+
 pub struct EventSub {
-    map: State,
+    state: State,
     auth: HelixAuth,
-    v_addr: url::Url,
+    mode: Mode,
+    budget: std::sync::Mutex<Option<Budget>>,
 }
 
 impl EventSub {
-    pub fn new(addr: std::net::SocketAddr, v_addr: &url::Url, auth: HelixAuth) -> Self {
-        let state = Arc::new(DashMap::new());
+    /// Starts the webhook server at `addr`, which twitch reaches at `v_addr`.
+    ///
+    /// With `debug`, the server also answers `GET /health`,
+    /// and `POST /callback?debug=1` reports how the request would be verified instead of handling it.
+    pub fn new(addr: std::net::SocketAddr, v_addr: &url::Url, auth: HelixAuth, debug: bool) -> Self {
+        let state = Arc::new(Shared::new());
         let mut serve = tide::with_state(Arc::clone(&state));
-        serve.at("/callback").post(callback);
+        if debug {
+            log::warn!("debug callback is enabled; do not use this in production");
+            serve.at("/health").get(health);
+            serve.at("/callback").post(callback_or_debug);
+        } else {
+            serve.at("/callback").post(callback);
+        }
 
         async_std::task::Builder::new()
             .name("callback".to_owned())
@@ -234,18 +442,132 @@ impl EventSub {
         log::info!("started server at {addr:?}");
 
         Self {
-            map: state,
+            state,
             auth,
-            v_addr: v_addr.join("callback").unwrap(),
+            mode: Mode::Webhook {
+                v_addr: v_addr.join("callback").unwrap(),
+            },
+            budget: std::sync::Mutex::new(None),
         }
     }
 
-    pub fn transport(&self) -> Transport {
-        Transport::Webhook {
-            callback: self.v_addr.as_str(),
+    /// Creates an `EventSub` receiving events over a websocket session instead of a webhook.
+    /// Subscriptions over websocket are created with the user access token of `auth`.
+    pub fn websocket(auth: HelixAuth) -> Self {
+        let state = Arc::new(Shared::new());
+        let session = Arc::new(async_std::sync::Mutex::new(None));
+        websocket::spawn(Arc::clone(&state), Arc::clone(&session));
+
+        Self {
+            state,
+            auth,
+            mode: Mode::Websocket { session },
+            budget: std::sync::Mutex::new(None),
         }
     }
 
+    /// Returns the last known subscription cost budget.
+    pub fn budget(&self) -> Option<Budget> {
+        *self.budget.lock().unwrap()
+    }
+
+    /// Sends a signed verification request to the public callback url,
+    /// checking that it is routed back to this server and verified end-to-end.
+    pub async fn verify_callback(&self) -> Result<()> {
+        let Mode::Webhook { v_addr } = &self.mode else {
+            log::debug!("websocket transport has no callback to verify");
+            return Ok(());
+        };
+
+        let id = format!("self-test-{}", rand::rand_hex(12));
+        let secret = rand::rand_hex(10);
+        let challenge = rand::rand_hex(16);
+        let unique = SubUnique::new(id.as_str());
+
+        let (tx, _rx) = async_std::channel::unbounded();
+        self.state.subs.insert(
+            unique.clone(),
+            (
+                Arc::new(Atomic::new(SubStatus::VerificationPending)),
+                secret.clone().into(),
+                tx,
+            ),
+        );
+
+        let res = retry(
+            || async {
+                #[derive(Serialize)]
+                struct ChallengeReq<'a> {
+                    subscription: Id<'a>,
+                    challenge: &'a str,
+                }
+
+                #[derive(Serialize)]
+                struct Id<'a> {
+                    id: &'a str,
+                }
+
+                let body = serde_json::to_vec(&ChallengeReq {
+                    subscription: Id { id: &id },
+                    challenge: &challenge,
+                })?;
+                let msg_id = rand::rand_hex(16);
+                let timestamp = chrono::Utc::now().to_rfc3339();
+                let sig = sign_msg(&secret, &msg_id, &timestamp, &body)
+                    .ok_or_else(|| anyhow!("could not sign self-test request"))?;
+
+                let mut res = net::client().post(v_addr.as_str())
+                    .header(MSG_ID, msg_id)
+                    .header(MSG_TIME, timestamp)
+                    .header(MSG_SIG, sig)
+                    .header(MSG_TYPE, MSG_VERIFICATION)
+                    .body_bytes(body)
+                    .content_type(surf::http::mime::JSON)
+                    .send()
+                    .await
+                    .map_err(|e| e.into_inner())?;
+
+                if !res.status().is_success() {
+                    return Err(anyhow!("callback returned status {}", res.status()));
+                }
+
+                let body = res.body_string().await.map_err(|e| e.into_inner())?;
+                if body != challenge {
+                    return Err(anyhow!("callback returned unexpected response: {body:?}"));
+                }
+
+                Ok(())
+            },
+            SELF_TEST_DELAY,
+            SELF_TEST_RETRY,
+            "callback self-test",
+        )
+        .await;
+
+        self.state.subs.remove(&unique);
+
+        res.with_context(|| format!(
+            "callback URL {v_addr} unreachable - check tunnel/firewall/HTTPS"
+        ))
+    }
+
+    /// Waits until a websocket session is available, returning it.
+    async fn session(session: &websocket::SessionState) -> Result<websocket::Session> {
+        retry(
+            || async {
+                session
+                    .lock()
+                    .await
+                    .clone()
+                    .ok_or_else(|| anyhow!("websocket session is not connected"))
+            },
+            SESSION_WAIT_DELAY,
+            SESSION_WAIT_RETRY,
+            "waiting for websocket session",
+        )
+        .await
+    }
+
     pub async fn subscribe<T: SubscriptionType>(
         &self,
         cond: impl Into<T::Cond>,
@@ -263,12 +585,15 @@ impl EventSub {
         struct TransportWithSecret<'a> {
             #[serde(flatten)]
             transport: Transport<'a>,
-            secret: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            secret: Option<&'a str>,
         }
 
         #[derive(Deserialize)]
         struct CreateSubRes {
             data: [SubDes; 1],
+            total_cost: u64,
+            max_total_cost: u64,
         }
 
         #[derive(Deserialize)]
@@ -277,19 +602,37 @@ impl EventSub {
             status: SubStatus,
             condition: Box<RawValue>,
             created_at: Box<str>,
+            cost: u64,
         }
 
         let cond = cond.into();
         let secret = rand::rand_hex(10);
 
+        let session;
+        let transport = match &self.mode {
+            Mode::Webhook { v_addr } => TransportWithSecret {
+                transport: Transport::Webhook {
+                    callback: v_addr.as_str(),
+                },
+                secret: Some(&secret),
+            },
+            Mode::Websocket { session: s } => {
+                session = Self::session(s).await?;
+                TransportWithSecret {
+                    transport: Transport::Websocket {
+                        session_id: &session.id,
+                        connected_at: None,
+                    },
+                    secret: None,
+                }
+            }
+        };
+
         let body = CreateSub {
             name: T::NAME,
             version: T::VERSION,
             condition: cond,
-            transport: TransportWithSecret {
-                transport: self.transport(),
-                secret: &secret,
-            },
+            transport,
         };
 
         log::trace!(
@@ -298,14 +641,44 @@ impl EventSub {
             serde_json::to_string(&body)
         );
 
-        let res: CreateSubRes = self
-            .auth
-            .send_req_json(surf::post(EVENTSUB_API).body_json(&body).unwrap().build())
-            .await
-            .context("failed to send subscription creation request")?;
+        let req = surf::post(api::helix("eventsub/subscriptions")).body_json(&body).unwrap().build();
+        let res: CreateSubRes = match self.mode {
+            Mode::Webhook { .. } => self.auth.send_req_json(req).await,
+            // websocket subscriptions must be created with a user access token
+            Mode::Websocket { .. } => async {
+                self.auth
+                    .send_user_req(req)
+                    .await?
+                    .body_json()
+                    .await
+                    .map_err(|e| e.into_inner())
+            }
+            .await,
+        }
+        .with_context(|| match self.budget() {
+            // the budget is only refreshed on creation, so it may be outdated; let twitch decide
+            Some(budget) if budget.remaining() == 0 => format!(
+                "failed to send subscription creation request (last known budget {}/{})",
+                budget.total_cost, budget.max_total_cost
+            ),
+            _ => "failed to send subscription creation request".to_owned(),
+        })?;
 
         let [s] = res.data;
 
+        let budget = Budget {
+            total_cost: res.total_cost,
+            max_total_cost: res.max_total_cost,
+        };
+        *self.budget.lock().unwrap() = Some(budget);
+        log::debug!(
+            "subscription #{} costs {}; remaining budget {}/{}",
+            s.id,
+            s.cost,
+            budget.remaining(),
+            budget.max_total_cost
+        );
+
         let (tx, rx) = async_std::channel::unbounded();
         let sub = Subscription::<T>::new(
             s.id,
@@ -316,10 +689,30 @@ impl EventSub {
             rx,
         );
 
-        self.map.insert(sub.get_unique(), (sub._status(), secret.into(), tx));
+        self.state.subs.insert(sub.get_unique(), (sub._status(), secret.into(), tx));
+        self.state.count_subs();
 
         Ok(sub)
     }
+
+    /// Deletes the subscription, and stops dispatching its events.
+    pub async fn unsubscribe<T>(&self, sub: Subscription<T>) -> Result<()> {
+        self.state.subs.remove(&sub.get_unique());
+        self.state.count_subs();
+
+        match self.mode {
+            Mode::Webhook { .. } => delete(&self.auth, sub.into_inner()).await,
+            // websocket subscriptions must be deleted with a user access token
+            Mode::Websocket { .. } => {
+                let req = delete_req(sub.id())?;
+                self.auth
+                    .send_user_req(req)
+                    .await
+                    .with_context(|| format!("error while deleting subscription {}", sub.id()))?;
+                Ok(())
+            }
+        }
+    }
 }
 
 pub async fn get(auth: &HelixAuth) -> Result<Vec<SubInner>> {
@@ -328,27 +721,27 @@ pub async fn get(auth: &HelixAuth) -> Result<Vec<SubInner>> {
         data: Vec<SubInner>,
     }
 
-    let sub: SubRetDes = auth.send_req_json(surf::get(EVENTSUB_API).build()).await?;
+    let sub: SubRetDes = auth.send_req_json(surf::get(api::helix("eventsub/subscriptions")).build()).await?;
 
     log::debug!("retrieved {} subscriptions", sub.data.len());
 
     Ok(sub.data)
 }
 
-pub async fn delete(auth: &HelixAuth, sub: SubInner) -> Result<()> {
+fn delete_req(id: &str) -> Result<surf::Request> {
     #[derive(Serialize)]
     struct Id<'a> {
         id: &'a str,
     }
 
-    let mut res = auth
-        .send_req(
-            surf::delete(EVENTSUB_API)
-                .query(&Id { id: sub.id() })
-                .map_err(|e| e.into_inner())?
-                .build(),
-        )
-        .await?;
+    Ok(surf::delete(api::helix("eventsub/subscriptions"))
+        .query(&Id { id })
+        .map_err(|e| e.into_inner())?
+        .build())
+}
+
+pub async fn delete(auth: &HelixAuth, sub: SubInner) -> Result<()> {
+    let mut res = auth.send_req(delete_req(sub.id())?).await?;
 
     if !res.status().is_success() {
         let body = res.body_string().await.map_err(|e| {
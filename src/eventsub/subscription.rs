@@ -1,17 +1,25 @@
 use async_std::channel::Receiver;
 use atomic::{Atomic, Ordering};
+use chrono::{DateTime, Local};
 use serde_json::value::RawValue;
 use std::{marker::PhantomData, sync::Arc};
 
 use super::SubscriptionType;
 use crate::prelude::*;
 
+/// An event, along with when it was received.
+pub(crate) type Received = (DateTime<Local>, Box<RawValue>);
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
 pub struct SubUnique {
     id: Box<str>,
 }
 
 impl SubUnique {
+    pub(crate) fn new(id: impl Into<Box<str>>) -> Self {
+        Self { id: id.into() }
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
@@ -72,7 +80,7 @@ impl From<SubInnerDes> for SubInner {
 pub struct Subscription<T> {
     inner: SubInner,
     secret: Box<str>,
-    rx: Receiver<Box<RawValue>>,
+    rx: Receiver<Received>,
     phantom: PhantomData<T>,
 }
 
@@ -89,6 +97,9 @@ impl<T> Subscription<T> {
     pub(crate) fn _status(&self) -> Arc<Atomic<SubStatus>> {
         self.inner._status()
     }
+    pub(crate) fn into_inner(self) -> SubInner {
+        self.inner
+    }
 }
 
 impl<T: SubscriptionType> Subscription<T> {
@@ -98,7 +109,7 @@ impl<T: SubscriptionType> Subscription<T> {
         condition: Box<RawValue>,
         created_at: Box<str>,
         secret: Box<str>,
-        rx: Receiver<Box<RawValue>>,
+        rx: Receiver<Received>,
     ) -> Self {
         Self {
             inner: SubInner::new(id, status, condition, created_at),
@@ -109,14 +120,25 @@ impl<T: SubscriptionType> Subscription<T> {
     }
 
     pub async fn recv(&self) -> Result<Option<T::Event>, RecvError> {
+        Ok(self.recv_at().await?.map(|(_, x)| x))
+    }
+
+    /// Receives the next event like [`recv`](Self::recv), along with when it was received.
+    /// Events wait to be received, so this can be long before this returns.
+    pub async fn recv_at(&self) -> Result<Option<(DateTime<Local>, T::Event)>, RecvError> {
         if !self.status().is_ok() {
             return Ok(None);
         }
 
-        let event = self.rx.recv().await.map_err(RecvError::ChannelClosed)?;
+        let (at, event) = match self.rx.recv().await {
+            Ok(x) => x,
+            // revoked while waiting
+            Err(_) if !self.status().is_ok() => return Ok(None),
+            Err(e) => return Err(RecvError::ChannelClosed(e)),
+        };
 
         match serde_json::from_str(event.get()) {
-            Ok(x) => Ok(Some(x)),
+            Ok(x) => Ok(Some((at, x))),
             Err(e) => Err(RecvError::ParseError(e)),
         }
     }
@@ -152,6 +174,13 @@ pub enum SubStatus {
     ModeratorRemoved,
     UserRemoved,
     VersionRemoved,
+    WebsocketDisconnected,
+    WebsocketFailedPingPong,
+    WebsocketReceivedInboundTraffic,
+    WebsocketConnectionUnused,
+    WebsocketInternalError,
+    WebsocketNetworkTimeout,
+    WebsocketNetworkError,
 }
 
 impl SubStatus {
@@ -0,0 +1,227 @@
+use anyhow::Context;
+use async_std::{
+    future::timeout,
+    sync::{Arc, Mutex},
+};
+use async_tungstenite::{async_std::{connect_async, ConnectStream}, tungstenite::Message, WebSocketStream};
+use atomic::Ordering;
+use futures::{future::{self, Either}, StreamExt};
+use serde_json::value::RawValue;
+use std::time::Duration;
+
+use super::{State, SubStatus, SubUnique, MSG_NOTIFICATION, MSG_REVOCATION};
+use crate::{api, metrics, prelude::*};
+
+
+const MSG_WELCOME: &str = "session_welcome";
+const MSG_KEEPALIVE: &str = "session_keepalive";
+const MSG_RECONNECT: &str = "session_reconnect";
+
+/// The keepalive timeout used until twitch tells us otherwise.
+const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(10);
+/// Extra time allowed on top of the keepalive timeout before the connection is considered dead.
+const KEEPALIVE_GRACE: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A websocket session, which subscriptions are bound to.
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub id: Box<str>,
+}
+
+pub type SessionState = Arc<Mutex<Option<Session>>>;
+
+#[derive(Deserialize)]
+struct WsMessage {
+    metadata: Metadata,
+    payload: Box<RawValue>,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    message_id: Box<str>,
+    message_type: Box<str>,
+}
+
+#[derive(Deserialize)]
+struct SessionPayload {
+    session: SessionDes,
+}
+
+#[derive(Deserialize)]
+struct SessionDes {
+    id: Box<str>,
+    keepalive_timeout_seconds: Option<u64>,
+    reconnect_url: Option<String>,
+}
+
+/// Keeps a websocket session to twitch open, dispatching received events to the subscriptions in `state`.
+///
+/// When the session is lost, every subscription bound to it is dropped,
+/// and a new session is opened.
+pub fn spawn(state: State, session: SessionState) {
+    async_std::task::Builder::new()
+        .name("eventsub-ws".to_owned())
+        .spawn(async move {
+            loop {
+                if let Err(e) = run(&state, &session).await {
+                    log::error!("eventsub websocket session lost: {e:?}");
+                }
+
+                // subscriptions do not carry over to a new session
+                *session.lock().await = None;
+                state.subs.clear();
+                state.count_subs();
+
+                async_std::task::sleep(RECONNECT_DELAY).await;
+            }
+        })
+        .expect("cannot spawn future");
+}
+
+async fn run(state: &State, session: &SessionState) -> Result<()> {
+    let (mut ws, _) = connect_async(api::eventsub_ws())
+        .await
+        .context("failed to connect to eventsub websocket")?;
+    let mut keepalive = DEFAULT_KEEPALIVE;
+    // the connection to the reconnect url, until its session is welcomed
+    let mut reconnect: Option<WebSocketStream<ConnectStream>> = None;
+
+    loop {
+        // events are still delivered to the old connection until the new one is welcomed
+        let next = async {
+            match &mut reconnect {
+                Some(new) => match future::select(ws.next(), new.next()).await {
+                    Either::Left((x, _)) => (x, false),
+                    Either::Right((x, _)) => (x, true),
+                },
+                None => (ws.next().await, false),
+            }
+        };
+        let (msg, from_new) = match timeout(keepalive + KEEPALIVE_GRACE, next).await {
+            Ok(x) => x,
+            Err(_) => {
+                return Err(anyhow!(
+                    "no message received for {}s",
+                    (keepalive + KEEPALIVE_GRACE).as_secs()
+                ))
+            }
+        };
+
+        // twitch may close the old connection before the new one is welcomed
+        let ended = matches!(msg, None | Some(Err(_)) | Some(Ok(Message::Close(_))));
+        if ended && !from_new && reconnect.is_some() {
+            log::debug!("old eventsub websocket connection ended: {msg:?}");
+            ws = reconnect.take().unwrap();
+            continue;
+        }
+
+        let msg = match msg {
+            Some(x) => x.context("failed to receive websocket message")?,
+            None => return Err(anyhow!("websocket connection closed")),
+        };
+
+        let text = match msg {
+            Message::Text(x) => x,
+            Message::Close(frame) => {
+                return Err(anyhow!("websocket connection closed by server: {frame:?}"))
+            }
+            _ => continue,
+        };
+
+        log::trace!("received websocket message: {text}");
+        let msg: WsMessage = serde_json::from_str(&text)?;
+
+        match &*msg.metadata.message_type {
+            MSG_WELCOME => {
+                let SessionPayload { session: s } = serde_json::from_str(msg.payload.get())?;
+                if let Some(x) = s.keepalive_timeout_seconds {
+                    keepalive = Duration::from_secs(x);
+                }
+
+                if from_new {
+                    // the new connection replaces the old one only once it is welcomed
+                    let mut old = std::mem::replace(&mut ws, reconnect.take().unwrap());
+                    let _ = old.close(None).await;
+                }
+
+                log::info!("connected to eventsub websocket session {}", s.id);
+                *session.lock().await = Some(Session { id: s.id });
+            }
+            MSG_KEEPALIVE => log::trace!("received websocket keepalive"),
+            MSG_RECONNECT => {
+                let SessionPayload { session: s } = serde_json::from_str(msg.payload.get())?;
+                let url = s
+                    .reconnect_url
+                    .ok_or_else(|| anyhow!("reconnect message is missing the reconnect url"))?;
+
+                log::debug!("reconnecting eventsub websocket session {}", s.id);
+                let (new, _) = connect_async(&url)
+                    .await
+                    .context("failed to connect to the reconnect url")?;
+                reconnect = Some(new);
+            }
+            MSG_NOTIFICATION => {
+                let id = msg.metadata.message_id;
+
+                #[derive(Deserialize)]
+                struct RawEvent {
+                    subscription: SubUnique,
+                    event: Box<RawValue>,
+                }
+
+                let msg: RawEvent = serde_json::from_str(msg.payload.get())?;
+                metrics::NOTIFICATIONS.inc();
+
+                let e = state.subs.get(&msg.subscription);
+                let Some((status, _, tx)) = e.as_deref() else {
+                    log::warn!("subscription #{} not found", msg.subscription.id());
+                    continue;
+                };
+
+                let s = status.load(Ordering::Relaxed);
+                if s != SubStatus::Enabled {
+                    log::warn!("subscription #{} is not enabled: {s:?}", msg.subscription.id());
+                    continue;
+                }
+
+                if !state.seen.insert(&id) {
+                    log::debug!("ignoring duplicate message #{id}");
+                    continue;
+                }
+
+                if tx.send((chrono::Local::now(), msg.event)).await.is_err() {
+                    drop(e);
+                    state.subs.remove(&msg.subscription);
+                    state.count_subs();
+                }
+            }
+            MSG_REVOCATION => {
+                #[derive(Deserialize)]
+                struct RevokeReq {
+                    subscription: SubUnqStatus,
+                }
+
+                #[derive(Deserialize)]
+                struct SubUnqStatus {
+                    #[serde(flatten)]
+                    unique: SubUnique,
+                    status: SubStatus,
+                }
+
+                let rev: RevokeReq = serde_json::from_str(msg.payload.get())?;
+
+                let Some((_, (status, _, tx))) = state.subs.remove(&rev.subscription.unique) else {
+                    log::warn!("subscription #{} not found", rev.subscription.unique.id());
+                    continue;
+                };
+                state.count_subs();
+
+                // the status is set before the channel closes, so that the receiver sees why
+                status.swap(rev.subscription.status, Ordering::Relaxed);
+                drop(tx);
+            }
+            unknown => log::warn!("received websocket message has unknown message type: {unknown}"),
+        }
+    }
+}
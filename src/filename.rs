@@ -2,7 +2,10 @@ use std::borrow::Cow;
 
 use crate::helix::*;
 
-use chrono::{Datelike, Timelike};
+use chrono::{
+    format::{Item, StrftimeItems},
+    Datelike, Timelike,
+};
 use sanitize_filename::Options;
 
 #[derive(Debug)]
@@ -19,6 +22,9 @@ enum Elements {
     Timezone,
     StreamId,
     StreamTitle,
+    GameId,
+    GameName,
+    StrfTime(Box<str>),
     Escape,
     Seperator,
     String(Box<str>),
@@ -29,68 +35,157 @@ pub struct Formatter {
     inner: Box<[Elements]>,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum FormatParseError {
+    /// The format is an empty string.
+    Empty,
+    /// The format ends with a path separator, leaving no file name.
+    TrailingSeparator { offset: usize },
+    /// A placeholder symbol which is not known.
+    UnknownSymbol { offset: usize, symbol: String },
+    /// A `%` at the end of the format without a following symbol.
+    Incomplete { offset: usize },
+    /// A `%T{` block without a closing brace.
+    Unclosed { offset: usize },
+    /// A `%T{...}` block containing an invalid strftime format.
+    InvalidTime { offset: usize, format: String },
+}
+
+impl std::fmt::Display for FormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "file names cannot be an empty string"),
+            Self::TrailingSeparator { offset } => {
+                write!(f, "trailing path separator at byte {offset}")
+            }
+            Self::UnknownSymbol { offset, symbol } => {
+                write!(f, "unknown symbol %{symbol} at byte {offset}")
+            }
+            Self::Incomplete { offset } => write!(f, "incomplete placeholder at byte {offset}"),
+            Self::Unclosed { offset } => write!(f, "unclosed %T{{ block at byte {offset}"),
+            Self::InvalidTime { offset, format } => {
+                write!(f, "invalid time format {format:?} at byte {offset}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FormatParseError {}
+
+impl<'de> serde::Deserialize<'de> for Formatter {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fmt = String::deserialize(deserializer)?;
+        Formatter::new(&fmt).map_err(|e| {
+            let errors: Vec<String> = e.iter().map(ToString::to_string).collect();
+            serde::de::Error::custom(format!(
+                "file name format {fmt:?} is invalid: {}",
+                errors.join("; ")
+            ))
+        })
+    }
+}
+
 impl Formatter {
-    pub fn new(fmt: &str) -> Self {
+    /// Parses the file name format, collecting every malformed placeholder.
+    pub fn new(fmt: &str) -> Result<Self, Vec<FormatParseError>> {
+        if fmt.is_empty() {
+            return Err(vec![FormatParseError::Empty]);
+        }
+
         let mut vec = Vec::new();
+        let mut errors = Vec::new();
+        let mut string = String::new();
 
-        for p in fmt.split(['\\', '/']) {
-            if p.contains(['\\', '/']) {
-                eprintln!("ERROR: filename contains unsanitized contents: {:?}", p);
-                std::process::exit(-1);
+        fn push_string(vec: &mut Vec<Elements>, string: &mut String) {
+            if !string.is_empty() {
+                vec.push(Elements::String(std::mem::take(string).into()));
             }
+        }
 
-            let mut skip = true;
-            for s in p.split('%') {
-                if skip {
-                    if !s.is_empty() {
-                        vec.push(Elements::String(s.into()));
-                    }
-                    skip = false;
-                    continue;
+        let mut chars = fmt.char_indices();
+        while let Some((offset, c)) = chars.next() {
+            match c {
+                '\\' | '/' => {
+                    push_string(&mut vec, &mut string);
+                    vec.push(Elements::Seperator);
                 }
+                '%' => {
+                    push_string(&mut vec, &mut string);
 
-                if s.is_empty() {
-                    vec.push(Elements::Escape);
-                    skip = true;
-                    continue;
-                }
+                    if fmt[offset + 1..].starts_with('%') {
+                        chars.next();
+                        vec.push(Elements::Escape);
+                        continue;
+                    }
 
-                let next = match &s[..2] {
-                    "Si" => Elements::UserId,
-                    "Sl" => Elements::UserLogin,
-                    "Sn" => Elements::UserName,
-                    "TY" => Elements::Year4,
-                    "Ty" => Elements::Year2,
-                    "TM" => Elements::Month,
-                    "TD" => Elements::Day,
-                    "TH" => Elements::Hour,
-                    "Tm" => Elements::Minute,
-                    "TZ" => Elements::Timezone,
-                    "si" => Elements::StreamId,
-                    "st" => Elements::StreamTitle,
-                    x => {
-                        eprintln!("ERROR: filename contains unknown symbol {:?}", x);
-                        std::process::exit(-1);
+                    if fmt[offset + 1..].starts_with("T{") {
+                        let start = offset + 3;
+                        let Some(len) = fmt[start..].find('}') else {
+                            errors.push(FormatParseError::Unclosed { offset });
+                            break;
+                        };
+
+                        let time = &fmt[start..start + len];
+                        if StrftimeItems::new(time).any(|x| x == Item::Error) {
+                            errors.push(FormatParseError::InvalidTime {
+                                offset,
+                                format: time.to_owned(),
+                            });
+                        } else {
+                            vec.push(Elements::StrfTime(time.into()));
+                        }
+
+                        // skip over `T{`, the block contents, and `}`
+                        chars.by_ref().take_while(|(i, _)| *i < start + len).for_each(drop);
+                        continue;
                     }
-                };
-                vec.push(next);
-                skip = false;
 
-                if !&s[2..].is_empty() {
-                    vec.push(Elements::String((&s[2..]).into()));
+                    let symbol: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                    if symbol.chars().count() < 2 {
+                        errors.push(FormatParseError::Incomplete { offset });
+                        continue;
+                    }
+
+                    let next = match symbol.as_str() {
+                        "Si" => Elements::UserId,
+                        "Sl" => Elements::UserLogin,
+                        "Sn" => Elements::UserName,
+                        "TY" => Elements::Year4,
+                        "Ty" => Elements::Year2,
+                        "TM" => Elements::Month,
+                        "TD" => Elements::Day,
+                        "TH" => Elements::Hour,
+                        "Tm" => Elements::Minute,
+                        "TZ" => Elements::Timezone,
+                        "si" => Elements::StreamId,
+                        "st" => Elements::StreamTitle,
+                        "gi" => Elements::GameId,
+                        "gn" => Elements::GameName,
+                        _ => {
+                            errors.push(FormatParseError::UnknownSymbol { offset, symbol });
+                            continue;
+                        }
+                    };
+                    vec.push(next);
                 }
+                c => string.push(c),
             }
+        }
+        push_string(&mut vec, &mut string);
 
-            vec.push(Elements::Seperator);
+        if let Some(c) = fmt.chars().last().filter(|c| matches!(c, '\\' | '/')) {
+            errors.push(FormatParseError::TrailingSeparator {
+                offset: fmt.len() - c.len_utf8(),
+            });
         }
 
-        if !vec.is_empty() {
-            vec.pop();
+        if !errors.is_empty() {
+            return Err(errors);
         }
 
-        Self {
+        Ok(Self {
             inner: vec.into_boxed_slice(),
-        }
+        })
     }
 
     pub fn format(&self, stream: &Stream) -> String {
@@ -105,6 +200,15 @@ impl Formatter {
             )
         }
 
+        // twitch returns empty game values for some categories
+        fn or_unknown(value: String) -> String {
+            if value.is_empty() {
+                "unknown".to_owned()
+            } else {
+                value
+            }
+        }
+
         let mut name = String::new();
 
         for e in self.inner.iter() {
@@ -121,6 +225,9 @@ impl Formatter {
                 Elements::Timezone => stream.started_at().offset().to_string().into(),
                 Elements::StreamId => san(stream.id()).into(),
                 Elements::StreamTitle => san(stream.title()).into(),
+                Elements::GameId => or_unknown(san(stream.game_id())).into(),
+                Elements::GameName => or_unknown(san(stream.game_name())).into(),
+                Elements::StrfTime(x) => san(&stream.started_at().format(x).to_string()).into(),
                 Elements::Escape => "%".into(),
                 Elements::Seperator => std::path::MAIN_SEPARATOR.to_string().into(),
                 Elements::String(x) => (&**x).into(),
@@ -130,4 +237,14 @@ impl Formatter {
 
         name
     }
+
+    /// Formats the name like [`Self::format`], appending ` [{id}]` when the format has no `%si`,
+    /// so that streams of a channel started the same day are not given the same name.
+    pub fn format_unique(&self, stream: &Stream) -> String {
+        let mut name = self.format(stream);
+        if !self.inner.iter().any(|x| matches!(x, Elements::StreamId)) {
+            name += &format!(" [{}]", stream.id());
+        }
+        name
+    }
 }
@@ -1,4 +1,4 @@
-use async_std::{fs, io, path};
+use async_std::{fs, io::{self, WriteExt}, path};
 use sanitize_filename::{sanitize_with_options, Options};
 
 const MAX_FILENAME_DUP: usize = 65536;
@@ -32,23 +32,32 @@ pub async fn create_new_file(path: &path::Path) -> io::Result<Option<fs::File>>
     })
 }
 
-pub async fn create_dedup_file(path: &path::Path) -> io::Result<(Box<path::Path>, fs::File)> {
-    log::trace!("download::create_dedup_file: {}", path.display());
+/// Creates a new file at `path` with the extension `ext` appended,
+/// de-duplicating the file name if it already exists.
+/// `ext` may consist of multiple parts, such as `tar.gz`.
+pub async fn create_dedup_file(path: &path::Path, ext: &str) -> io::Result<(Box<path::Path>, fs::File)> {
+    log::trace!("download::create_dedup_file: {}.{ext}", path.display());
+
+    let with_ext = |suffix: &str| {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(suffix);
+        name.push(".");
+        name.push(ext);
+        path.with_file_name(name)
+    };
 
     // fs::create_dir_all() does not error on duplicate paths; see async_std issue #1051
     if let Some(x) = path.parent() {
         fs::create_dir_all(x).await?
     }
 
-    if let Some(x) = create_new_file(path).await? {
-        return Ok((path.into(), x));
+    let new_path = with_ext("");
+    if let Some(x) = create_new_file(&new_path).await? {
+        return Ok((new_path.into(), x));
     };
 
     for i in 1..MAX_FILENAME_DUP {
-        let mut new_name = path.file_stem().unwrap_or_default().to_os_string();
-        new_name.push(format!("-{}", i));
-        let mut new_path = path.with_file_name(&new_name);
-        new_path.set_extension(path.extension().unwrap_or_default());
+        let new_path = with_ext(&format!("-{}", i));
 
         if let Some(x) = create_new_file(&new_path).await? {
             return Ok((new_path.into(), x));
@@ -94,3 +103,45 @@ pub async fn create_dedup_dir(path: &path::Path) -> io::Result<Box<path::Path>>
     }
     Err(io::ErrorKind::AlreadyExists.into())
 }
+
+/// Writes `data` to `path` through a temporary file next to it, renamed into place once synced,
+/// so that readers never see a partially written file.
+pub async fn write_atomic(path: &path::Path, data: &[u8]) -> io::Result<()> {
+    log::trace!("download::write_atomic: {}", path.display());
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = path::PathBuf::from(tmp);
+
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(&tmp, path).await
+}
+
+/// Copies the file or directory at `from` to `to`, recursing into directories.
+/// Used in place of `fs::rename` when `from` and `to` are on different filesystems.
+#[async_recursion::async_recursion]
+pub async fn copy_recursive(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    log::trace!("download::copy_recursive: {} -> {}", from.display(), to.display());
+
+    if !fs::metadata(from).await?.is_dir() {
+        return fs::copy(from, to).await.map(drop);
+    }
+
+    fs::create_dir(to).await?;
+    let mut entries = fs::read_dir(from).await?;
+    while let Some(entry) = futures::StreamExt::next(&mut entries).await {
+        let entry = entry?;
+        copy_recursive(&entry.path(), &to.join(entry.file_name())).await?;
+    }
+    Ok(())
+}
+
+/// Returns the space available to the current user on the filesystem containing `path`.
+pub async fn available_space(path: &path::Path) -> io::Result<u64> {
+    let path = path.to_path_buf();
+    async_std::task::spawn_blocking(move || fs2::available_space(path)).await
+}
@@ -3,23 +3,100 @@ use futures::Future;
 use serde::de::DeserializeOwned;
 use std::{
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
-use surf::http::mime;
+use surf::{http::mime, StatusCode};
 
-use crate::prelude::*;
+use crate::{api, metrics, net, prelude::*, retry::retry_backoff};
 
-const AUTH_API: &str = "https://id.twitch.tv/oauth2/token";
+const DEVICE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// The scopes requested for a user access token obtained with the device code flow.
+const DEVICE_SCOPES: &str = "chat:read";
+/// How much the polling interval is increased when twitch asks to slow down.
+const DEVICE_SLOW_DOWN: Duration = Duration::from_secs(5);
+const AUTH_RETRY: usize = 6;
+const AUTH_RETRY_DELAY: Duration = Duration::from_secs(5);
+const AUTH_RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+/// How long before expiry the app access token is refreshed.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+const REFRESH_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How many times a rate limited request is retried.
+const RATELIMIT_RETRY: usize = 3;
+/// How long to wait on a rate limited request without a reset time.
+const RATELIMIT_WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum AuthError {
+    Status {
+        status: StatusCode,
+        message: Option<String>,
+        /// How long twitch asked to wait before retrying, from `Retry-After` or `Ratelimit-Reset`.
+        retry_after: Option<Duration>,
+    },
+    Request(anyhow::Error),
+}
+
+impl AuthError {
+    /// Returns whether the error is worth retrying.
+    /// Client errors (4xx) other than rate limiting imply bad credentials and will not resolve on their own.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Status { status, .. } => {
+                !status.is_client_error() || *status == StatusCode::TooManyRequests
+            }
+            Self::Request(_) => true,
+        }
+    }
+
+    /// Returns how long to wait before retrying, if twitch asked for it.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Status { retry_after, .. } => *retry_after,
+            Self::Request(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Status { status, message, .. } => {
+                if *status == StatusCode::TooManyRequests {
+                    write!(f, "auth endpoint rate limited ({})", *status as u16)?;
+                } else if status.is_client_error() {
+                    write!(f, "invalid client credentials ({})", *status as u16)?;
+                } else if status.is_server_error() {
+                    write!(f, "auth endpoint unavailable ({})", *status as u16)?;
+                } else {
+                    write!(f, "authorization returned status {status}")?;
+                }
+
+                if let Some(x) = message {
+                    write!(f, ": {x}")?;
+                }
+                Ok(())
+            }
+            Self::Request(e) => write!(f, "authorization request failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 struct Inner {
     auth: Box<str>,
     client_id: Box<str>,
     expires: Instant,
+    user_token: Option<Box<str>>,
+    /// The refresh token of `user_token`, if it was obtained with the device code flow.
+    user_refresh: Option<Box<str>>,
+    /// Where `user_refresh` is cached to.
+    user_cache: Option<Box<std::path::Path>>,
 }
 
 impl Inner {
-    async fn _get(client_id: &str, secret: &str) -> Result<(Box<str>, Instant)> {
+    async fn _get(client_id: &str, secret: &str) -> Result<(Box<str>, Instant), AuthError> {
         #[derive(Deserialize)]
         struct AuthRes {
             access_token: String,
@@ -27,7 +104,7 @@ impl Inner {
         }
 
         let res: AuthRes = {
-            let mut res = surf::post(AUTH_API)
+            let mut res = net::client().post(api::id("token"))
                 .body_string(format!(
                     "client_id={}\
                 &client_secret={}\
@@ -37,13 +114,37 @@ impl Inner {
                 .content_type(mime::FORM)
                 .send()
                 .await
-                .map_err(|e| e.into_inner())?;
+                .map_err(|e| AuthError::Request(e.into_inner()))?;
 
             if !res.status().is_success() {
-                return Err(anyhow!("authorization returned status {}", res.status()));
+                #[derive(Deserialize)]
+                struct ErrorRes {
+                    message: String,
+                }
+
+                let body = res.body_string().await.unwrap_or_default();
+                let message = match serde_json::from_str::<ErrorRes>(&body) {
+                    Ok(x) => Some(x.message),
+                    Err(_) if body.is_empty() => None,
+                    Err(_) => Some(body),
+                };
+
+                let retry_after = res
+                    .header("Retry-After")
+                    .and_then(|x| x.as_str().parse().ok())
+                    .map(Duration::from_secs)
+                    .or_else(|| RateLimit::from_res(&res)?.reset_in());
+
+                return Err(AuthError::Status {
+                    status: res.status(),
+                    message,
+                    retry_after,
+                });
             }
 
-            res.body_json().await.map_err(|e| e.into_inner())?
+            res.body_json()
+                .await
+                .map_err(|e| AuthError::Request(e.into_inner()))?
         };
 
         log::debug!("retrieved auth: expires in {}", res.expires_in);
@@ -54,13 +155,16 @@ impl Inner {
         ))
     }
 
-    async fn get(client_id: String, secret: &str) -> Result<Self> {
+    async fn get(client_id: String, secret: &str) -> Result<Self, AuthError> {
         let (auth, expires) = Self::_get(&client_id, secret).await?;
 
         Ok(Self {
             auth,
             client_id: client_id.into_boxed_str(),
             expires,
+            user_token: None,
+            user_refresh: None,
+            user_cache: None,
         })
     }
 
@@ -73,26 +177,410 @@ impl Inner {
 
     async fn refresh(&mut self, secret: &str) -> Result<()> {
         (self.auth, self.expires) = Self::_get(&self.client_id, secret).await?;
+        metrics::AUTH_REFRESHES.inc();
         Ok(())
     }
 }
 
+/// A user access token, as returned by the device code and refresh token grants.
+#[derive(Deserialize)]
+struct UserTokenRes {
+    access_token: String,
+    refresh_token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserCache {
+    refresh_token: Box<str>,
+}
+
+/// Sends a user access token request to the token endpoint.
+/// If twitch rejects the request, the error message it returned is given as `Ok(Err(_))`.
+async fn user_token_req(form: &[(&str, &str)]) -> Result<Result<UserTokenRes, String>> {
+    #[derive(Deserialize)]
+    struct ErrorRes {
+        message: String,
+    }
+
+    let mut res = net::client().post(api::id("token"))
+        .body(surf::Body::from_form(&form).map_err(|e| e.into_inner())?)
+        .send()
+        .await
+        .map_err(|e| e.into_inner())?;
+
+    if !res.status().is_success() {
+        let body = res.body_string().await.unwrap_or_default();
+        return Ok(Err(match serde_json::from_str::<ErrorRes>(&body) {
+            Ok(x) => x.message,
+            Err(_) => format!("status {}: {body}", res.status()),
+        }));
+    }
+
+    res.body_json()
+        .await
+        .map(Ok)
+        .map_err(|e| e.into_inner())
+}
+
+/// Obtains a user access token with the device code flow, waiting for the user to authorize it.
+async fn device_flow(client_id: &str) -> Result<UserTokenRes> {
+    #[derive(Deserialize)]
+    struct DeviceRes {
+        device_code: String,
+        expires_in: u64,
+        interval: u64,
+        user_code: String,
+        verification_uri: String,
+    }
+
+    let mut res = net::client().post(api::id("device"))
+        .body(
+            surf::Body::from_form(&[("client_id", client_id), ("scopes", DEVICE_SCOPES)])
+                .map_err(|e| e.into_inner())?,
+        )
+        .send()
+        .await
+        .map_err(|e| e.into_inner())?;
+
+    if !res.status().is_success() {
+        return Err(anyhow!(
+            "device code request returned status {}: {}",
+            res.status(),
+            res.body_string().await.unwrap_or_default()
+        ));
+    }
+
+    let device: DeviceRes = res.body_json().await.map_err(|e| e.into_inner())?;
+    log::warn!(
+        "waiting for authorization; open {} and enter the code {}",
+        device.verification_uri,
+        device.user_code
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = Duration::from_secs(device.interval.max(1));
+    loop {
+        async_std::task::sleep(interval).await;
+        if Instant::now() > deadline {
+            return Err(anyhow!("device code expired before it was authorized"));
+        }
+
+        let form = [
+            ("client_id", client_id),
+            ("scopes", DEVICE_SCOPES),
+            ("device_code", &device.device_code),
+            ("grant_type", DEVICE_GRANT),
+        ];
+        match user_token_req(&form).await? {
+            Ok(x) => return Ok(x),
+            Err(x) if x == "authorization_pending" => continue,
+            Err(x) if x == "slow_down" => interval += DEVICE_SLOW_DOWN,
+            Err(x) => return Err(anyhow!("device authorization failed: {x}")),
+        }
+    }
+}
+
+async fn refresh_user_token(client_id: &str, secret: &str, refresh: &str) -> Result<UserTokenRes> {
+    let form = [
+        ("client_id", client_id),
+        ("client_secret", secret),
+        ("refresh_token", refresh),
+        ("grant_type", "refresh_token"),
+    ];
+    user_token_req(&form)
+        .await?
+        .map_err(|x| anyhow!("user token refresh failed: {x}"))
+}
+
+/// Writes the refresh token to `path`, readable only by the owner where supported.
+async fn save_user_cache(path: &std::path::Path, refresh: &str) -> Result<()> {
+    let data = serde_json::to_vec(&UserCache {
+        refresh_token: refresh.into(),
+    })?;
+    async_std::fs::write(path, data).await?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        async_std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+
+    Ok(())
+}
+
+/// The rate limit of the app access token, as last reported by twitch.
+#[derive(Debug)]
+struct RateLimit {
+    remaining: u64,
+    reset: SystemTime,
+}
+
+impl RateLimit {
+    fn from_res(res: &surf::Response) -> Option<Self> {
+        let remaining = res.header("Ratelimit-Remaining")?.as_str().parse().ok()?;
+        let reset: u64 = res.header("Ratelimit-Reset")?.as_str().parse().ok()?;
+
+        Some(Self {
+            remaining,
+            reset: SystemTime::UNIX_EPOCH + Duration::from_secs(reset),
+        })
+    }
+
+    /// The time left until the rate limit is reset.
+    fn reset_in(&self) -> Option<Duration> {
+        self.reset.duration_since(SystemTime::now()).ok()
+    }
+}
+
+/// A request which can be sent more than once.
+/// Cloning a request leaves out its body, so the body is kept aside and attached to every copy.
+struct Resendable {
+    req: surf::Request,
+    body: Vec<u8>,
+    mime: mime::Mime,
+}
+
+impl Resendable {
+    async fn new(mut req: surf::Request) -> Result<Self> {
+        let body = req.take_body();
+        let mime = body.mime().clone();
+        let body = body.into_bytes().await.map_err(|e| e.into_inner())?;
+        Ok(Self { req, body, mime })
+    }
+
+    fn get(&self) -> surf::Request {
+        let mut req = self.req.clone();
+        if !self.body.is_empty() {
+            let mut body = surf::Body::from_bytes(self.body.clone());
+            body.set_mime(self.mime.clone());
+            req.set_body(body);
+        }
+        req
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct HelixAuth {
     inner: Arc<Mutex<(Inner, Box<str>)>>,
+    ratelimit: Arc<std::sync::Mutex<Option<RateLimit>>>,
 }
 
 impl HelixAuth {
     pub async fn new(client_id: String, secret: String) -> Result<Self> {
-        Inner::get(client_id, &secret).await.map(|x| Self {
-            inner: Arc::new(Mutex::new((x, secret.into_boxed_str()))),
-        })
+        // non-transient errors are passed through as `Ok(Err(_))` to skip the remaining retries
+        let inner = retry_backoff(
+            || async {
+                match Inner::get(client_id.clone(), &secret).await {
+                    Err(e) if !e.is_transient() => Ok(Err(e)),
+                    Err(e) => {
+                        // the backoff delay is added on top of the wait twitch asked for
+                        if let Some(x) = e.retry_after() {
+                            log::warn!("{e}; waiting {}s before retrying", x.as_secs());
+                            async_std::task::sleep(x).await;
+                        }
+                        Err(e)
+                    }
+                    x => x.map(Ok),
+                }
+            },
+            AUTH_RETRY_DELAY,
+            AUTH_RETRY_MAX_DELAY,
+            AUTH_RETRY,
+            "initial authorization",
+        )
+        .await??;
+
+        let auth = Self {
+            inner: Arc::new(Mutex::new((inner, secret.into_boxed_str()))),
+            ratelimit: Arc::new(std::sync::Mutex::new(None)),
+        };
+        auth.spawn_auto_refresh();
+
+        Ok(auth)
+    }
+
+    /// Spawns a task refreshing the app access token shortly before it expires.
+    /// The task stops when every handle of this auth has been dropped.
+    ///
+    /// A token is still refreshed when a request is unauthorized, in case this fails.
+    fn spawn_auto_refresh(&self) {
+        let weak = Arc::downgrade(&self.inner);
+
+        async_std::task::Builder::new()
+            .name("auth-refresh".to_owned())
+            .spawn(async move {
+                loop {
+                    let Some(inner) = weak.upgrade() else { return };
+                    let expires = inner.lock().await.0.expires;
+                    drop(inner);
+
+                    let wait = expires
+                        .saturating_duration_since(Instant::now())
+                        .saturating_sub(REFRESH_MARGIN);
+                    if !wait.is_zero() {
+                        async_std::task::sleep(wait).await;
+                        // the token may have been refreshed in the meantime
+                        continue;
+                    }
+
+                    let Some(inner) = weak.upgrade() else { return };
+                    // the lock is not held while the token is requested
+                    let (client_id, secret) = {
+                        let (inner, secret) = &*inner.lock().await;
+                        (inner.client_id.clone(), secret.clone())
+                    };
+
+                    let mut wait = REFRESH_RETRY_DELAY;
+                    match Inner::_get(&client_id, &secret).await {
+                        Ok((auth, expires)) => {
+                            let (inner, _) = &mut *inner.lock().await;
+                            (inner.auth, inner.expires) = (auth, expires);
+                            metrics::AUTH_REFRESHES.inc();
+                            log::debug!("refreshed auth before expiry");
+                        }
+                        Err(e) => {
+                            log::warn!("could not refresh auth before expiry: {e}");
+                            wait = wait.max(e.retry_after().unwrap_or_default());
+                        }
+                    }
+                    drop(inner);
+
+                    // also guards against tokens expiring within the margin being refreshed in a loop
+                    async_std::task::sleep(wait).await;
+                }
+            })
+            .expect("cannot spawn task");
     }
 
     async fn has_expired(&self) -> bool {
         (*self.inner.lock().await).0.has_expired()
     }
 
+    /// Sets a pre-obtained user access token, which is used for user-scoped requests.
+    /// The token is validated against the twitch server before being accepted.
+    pub async fn set_user_token(&self, token: &str) -> Result<()> {
+        #[derive(Deserialize)]
+        struct ValidateRes {
+            client_id: String,
+            login: Option<String>,
+            #[serde(default)]
+            scopes: Vec<String>,
+        }
+
+        let token = token.trim_start_matches("oauth:");
+
+        let mut res = net::client().get(api::id("validate"))
+            .header("Authorization", format!("OAuth {token}"))
+            .send()
+            .await
+            .map_err(|e| e.into_inner())?;
+
+        if !res.status().is_success() {
+            return Err(anyhow!("user token validation returned status {}", res.status()));
+        }
+
+        let res: ValidateRes = res.body_json().await.map_err(|e| e.into_inner())?;
+        log::info!(
+            "using user access token for {} (scopes: {:?})",
+            res.login.as_deref().unwrap_or("<unknown>"),
+            res.scopes
+        );
+
+        let (inner, _) = &mut *self.inner.lock().await;
+        if *res.client_id != *inner.client_id {
+            log::warn!(
+                "user access token was issued for a different client id ({}); user-scoped helix requests may fail",
+                res.client_id
+            );
+        }
+        inner.user_token = Some(token.into());
+
+        Ok(())
+    }
+
+    /// Obtains a user access token with the device code flow, which is used for user-scoped requests.
+    ///
+    /// The refresh token is cached to `cache`, so that later runs refresh the cached token
+    /// instead of asking the user to authorize again.
+    pub async fn login_device(&self, cache: Option<&std::path::Path>) -> Result<()> {
+        let (client_id, secret) = {
+            let (inner, secret) = &*self.inner.lock().await;
+            (inner.client_id.clone(), secret.clone())
+        };
+
+        let cached = match cache {
+            Some(path) => match async_std::fs::read(path).await {
+                Ok(x) => match serde_json::from_slice::<UserCache>(&x) {
+                    Ok(x) => Some(x.refresh_token),
+                    Err(e) => {
+                        log::warn!("user token cache {} is invalid; ignoring: {e}", path.display());
+                        None
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    log::warn!("could not read user token cache {}: {e}", path.display());
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let refreshed = match cached {
+            Some(x) => match refresh_user_token(&client_id, &secret, &x).await {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    log::warn!("could not refresh the cached user token; authorizing again: {e:?}");
+                    None
+                }
+            },
+            None => None,
+        };
+        let token = match refreshed {
+            Some(x) => x,
+            None => device_flow(&client_id).await?,
+        };
+
+        self.set_user_token(&token.access_token).await?;
+
+        let (inner, _) = &mut *self.inner.lock().await;
+        inner.user_refresh = Some(token.refresh_token.into());
+        inner.user_cache = cache.map(Into::into);
+        if let (Some(path), Some(refresh)) = (&inner.user_cache, &inner.user_refresh) {
+            if let Err(e) = save_user_cache(path, refresh).await {
+                log::warn!("could not write user token cache {}: {e:?}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Refreshes the user access token, if it was obtained with the device code flow.
+    pub async fn refresh_user(&self) -> Result<()> {
+        let (inner, secret) = &mut *self.inner.lock().await;
+        let Some(refresh) = &inner.user_refresh else {
+            return Err(anyhow!("user access token cannot be refreshed"));
+        };
+
+        let token = refresh_user_token(&inner.client_id, secret, refresh).await?;
+        inner.user_token = Some(token.access_token.into());
+        inner.user_refresh = Some(token.refresh_token.into());
+        metrics::AUTH_REFRESHES.inc();
+        log::debug!("refreshed user access token");
+
+        if let (Some(path), Some(refresh)) = (&inner.user_cache, &inner.user_refresh) {
+            if let Err(e) = save_user_cache(path, refresh).await {
+                log::warn!("could not write user token cache {}: {e:?}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn user_token(&self) -> Option<String> {
+        (*self.inner.lock().await).0.user_token.as_deref().map(Into::into)
+    }
+
     pub async fn refresh(&mut self) -> Result<()> {
         let (inner, secret) = &mut *self.inner.lock().await;
         inner.refresh(secret).await?;
@@ -141,21 +629,98 @@ impl HelixAuth {
             drop(lock);
 
             log::trace!("sending request: {:?}", req);
-            surf::client().send(req).await.map_err(|e| e.into_inner())
+            net::client().send(req).await.map_err(|e| e.into_inner())
         }
 
         use surf::StatusCode;
-        let b = req.clone();
-        let res = _send(self, req, false).await?;
+        let req = Resendable::new(req).await?;
+        let (mut refresh, mut refreshed) = (false, false);
+        let mut retries = 0;
+
+        loop {
+            self.throttle().await;
+            let res = _send(self, req.get(), std::mem::take(&mut refresh)).await?;
 
-        match res.status() {
-            StatusCode::Unauthorized => (),
-            x if x.is_success() => return Ok(res),
-            x => return Err(anyhow!("request returned status {}", x)),
+            let ratelimit = RateLimit::from_res(&res);
+            let reset_in = ratelimit.as_ref().and_then(RateLimit::reset_in);
+            if let Some(x) = ratelimit {
+                *self.ratelimit.lock().unwrap() = Some(x);
+            }
+
+            match res.status() {
+                StatusCode::Unauthorized if !refreshed => {
+                    log::info!("received status code 401; refreshing auth");
+                    (refresh, refreshed) = (true, true);
+                }
+                StatusCode::TooManyRequests if retries < RATELIMIT_RETRY => {
+                    retries += 1;
+                    let wait = reset_in.unwrap_or(RATELIMIT_WAIT);
+                    log::warn!(
+                        "received status code 429; retrying in {}s ({retries}/{RATELIMIT_RETRY})",
+                        wait.as_secs()
+                    );
+                    async_std::task::sleep(wait).await;
+                }
+                x if x.is_success() => return Ok(res),
+                // the response to a request with a refreshed auth is returned as is
+                _ if refreshed => return Ok(res),
+                x => return Err(anyhow!("request returned status {}", x)),
+            }
         }
+    }
 
-        log::info!("received status code 401; refreshing auth");
-        _send(self, b, true).await
+    /// Waits until the rate limit is reset, if no requests are remaining.
+    /// Each request takes from the remaining count, so that concurrent requests do not exceed the limit
+    /// before the next response updates it.
+    async fn throttle(&self) {
+        let wait = {
+            let mut lock = self.ratelimit.lock().unwrap();
+            match &mut *lock {
+                Some(x) if x.remaining == 0 => x.reset_in(),
+                Some(x) => {
+                    x.remaining -= 1;
+                    None
+                }
+                None => None,
+            }
+        };
+
+        if let Some(x) = wait {
+            log::warn!("helix rate limit reached; waiting {}s for reset", x.as_secs());
+            async_std::task::sleep(x).await;
+        }
+    }
+
+    /// Sends a request authorized with the user access token, instead of the app access token.
+    /// A token obtained with the device code flow is refreshed once if the request is unauthorized.
+    pub async fn send_user_req(&self, req: surf::Request) -> Result<surf::Response> {
+        let req = Resendable::new(req).await?;
+        let mut refreshed = false;
+
+        loop {
+            let mut req = req.get();
+            let lock = self.inner.lock().await;
+            let Some(token) = &lock.0.user_token else {
+                return Err(anyhow!("user-scoped request requires a user access token"));
+            };
+            req.insert_header("Authorization", format!("Bearer {token}"));
+            req.insert_header("Client-Id", &*lock.0.client_id);
+            let refreshable = lock.0.user_refresh.is_some();
+            drop(lock);
+
+            log::trace!("sending user request: {:?}", req);
+            let res = net::client().send(req).await.map_err(|e| e.into_inner())?;
+
+            match res.status() {
+                StatusCode::Unauthorized if refreshable && !refreshed => {
+                    log::info!("received status code 401; refreshing user access token");
+                    self.refresh_user().await?;
+                    refreshed = true;
+                }
+                x if x.is_success() => return Ok(res),
+                x => return Err(anyhow!("user request returned status {}", x)),
+            }
+        }
     }
 
     pub async fn send_req_json<T: DeserializeOwned>(&self, req: surf::Request) -> Result<T> {
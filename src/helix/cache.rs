@@ -0,0 +1,85 @@
+use anyhow::Context;
+use async_std::{fs, path};
+use chrono::{DateTime, Utc};
+use core::time::Duration;
+use std::collections::HashMap;
+
+use super::User;
+use crate::prelude::*;
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    login: Box<str>,
+    name: Box<str>,
+    cached_at: DateTime<Utc>,
+}
+
+/// An on-disk cache of resolved users, keyed by user id.
+///
+/// Logins can change, so entries older than the ttl are ignored,
+/// and the user is resolved again.
+pub struct UserCache {
+    path: path::PathBuf,
+    ttl: Duration,
+    entries: HashMap<Box<str>, CacheEntry>,
+}
+
+impl UserCache {
+    /// Loads the cache at `path`, starting with an empty cache if it does not exist or is invalid.
+    pub async fn load(path: impl Into<path::PathBuf>, ttl: Duration) -> Self {
+        let path = path.into();
+        let entries = match fs::read(&path).await {
+            Ok(x) => serde_json::from_slice(&x).unwrap_or_else(|e| {
+                log::warn!("user cache {} is invalid; ignoring: {e}", path.display());
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                log::warn!("could not read user cache {}: {e}", path.display());
+                HashMap::new()
+            }
+        };
+
+        Self { path, ttl, entries }
+    }
+
+    /// Writes the cache to disk, dropping expired entries.
+    pub async fn save(&mut self) -> Result<()> {
+        self.entries.retain(|_, x| !Self::expired(self.ttl, x));
+        let data = serde_json::to_vec(&self.entries)?;
+        fs::write(&self.path, data)
+            .await
+            .with_context(|| format!("could not write user cache {}", self.path.display()))
+    }
+
+    pub fn get_id(&self, id: &str) -> Option<User> {
+        self.entries
+            .get_key_value(id)
+            .filter(|(_, x)| !Self::expired(self.ttl, x))
+            .map(|(id, x)| User::new(id, &x.login, &x.name))
+    }
+
+    pub fn get_login(&self, login: &str) -> Option<User> {
+        self.entries
+            .iter()
+            .find(|(_, x)| x.login.eq_ignore_ascii_case(login) && !Self::expired(self.ttl, x))
+            .map(|(id, x)| User::new(id, &x.login, &x.name))
+    }
+
+    pub fn insert(&mut self, user: &User) {
+        self.entries.insert(
+            user.id().into(),
+            CacheEntry {
+                login: user.login().into(),
+                name: user.name().into(),
+                cached_at: Utc::now(),
+            },
+        );
+    }
+
+    fn expired(ttl: Duration, entry: &CacheEntry) -> bool {
+        (Utc::now() - entry.cached_at)
+            .to_std()
+            .is_ok_and(|x| x > ttl)
+    }
+}
@@ -1,10 +1,14 @@
 mod auth;
+mod cache;
 mod stream;
 mod user;
+mod video;
 
 pub use auth::*;
+pub use cache::*;
 pub use stream::*;
 pub use user::*;
+pub use video::*;
 
 pub struct Helix {
     //user_buf: Vec<UserCredentials>
@@ -1,9 +1,8 @@
 use chrono::{DateTime, Local};
 
-use super::{HelixAuth, User};
-use crate::prelude::*;
+use super::{HelixAuth, User, Video};
+use crate::{api, prelude::*};
 
-const STREAM_API: &str = "https://api.twitch.tv/helix/streams";
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(try_from = "StreamDes")]
@@ -14,10 +13,41 @@ pub struct Stream {
     game_name: Box<str>,
     title: Box<str>,
     started_at: DateTime<Local>,
+    language: Box<str>,
+    tags: Box<[Box<str>]>,
     is_mature: bool,
 }
 
 impl Stream {
+    pub fn new(
+        id: impl ToString,
+        user: User,
+        game_id: impl ToString,
+        game_name: impl ToString,
+        title: impl ToString,
+        started_at: DateTime<Local>,
+    ) -> Self {
+        Self {
+            id: id.to_string().into(),
+            user,
+            game_id: game_id.to_string().into(),
+            game_name: game_name.to_string().into(),
+            title: title.to_string().into(),
+            started_at,
+            language: "".into(),
+            tags: Box::new([]),
+            is_mature: false,
+        }
+    }
+
+    /// Sets the details which are only known from the Get Streams response.
+    pub fn with_details(mut self, language: impl ToString, tags: Vec<Box<str>>, is_mature: bool) -> Self {
+        self.language = language.to_string().into();
+        self.tags = tags.into();
+        self.is_mature = is_mature;
+        self
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
@@ -36,6 +66,17 @@ impl Stream {
     pub fn started_at(&self) -> DateTime<Local> {
         self.started_at
     }
+    /// The ISO 639-1 code of the broadcast language, or `other`.
+    /// Empty if it is not known.
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+    pub fn tags(&self) -> &[Box<str>] {
+        &self.tags
+    }
+    pub fn is_mature(&self) -> bool {
+        self.is_mature
+    }
 }
 
 #[derive(Deserialize)]
@@ -48,9 +89,22 @@ pub struct StreamDes {
     game_name: Box<str>,
     title: Box<str>,
     started_at: Box<str>,
+    #[serde(default)]
+    language: Box<str>,
+    /// Twitch returns `null` for streams without tags.
+    #[serde(default, deserialize_with = "nullable")]
+    tags: Vec<Box<str>>,
     is_mature: bool,
 }
 
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 impl TryFrom<StreamDes> for Stream {
     type Error = chrono::ParseError;
 
@@ -62,11 +116,31 @@ impl TryFrom<StreamDes> for Stream {
             game_name: value.game_name,
             title: value.title,
             started_at: DateTime::parse_from_rfc3339(&value.started_at)?.with_timezone(&Local),
+            language: value.language,
+            tags: value.tags.into(),
             is_mature: value.is_mature,
         })
     }
 }
 
+impl From<&Video> for Stream {
+    /// Describes a VOD as a stream, for formatting and datafiles.
+    /// The game is not known from a video, and is left empty.
+    fn from(value: &Video) -> Self {
+        Self {
+            id: value.id().into(),
+            user: value.user().clone(),
+            game_id: "".into(),
+            game_name: "".into(),
+            title: value.title().into(),
+            started_at: value.created_at(),
+            language: "".into(),
+            tags: Box::new([]),
+            is_mature: false,
+        }
+    }
+}
+
 pub enum StreamFilter<'a> {
     User(&'a User),
     GameId(&'a str),
@@ -74,11 +148,28 @@ pub enum StreamFilter<'a> {
     Language(&'a str),
 }
 
+/// The largest number of streams the Get Streams api returns per page.
+pub const MAX_PAGE_SIZE: u8 = 100;
+
 use futures::TryStream;
+/// Fetches the live streams matching every filter, in pages of [`MAX_PAGE_SIZE`].
 pub fn get_streams<'a, T>(
     auth: HelixAuth,
     filter: T,
 ) -> impl TryStream<Ok = Stream, Error = anyhow::Error> + Unpin
+where
+    T: IntoIterator<Item = StreamFilter<'a>>,
+{
+    get_streams_paged(auth, filter, MAX_PAGE_SIZE)
+}
+
+/// Fetches the live streams matching every filter, `first` streams per page.
+/// `first` is clamped to between 1 and [`MAX_PAGE_SIZE`].
+pub fn get_streams_paged<'a, T>(
+    auth: HelixAuth,
+    filter: T,
+    first: u8,
+) -> impl TryStream<Ok = Stream, Error = anyhow::Error> + Unpin
 where
     T: IntoIterator<Item = StreamFilter<'a>>,
 {
@@ -98,8 +189,10 @@ where
         pagination: Pagination,
     }
 
-    let mut url: Url = STREAM_API.parse().unwrap();
+    let first = first.clamp(1, MAX_PAGE_SIZE);
+    let mut url: Url = api::helix("streams").parse().unwrap();
     url.query_pairs_mut()
+        .append_pair("first", &first.to_string())
         .extend_pairs(filter.into_iter().map(|x| match x {
             StreamFilter::User(user) => ("user_id", user.id()),
             StreamFilter::GameId(x) => ("game_id", x),
@@ -113,7 +206,7 @@ where
 
     return futures::stream::try_unfold(
         (State::Init(Box::new(url)), auth),
-        |(state, auth)| async {
+        move |(state, auth)| async move {
             let (mut data, page) = match state {
                 State::Init(url) => {
                     log::trace!("fetching streams: {url}");
@@ -145,9 +238,9 @@ where
             log::trace!("fetching streams (next page)");
             let res: GetStreamsRes = auth
                 .send_req_json(
-                    surf::get(STREAM_API)
+                    surf::get(api::helix("streams"))
                         .query(&Query {
-                            first: 100,
+                            first,
                             after: &cursor,
                         })
                         .map_err(|e| e.into_inner())?
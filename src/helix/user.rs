@@ -3,9 +3,10 @@ use core::hash::{Hash, Hasher};
 use url::Url;
 
 use super::HelixAuth;
-use crate::prelude::*;
+use crate::{api, prelude::*};
 
-const USER_API: &str = "https://api.twitch.tv/helix/users";
+/// The maximum number of users that can be requested at once.
+const MAX_USERS: usize = 100;
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -168,9 +169,9 @@ impl From<User> for UserSer {
     }
 }
 
-#[derive(PartialEq, Eq, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
 #[serde(rename_all = "snake_case")]
-pub(crate) enum UserCredentials<'a> {
+pub enum UserCredentials<'a> {
     Id(&'a str),
     Login(&'a str),
 }
@@ -193,7 +194,7 @@ async fn _get_user(
         details: Details,
     }
 
-    let mut url: Url = USER_API.parse().unwrap();
+    let mut url: Url = api::helix("users").parse().unwrap();
     url.query_pairs_mut()
         .extend_pairs(users.iter().map(|user| match user {
             UserCredentials::Id(id) => ("id", *id),
@@ -213,6 +214,16 @@ async fn _get_user(
     ))
 }
 
+/// Retrieves every given user, requesting up to 100 users at once.
+/// Users that could not be found are missing from the result.
+pub async fn get_users(auth: &HelixAuth, users: &[UserCredentials<'_>]) -> Result<Vec<User>> {
+    let mut res = Vec::with_capacity(users.len());
+    for chunk in users.chunks(MAX_USERS) {
+        res.extend(_get_user(auth, chunk).await?);
+    }
+    Ok(res)
+}
+
 pub(crate) async fn get_user(auth: &HelixAuth, cred: UserCredentials<'_>) -> Result<User> {
     Ok(_get_user(auth, &[cred])
         .await?
@@ -0,0 +1,155 @@
+use chrono::{DateTime, Local};
+
+use super::{HelixAuth, User};
+use crate::{api, prelude::*};
+
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "VideoDes")]
+pub struct Video {
+    id: Box<str>,
+    user: User,
+    title: Box<str>,
+    created_at: DateTime<Local>,
+    duration: Box<str>,
+}
+
+impl Video {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+    pub fn created_at(&self) -> DateTime<Local> {
+        self.created_at
+    }
+    /// The video length, in twitch's `1h2m3s` format.
+    pub fn duration(&self) -> &str {
+        &self.duration
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VideoDes {
+    id: Box<str>,
+    user_id: Box<str>,
+    user_login: Box<str>,
+    user_name: Box<str>,
+    title: Box<str>,
+    created_at: Box<str>,
+    duration: Box<str>,
+}
+
+impl TryFrom<VideoDes> for Video {
+    type Error = chrono::ParseError;
+
+    fn try_from(value: VideoDes) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: value.id,
+            user: User::new(value.user_id, value.user_login, value.user_name),
+            title: value.title,
+            created_at: DateTime::parse_from_rfc3339(&value.created_at)?.with_timezone(&Local),
+            duration: value.duration,
+        })
+    }
+}
+
+pub enum VideoFilter<'a> {
+    Id(&'a str),
+    User(&'a User),
+    GameId(&'a str),
+}
+
+use futures::TryStream;
+pub fn get_videos<'a, T>(
+    auth: HelixAuth,
+    filter: T,
+) -> impl TryStream<Ok = Video, Error = anyhow::Error> + Unpin
+where
+    T: IntoIterator<Item = VideoFilter<'a>>,
+{
+    use futures::StreamExt;
+    use std::collections::VecDeque;
+    use surf::{http, RequestBuilder};
+    use url::Url;
+
+    #[derive(Debug, Deserialize)]
+    struct Pagination {
+        cursor: Option<Box<str>>,
+    }
+
+    #[derive(Deserialize)]
+    struct GetVideosRes {
+        data: VecDeque<Video>,
+        pagination: Pagination,
+    }
+
+    let mut url: Url = api::helix("videos").parse().unwrap();
+    url.query_pairs_mut()
+        .extend_pairs(filter.into_iter().map(|x| match x {
+            VideoFilter::Id(x) => ("id", x),
+            VideoFilter::User(user) => ("user_id", user.id()),
+            VideoFilter::GameId(x) => ("game_id", x),
+        }));
+
+    enum State<T: Iterator<Item = Video>> {
+        Init(Box<Url>),
+        Next(T, Pagination),
+    }
+
+    return futures::stream::try_unfold(
+        (State::Init(Box::new(url)), auth),
+        |(state, auth)| async {
+            let (mut data, page) = match state {
+                State::Init(url) => {
+                    log::trace!("fetching videos: {url}");
+                    let res: GetVideosRes = auth
+                        .send_req_json(RequestBuilder::new(http::Method::Get, *url).build())
+                        .await?;
+                    log::trace!("fetch successful: {:?}, {:?}", res.data, res.pagination);
+                    (res.data.into_iter(), res.pagination)
+                }
+                State::Next(data, page) => (data, page),
+            };
+
+            if let Some(x) = data.next() {
+                return Ok(Some((x, (State::Next(data, page), auth))));
+            }
+            log::trace!("no data; fetching next page");
+
+            let Some(cursor) = page.cursor else { return Ok(None) };
+            if cursor.is_empty() {
+                return Ok(None);
+            };
+
+            #[derive(Serialize)]
+            struct Query<'a> {
+                first: u8,
+                after: &'a str,
+            }
+
+            log::trace!("fetching videos (next page)");
+            let res: GetVideosRes = auth
+                .send_req_json(
+                    surf::get(api::helix("videos"))
+                        .query(&Query {
+                            first: 100,
+                            after: &cursor,
+                        })
+                        .map_err(|e| e.into_inner())?
+                        .build(),
+                )
+                .await?;
+
+            let (mut data, page) = (res.data.into_iter(), res.pagination);
+
+            Ok(data.next().map(|x| (x, (State::Next(data, page), auth))))
+        },
+    )
+    .fuse()
+    .boxed();
+}
@@ -1,60 +1,252 @@
-use anyhow::{anyhow, Context};
 use async_std::{
     fs, io::{self, BufWriter, WriteExt}, path, task, future::timeout
 };
-use futures::{SinkExt, AsyncWrite, Stream, io::AllowStdIo, StreamExt, future};
-use m3u8_rs::{AlternativeMedia, AlternativeMediaType, VariantStream, MediaPlaylist, MediaPlaylistType, MediaSegment};
-use once_cell::sync::Lazy;
-use std::{time, sync::Arc};
-use surf::{Client, Response, Url, http::Method, RequestBuilder};
+use futures::{SinkExt, AsyncWrite, Stream, io::AllowStdIo, StreamExt, FutureExt, channel::{mpsc, oneshot}, future::{self, Either}, stream::FuturesUnordered};
+use m3u8_rs::{AlternativeMedia, AlternativeMediaType, ByteRange, DateRange, Map, MasterPlaylist, VariantStream, MediaPlaylist, MediaPlaylistType, MediaSegment};
+use once_cell::sync::OnceCell;
+use std::{collections::BTreeMap, time, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+use surf::{Response, Url, http::Method, RequestBuilder};
 
 use crate::prelude::*;
-use crate::retry::retry;
+use crate::{checksum::{hash_file, sha256_hex, Checksums, HashWriter}, metrics, net, retry::retry};
 
 pub type StreamData = (path::PathBuf, AlternativeMedia, Option<VariantStream>);
 
-static CLIENT: Lazy<Client> = Lazy::new(|| surf::Config::new()
-        .set_timeout(Some(time::Duration::from_secs(10)))
-        .try_into()
-        .unwrap()
-    );
+pub const SEGMENT_CONCURRENCY: usize = 6;
+pub const MAX_SEGMENT_CONCURRENCY: usize = 32;
+/// The number of completed segments kept while waiting for an earlier, slower segment.
+const MAX_REORDER: usize = 2 * MAX_SEGMENT_CONCURRENCY;
+pub const SEGMENT_TIMEOUT: time::Duration = time::Duration::from_secs(300);
+/// The default number of attempts at a request to the CDN.
+pub const REQUEST_RETRY: usize = 10;
+/// The default timeout of a request to the CDN.
+pub const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// The number of attempts at fetching a complete segment body.
+const SEGMENT_RETRY: usize = 3;
+const SEGMENT_RETRY_DELAY: time::Duration = time::Duration::from_secs(1);
+const SEGMENT_BODY_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+/// The number of written segments after which the media playlist is flushed to disk,
+/// so that a partial download is always playable.
+const PLAYLIST_FLUSH_SEGMENTS: usize = 5;
+/// The time after which the media playlist is flushed, even if fewer segments were written.
+const PLAYLIST_FLUSH_INTERVAL: time::Duration = time::Duration::from_secs(10);
 
-pub async fn get(uri: impl Into<Url>, context: &str) -> Result<Response> {
+/// An error while downloading a HLS stream.
+#[derive(Debug)]
+pub enum HlsError {
+    /// A request failed, or returned an unsuccessful status.
+    Http(surf::Error),
+    /// A playlist url is malformed.
+    Url(url::ParseError),
+    /// A response body was not received in time.
+    Timeout { context: String },
+    /// A segment body was not received in time, on every attempt.
+    SegmentTimeout { index: usize },
+    /// A segment body was shorter than expected, on every attempt.
+    SegmentIncomplete { index: usize, received: usize, expected: usize },
+    /// The master playlist could not be parsed.
+    MasterParse,
+    /// The media playlist could not be parsed.
+    MediaParse,
+    /// No rendition of the master playlist matched any of the requested formats.
+    NoMatchingQuality { formats: Vec<String> },
+    /// The selected rendition has no media playlist url.
+    MissingUri { format: String },
+    /// The output could not be written.
+    Io { context: &'static str, source: io::Error },
+    /// Some of the renditions downloaded together failed, each named along with its error.
+    /// The renditions which finished are kept in `completed`.
+    RenditionsFailed { completed: Vec<StreamData>, failed: Vec<(String, HlsError)> },
+}
+
+impl HlsError {
+    /// Returns whether the error is caused by the network, and may not occur on a later attempt.
+    pub fn is_network(&self) -> bool {
+        matches!(
+            self,
+            Self::Http(_) | Self::Timeout { .. } | Self::SegmentTimeout { .. } | Self::SegmentIncomplete { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for HlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "request failed: {e}"),
+            Self::Url(e) => write!(f, "malformed playlist url: {e}"),
+            Self::Timeout { context } => write!(f, "{context} timed out"),
+            Self::SegmentTimeout { index } => write!(f, "media segment #{index} timed out"),
+            Self::SegmentIncomplete { index, received, expected } => {
+                write!(f, "media segment #{index} returned {received} of {expected} bytes")
+            }
+            Self::MasterParse => write!(f, "malformed m3u8 hls master playlist"),
+            Self::MediaParse => write!(f, "malformed m3u8 hls media playlist"),
+            Self::NoMatchingQuality { formats } => {
+                write!(f, "no rendition matches the formats {formats:?}")
+            }
+            Self::MissingUri { format } => write!(f, "url missing for format {format}"),
+            Self::Io { context, source } => write!(f, "{context}: {source}"),
+            Self::RenditionsFailed { completed, failed } => {
+                write!(f, "{} of {} renditions failed", failed.len(), failed.len() + completed.len())?;
+                for (name, e) in failed {
+                    write!(f, "; {name}: {e}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for HlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(e) => Some(e.as_ref()),
+            Self::Url(e) => Some(e),
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<url::ParseError> for HlsError {
+    fn from(e: url::ParseError) -> Self {
+        Self::Url(e)
+    }
+}
+
+/// Wraps an I/O error with what was being done.
+fn io_err(context: &'static str) -> impl FnOnce(io::Error) -> HlsError {
+    move |source| HlsError::Io { context, source }
+}
+
+/// Settings for downloading the media segments of a stream.
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    /// The number of segments fetched at once.
+    /// In adaptive mode, this is the initial value.
+    pub segment_concurrency: usize,
+    pub adaptive_concurrency: bool,
+    /// The duration to wait for a new segment before considering the stream as ended.
+    pub segment_timeout: time::Duration,
+    /// Appends all segments to a single file, addressed by byte ranges in the playlist.
+    pub single_file: bool,
+    /// Records the checksum of each written file.
+    pub checksums: Option<Checksums>,
+    /// Leaves out segments of ads stitched into the stream.
+    pub skip_ads: bool,
+    /// Names segment files by their media sequence number, instead of their index in the playlist.
+    pub sequence_names: bool,
+    /// The number of consecutive empty or failed playlist polls after which the stream is considered ended.
+    pub max_empty_polls: Option<u32>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            segment_concurrency: SEGMENT_CONCURRENCY,
+            adaptive_concurrency: false,
+            segment_timeout: SEGMENT_TIMEOUT,
+            single_file: false,
+            checksums: None,
+            skip_ads: true,
+            sequence_names: false,
+            max_empty_polls: None,
+        }
+    }
+}
+
+/// Settings for the requests made to the CDN.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestOptions {
+    /// The number of attempts at a request.
+    pub retry: usize,
+    /// The timeout of a request, and of receiving its body.
+    pub timeout: time::Duration,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            retry: REQUEST_RETRY,
+            timeout: REQUEST_TIMEOUT,
+        }
+    }
+}
+
+static REQUEST_OPTIONS: OnceCell<RequestOptions> = OnceCell::new();
+
+/// Sets the request options used for every download.
+/// Has no effect once a request has been made.
+pub fn set_request_options(opts: RequestOptions) {
+    let _ = REQUEST_OPTIONS.set(opts);
+}
+
+fn request_options() -> &'static RequestOptions {
+    REQUEST_OPTIONS.get_or_init(RequestOptions::default)
+}
+
+pub async fn get(uri: impl Into<Url>, context: &str) -> Result<Response, HlsError> {
     let uri = uri.into();
     log::trace!("sending {context}: {uri}");
 
     retry(|| async {
         let req = RequestBuilder::new(Method::Get, uri.clone()).build();
 
-        let res = CLIENT.send(req).await
-            .map_err(|e| e.into_inner())
-            .with_context(|| format!("{context} failed"))?;
+        let res = timeout(request_options().timeout, net::client().send(req)).await
+            .map_err(|_| HlsError::Timeout { context: context.to_owned() })?
+            .map_err(HlsError::Http)?;
         
         if !res.status().is_success() {
-            return Err(anyhow!(
-                "{context} returned status {}",
-                res.status()
-            ))
+            return Err(HlsError::Http(surf::Error::from_str(
+                res.status(),
+                format!("{context} returned status {}", res.status())
+            )))
         }
 
         Ok(res)
-    }, time::Duration::ZERO, 10, context).await
+    }, time::Duration::ZERO, request_options().retry, context).await
 }
 
-pub async fn get_bytes(uri: impl Into<Url>, context: &str) -> Result<Vec<u8>> {
+pub async fn get_bytes(uri: impl Into<Url>, context: &str) -> Result<Vec<u8>, HlsError> {
     let uri = uri.into();
 
     retry(|| async {
         let mut res = get(uri.clone(), context).await?;
 
-        timeout(time::Duration::from_secs(10), res.body_bytes()).await?
-            .map_err(|e| e.into_inner())
-    }, time::Duration::ZERO, 10, context).await
+        timeout(request_options().timeout, res.body_bytes()).await
+            .map_err(|_| HlsError::Timeout { context: context.to_owned() })?
+            .map_err(HlsError::Http)
+    }, time::Duration::ZERO, request_options().retry, context).await
+}
+
+/// Fetches the body of the `index`th segment, retrying when fewer bytes than the `Content-Length` were received.
+pub async fn get_segment(uri: Url, index: usize) -> Result<Vec<u8>, HlsError> {
+    let context = format!("request for media segment #{index}");
+
+    retry(|| async {
+        let mut res = get(uri.clone(), &context).await?;
+        let expected = res.len();
+
+        let body = timeout(SEGMENT_BODY_TIMEOUT, res.body_bytes()).await
+            .map_err(|_| HlsError::SegmentTimeout { index })?
+            .map_err(HlsError::Http)?;
+
+        match expected {
+            Some(n) if n != body.len() => Err(HlsError::SegmentIncomplete {
+                index,
+                received: body.len(),
+                expected: n,
+            }),
+            _ => Ok(body),
+        }
+    }, SEGMENT_RETRY_DELAY, SEGMENT_RETRY, &context).await
 }
 
 pub struct MediaPlaylistWriter<W> {
     buf: Vec<u8>,
-    writer: Option<BufWriter<W>>
+    writer: Option<BufWriter<W>>,
+    /// The end of the last segment written with `write_range`
+    offset: u64,
 }
 
 impl<W> MediaPlaylistWriter<W> {
@@ -64,9 +256,18 @@ impl<W> MediaPlaylistWriter<W> {
 
         Ok( Self {
             buf,
-            writer: None
+            writer: None,
+            offset: 0,
         } )
     }
+
+    /// Continues writing the playlist of an interrupted download.
+    fn resume(resume: &Resume) -> std::io::Result<Self> {
+        Ok(Self {
+            offset: resume.offset,
+            ..Self::new(&resume.playlist)?
+        })
+    }
 }
 
 impl<W: AsyncWrite + Unpin> MediaPlaylistWriter<W> {
@@ -78,6 +279,11 @@ impl<W: AsyncWrite + Unpin> MediaPlaylistWriter<W> {
         Ok(())
     }
 
+    /// The writer the playlist is written to, once initialized.
+    pub fn get_ref(&self) -> Option<&W> {
+        self.writer.as_ref().map(BufWriter::get_ref)
+    }
+
     pub async fn init(&mut self, writer: W) -> io::Result<()> {
         self.writer = Some(BufWriter::new(writer));
         self.write_buf().await
@@ -88,6 +294,29 @@ impl<W: AsyncWrite + Unpin> MediaPlaylistWriter<W> {
         self.write_buf().await
     }
 
+    /// Flushes the segments written so far to the playlist file.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        if let Some(w) = &mut self.writer {
+            w.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// The offset the next segment written with `write_range` starts at.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Writes a segment which was appended to a single media file as the next `length` bytes.
+    pub async fn write_range(&mut self, mut segment: MediaSegment, length: u64) -> io::Result<()> {
+        segment.byte_range = Some(ByteRange {
+            length,
+            offset: Some(self.offset),
+        });
+        self.offset += length;
+        self.write_segment(segment).await
+    }
+
     pub async fn finish(&mut self) -> io::Result<()> {
         self.buf.extend(b"#EXT-X-ENDLIST");
         
@@ -100,19 +329,66 @@ impl<W: AsyncWrite + Unpin> MediaPlaylistWriter<W> {
     }
 }
 
-pub async fn spawn_downloader<W> (uri: Url) -> Result<(MediaPlaylistWriter<W>, impl Stream<Item = MediaSegment>)> {
-    async fn fetch_media(uri: Url) -> Result<MediaPlaylist> {
-        let body = get_bytes(uri, "request for media playlist").await
-            .context("failed to retrieve media playlist")?;
+/// Tracks the `EXT-X-MAP` in effect, so that it is written again only when it changes.
+/// Twitch only tags the first segment of each playlist with the map,
+/// which is usually a segment that was already received.
+#[derive(Default)]
+struct MapState {
+    current: Option<Map>,
+    sent: Option<Map>,
+}
+
+impl MapState {
+    fn observe(&mut self, segment: &MediaSegment) {
+        if let Some(x) = &segment.map {
+            self.current = Some(x.clone());
+        }
+    }
+
+    fn attach(&mut self, segment: &mut MediaSegment) {
+        segment.map = if self.current != self.sent {
+            self.sent = self.current.clone();
+            self.current.clone()
+        } else {
+            None
+        };
+    }
+}
+
+/// Polls the media playlist, yielding each new segment with its media sequence number.
+/// The stream ends on `#EXT-X-ENDLIST`, or after one last poll once `stop` is signaled,
+/// so that the playlist can be finished without waiting for the segment timeout.
+///
+/// Segments before the media sequence number `from` are skipped, to continue a previous download.
+///
+/// With `max_empty_polls`, the stream also ends after that many consecutive polls returned no segments,
+/// and a failed poll counts as an empty one instead of ending the stream.
+pub async fn spawn_downloader<W> (
+    uri: Url,
+    stop: oneshot::Receiver<()>,
+    from: Option<u64>,
+    max_empty_polls: Option<u32>,
+) -> Result<(MediaPlaylistWriter<W>, impl Stream<Item = (u64, MediaSegment)>), HlsError> {
+    async fn fetch_media(uri: Url) -> Result<MediaPlaylist, HlsError> {
+        let body = get_bytes(uri, "request for media playlist").await?;
 
         let (_, media) = m3u8_rs::parse_media_playlist(&body).map_err(|e| {
             log::error!("failed to parse m3u8 hls media playlist: {e:?}");
-            anyhow!("failed to parse m3u8 hls media playlist")
+            HlsError::MediaParse
         })?;
 
         Ok(media)
     }
 
+    /// Sleeps until `until`, returning whether `stop` resolved first.
+    async fn sleep_until<F>(until: time::Instant, stop: &mut F) -> bool
+    where
+        F: futures::Future<Output = ()> + Unpin,
+    {
+        let sleep = Box::pin(task::sleep(until.saturating_duration_since(time::Instant::now())));
+        matches!(future::select(sleep, stop).await, Either::Right(_))
+    }
+
     let (mut tx, rx) = futures::channel::mpsc::unbounded();
 
     let media = fetch_media(uri.clone()).await?;
@@ -120,16 +396,35 @@ pub async fn spawn_downloader<W> (uri: Url) -> Result<(MediaPlaylistWriter<W>, i
     let len = media.segments.len() as u64;
 
     log::trace!("received {len} segments ({} - {})", media.media_sequence, media.media_sequence + len);
-    for e in media.segments {
-        tx.send(e).await?;
+    let from = from.unwrap_or(media.media_sequence);
+    if from < media.media_sequence {
+        log::warn!(
+            "media sequence bigger than the resumed position ({} > {from}); {} segments were lost",
+            media.media_sequence,
+            media.media_sequence - from
+        );
+    }
+
+    let target = time::Duration::from_secs_f32(media.target_duration);
+    let mut maps = MapState::default();
+    for (seq, mut e) in (media.media_sequence..).zip(media.segments) {
+        maps.observe(&e);
+        if seq < from {
+            continue;
+        }
+        maps.attach(&mut e);
+        // the receiver is still held here
+        let _ = tx.send((seq, e)).await;
     }
 
     let mw = MediaPlaylistWriter::new(&MediaPlaylist {
+        // EXT-X-BYTERANGE requires version 4
+        version: Some(media.version.unwrap_or(0).max(4)),
         segments: Vec::new(),
         end_list: false,
         playlist_type: Some(MediaPlaylistType::Vod),
         ..media
-    })?;
+    }).map_err(io_err("failed to write media playlist"))?;
 
     if media.end_list {
         log::trace!("received ENDLIST; finishing stream");
@@ -140,22 +435,55 @@ pub async fn spawn_downloader<W> (uri: Url) -> Result<(MediaPlaylistWriter<W>, i
     let _ = task::Builder::new()
         .name(format!("{}-hls", task::current().name().unwrap_or(&task::current().id().to_string())))
         .spawn(async move {
-            let mut pos = media.media_sequence + len;
+            let mut pos = (media.media_sequence + len).max(from);
             let mut tx = tx;
+            let mut maps = maps;
 
-            task::sleep(next_poll - time::Instant::now()).await;
+            // the sender being dropped without signaling does not stop the stream
+            let mut stop = Box::pin(async move {
+                if stop.await.is_err() {
+                    future::pending::<()>().await;
+                }
+            }.fuse());
+            let mut stopped = sleep_until(next_poll, &mut stop).await;
+            let mut target = target;
+            let mut empty: u32 = 0;
 
             loop {
                 let ts = time::Instant::now();
-                let media = fetch_media(uri.clone()).await?;
-                let next_poll = ts + time::Duration::from_secs_f32(media.target_duration);
+                let media = match fetch_media(uri.clone()).await {
+                    Ok(x) => Some(x),
+                    Err(e) if max_empty_polls.is_some() => {
+                        log::debug!("failed to poll media playlist: {e}");
+                        None
+                    }
+                    Err(e) => return Err(e),
+                };
+                if let Some(x) = &media {
+                    target = time::Duration::from_secs_f32(x.target_duration);
+                }
+                let next_poll = ts + target;
 
-                let mut list = media.segments;
+                let Some(media) = media.filter(|x| !x.segments.is_empty()) else {
+                    if stopped {
+                        log::info!("download stopped; finishing stream after trailing segments");
+                        tx.close_channel();
+                        break;
+                    }
+
+                    empty += 1;
+                    if max_empty_polls.is_some_and(|x| empty >= x) {
+                        log::info!("no segments received in {empty} consecutive polls; finishing stream");
+                        tx.close_channel();
+                        break;
+                    }
 
-                if list.is_empty() {
-                    task::sleep(next_poll - time::Instant::now()).await;
+                    stopped = sleep_until(next_poll, &mut stop).await;
                     continue;
-                }
+                };
+                empty = 0;
+
+                let list = media.segments;
 
                 let len = list.len() as u64;
                 log::trace!("received {len} segments ({pos} - {})", pos + len);
@@ -167,13 +495,20 @@ pub async fn spawn_downloader<W> (uri: Url) -> Result<(MediaPlaylistWriter<W>, i
                     }
                     None => {
                         log::warn!("media sequence bigger than expected pos ({} > {pos}); stream may not be continuous!", media.media_sequence);
-                        list[0].discontinuity = true;
                         0
                     }
                 };
 
-                for e in list.into_iter().skip(skip) {
-                    tx.send(e).await?;
+                for (i, mut e) in list.into_iter().enumerate() {
+                    maps.observe(&e);
+                    if i < skip {
+                        continue;
+                    }
+                    maps.attach(&mut e);
+                    if tx.send((media.media_sequence + i as u64, e)).await.is_err() {
+                        log::trace!("segment receiver dropped; stopping playlist polling");
+                        return Ok(());
+                    }
                 }
 
                 pos = media.media_sequence + len as u64;
@@ -184,132 +519,825 @@ pub async fn spawn_downloader<W> (uri: Url) -> Result<(MediaPlaylistWriter<W>, i
                     break;
                 }
 
-                task::sleep(next_poll - time::Instant::now()).await;
+                if stopped {
+                    log::info!("download stopped; finishing stream after trailing segments");
+                    tx.close_channel();
+                    break;
+                }
+
+                stopped = sleep_until(next_poll, &mut stop).await;
             }
 
-            Result::<()>::Ok(())
+            Result::<(), HlsError>::Ok(())
         });
 
     Ok((mw, rx))
 }
 
+/// Controls how many segments are fetched at once.
+/// When adaptive, the width grows while segments arrive faster than their playback duration,
+/// and is halved when a segment takes longer than its duration to fetch.
+struct Concurrency {
+    width: usize,
+    adaptive: bool,
+}
+
+impl Concurrency {
+    fn update(&mut self, latency: time::Duration, duration: f32) {
+        if !self.adaptive {
+            return;
+        }
+
+        let duration = time::Duration::from_secs_f32(duration);
+        let width = if latency > duration {
+            (self.width / 2).max(1)
+        } else if latency * 2 < duration {
+            (self.width + 1).min(MAX_SEGMENT_CONCURRENCY)
+        } else {
+            self.width
+        };
+
+        if width != self.width {
+            log::debug!("segment concurrency changed ({} -> {width}); latency {latency:?} for {duration:?} segment", self.width);
+            self.width = width;
+        }
+    }
+}
+
+/// Maps the stream time of each written segment to its location on disk,
+/// so that external tools can align their output to the archive.
+#[derive(Default, Serialize)]
+struct Timeline {
+    segments: Vec<TimelineEntry>,
+    #[serde(skip)]
+    position: f64,
+}
+
+#[derive(Serialize)]
+struct TimelineEntry {
+    /// Seconds since the start of the archive
+    start: f64,
+    duration: f32,
+    path: String,
+    offset: u64,
+    length: u64,
+    /// The hex-encoded SHA-256 of the segment body
+    sha256: String,
+    discontinuity: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    program_date_time: Option<String>,
+}
+
+impl Timeline {
+    fn push(&mut self, segment: &MediaSegment, offset: u64, length: u64, sha256: String) {
+        self.segments.push(TimelineEntry {
+            start: self.position,
+            duration: segment.duration,
+            path: segment.uri.clone(),
+            offset,
+            length,
+            sha256,
+            discontinuity: segment.discontinuity,
+            program_date_time: segment.program_date_time.map(|x| x.to_rfc3339()),
+        });
+        self.position += segment.duration as f64;
+    }
+
+    /// Writes the timeline to `path`, returning the SHA-256 of the written file.
+    async fn write(&self, path: &path::Path) -> io::Result<String> {
+        let data = serde_json::to_vec(self)?;
+        let mut file = fs::File::create(path).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        Ok(sha256_hex(&data))
+    }
+}
+
+/// Points every entry of the timeline in `dir` at `file`, after its segments were remuxed into it,
+/// returning the SHA-256 of the rewritten timeline.
+///
+/// The stream times still apply to the remuxed file, but the byte ranges and checksums
+/// of the segments no longer exist, so they are left out.
+pub async fn remux_timeline(dir: &path::Path, file: &str) -> io::Result<String> {
+    let path = dir.join("timeline.json");
+    let mut timeline: serde_json::Value = serde_json::from_slice(&fs::read(&path).await?)?;
+
+    let segments = timeline["segments"].as_array_mut().into_iter().flatten();
+    for x in segments.filter_map(serde_json::Value::as_object_mut) {
+        x.insert("path".to_owned(), file.into());
+        x.remove("offset");
+        x.remove("length");
+        x.remove("sha256");
+    }
+
+    let data = serde_json::to_vec(&timeline)?;
+    fs::write(&path, &data).await?;
+    Ok(sha256_hex(&data))
+}
+
+/// The part of an interrupted download which `download_media` continues from.
+struct Resume {
+    /// The playlist as it was written, up to its last complete segment.
+    playlist: MediaPlaylist,
+    /// The media sequence number of the next segment, at the earliest;
+    /// skipped ads make the actual number larger.
+    sequence: u64,
+    /// The program date time of the last written segment.
+    last_time: Option<chrono::DateTime<chrono::FixedOffset>>,
+    /// The end of the last segment in the single media file.
+    offset: u64,
+    timeline: Timeline,
+}
+
+impl Resume {
+    /// Reads the playlist at `mediapath` left behind by an interrupted download.
+    /// Returns `None` if there is no playlist, or if it was already finished.
+    async fn read(mediapath: &path::Path, dest: &path::Path, single_file: bool) -> Result<Option<Self>, HlsError> {
+        use async_std::io::{prelude::SeekExt, ReadExt, SeekFrom};
+
+        let data = match fs::read(mediapath).await {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_err("failed to read media playlist")(e)),
+        };
+
+        // the playlist may end in a partially written segment, which is cut off after its last uri
+        let end = data
+            .split_inclusive(|&x| x == b'\n')
+            .scan(0, |pos, line| {
+                *pos += line.len();
+                Some((*pos, line))
+            })
+            .filter(|(_, line)| !line.starts_with(b"#") && !line.trim_ascii().is_empty())
+            .last()
+            .map_or(data.len(), |(pos, _)| pos);
+
+        let (_, mut playlist) = m3u8_rs::parse_media_playlist(&data[..end]).map_err(|e| {
+            log::error!("failed to parse previous media playlist: {e:?}");
+            HlsError::MediaParse
+        })?;
+        if playlist.end_list {
+            return Ok(None);
+        }
+
+        let mut single = match single_file {
+            true => Some(
+                fs::File::open(mediapath.with_extension("ts"))
+                    .await
+                    .map_err(io_err("failed to open media file"))?,
+            ),
+            false => None,
+        };
+        let single_len = match &single {
+            Some(x) => x.metadata().await.map_err(io_err("failed to open media file"))?.len(),
+            None => 0,
+        };
+
+        // the checksums of the timeline are taken again from what was written
+        let mut timeline = Timeline::default();
+        let mut offset = 0;
+        let mut complete = 0;
+        for segment in &playlist.segments {
+            let (body, start) = match (&mut single, &segment.byte_range) {
+                (Some(file), Some(range)) => {
+                    let start = range.offset.unwrap_or(offset);
+                    if start + range.length > single_len {
+                        break;
+                    }
+                    let mut body = vec![0; range.length as usize];
+                    file.seek(SeekFrom::Start(start)).await.map_err(io_err("failed to read media file"))?;
+                    file.read_exact(&mut body).await.map_err(io_err("failed to read media file"))?;
+                    (body, start)
+                }
+                _ => match fs::read(dest.join(&segment.uri)).await {
+                    Ok(x) => (x, 0),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                    Err(e) => return Err(io_err("failed to read segment file")(e)),
+                },
+            };
+
+            let length = body.len() as u64;
+            timeline.push(segment, start, length, sha256_hex(&body));
+            offset = start + length;
+            complete += 1;
+        }
+        playlist.segments.truncate(complete);
+
+        Ok(Some(Self {
+            sequence: playlist.media_sequence + complete as u64,
+            last_time: playlist.segments.last().and_then(|x| x.program_date_time),
+            offset: if single_file { offset } else { 0 },
+            timeline,
+            playlist,
+        }))
+    }
+}
+
+/// Reported by `download_media` after each segment is written.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    /// The index of the written segment, counting from 0
+    pub index: usize,
+    /// The total number of bytes written so far
+    pub bytes: u64,
+    /// The duration of the written segment, in seconds
+    pub duration: f32,
+}
+
+/// Detects the segments of ads Twitch stitches into the stream.
+///
+/// Ad segments are titled `Amazon`, and the ad break is announced with an `EXT-X-DATERANGE`
+/// of class `twitch-stitched-ad`, or with SCTE-35 attributes; segments starting inside that range are ads too.
+#[derive(Default)]
+struct AdFilter {
+    /// The end of the ad break in progress.
+    until: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
+
+impl AdFilter {
+    fn is_ad_range(range: &DateRange) -> bool {
+        let attr = |map: &Option<std::collections::HashMap<String, m3u8_rs::QuotedOrUnquoted>>, prefix: &str| {
+            map.as_ref().is_some_and(|x| x.keys().any(|k| k.starts_with(prefix)))
+        };
+
+        range.class.as_deref() == Some("twitch-stitched-ad")
+            || range.id.starts_with("stitched-ad")
+            || attr(&range.x_prefixed, "X-TV-TWITCH-AD")
+            || attr(&range.other_attributes, "SCTE35-OUT")
+    }
+
+    fn is_ad(&mut self, segment: &MediaSegment) -> bool {
+        if let Some(range) = segment.daterange.as_ref().filter(|x| Self::is_ad_range(x)) {
+            let length = range.duration.or(range.planned_duration);
+            self.until = range.end_date.or_else(|| {
+                let ms = (length? * 1000.0) as i64;
+                Some(range.start_date + chrono::Duration::milliseconds(ms))
+            });
+        }
+
+        if segment.title.as_deref().is_some_and(|x| x.starts_with("Amazon")) {
+            return true;
+        }
+
+        match (self.until, segment.program_date_time) {
+            (Some(until), Some(time)) if time < until => true,
+            (Some(_), _) => {
+                self.until = None;
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A downloaded segment, either already written to its own file or held until it can be appended.
+enum SegmentData {
+    Written(u64),
+    Buffered(Vec<u8>),
+}
+
 pub async fn download_media(
     uri: impl AsRef<str>,
     dest: &path::Path,
     stream_name: &str,
-) -> Result<path::PathBuf> {
+    opts: &DownloadOptions,
+    stop: oneshot::Receiver<()>,
+    mut progress: Option<mpsc::Sender<Progress>>,
+) -> Result<path::PathBuf, HlsError> {
     let uri: Arc<Url> = Arc::new(uri.as_ref().parse()?);
 
     let mediapath = dest.join(format!("{stream_name}.m3u8"));
+    let single_file = opts.single_file;
+
+    // a playlist left behind by an interrupted download is continued, and rewritten up to its last complete segment
+    let resume = Resume::read(&mediapath, dest, single_file).await?;
+    if let Some(x) = &resume {
+        log::info!(
+            "resuming download of {stream_name} after {} segments, from media sequence {}",
+            x.playlist.segments.len(),
+            x.sequence
+        );
+    }
+    let resumed = resume.is_some();
+
     let mediafile = fs::OpenOptions::new()
-        .create_new(true)
+        .create_new(!resumed)
+        .create(resumed)
+        .truncate(resumed)
         .write(true)
         .open(&mediapath)
         .await
-        .context("failed to create media playlist file")?;
-    
-    let segdest = dest.join(stream_name);
-    fs::create_dir_all(&segdest)
-        .await
-        .context("failed to create segment directory")?;
+        .map_err(io_err("failed to create media playlist file"))?;
+
+    let mut single = if single_file {
+        let singlepath = dest.join(format!("{stream_name}.ts"));
+        let file = match &resume {
+            Some(x) => {
+                // bytes past the last segment in the playlist were never referenced
+                let file = fs::OpenOptions::new()
+                    .write(true)
+                    .open(&singlepath)
+                    .await
+                    .map_err(io_err("failed to open media file"))?;
+                file.set_len(x.offset).await.map_err(io_err("failed to open media file"))?;
+                drop(file);
+                fs::OpenOptions::new().append(true).open(&singlepath).await
+            }
+            None => fs::OpenOptions::new().create_new(true).write(true).open(&singlepath).await,
+        }
+        .map_err(io_err("failed to create media file"))?;
+        Some(BufWriter::new(HashWriter::new(file)))
+    } else {
+        let segdest = dest.join(stream_name);
+        fs::create_dir_all(&segdest)
+            .await
+            .map_err(io_err("failed to create segment directory"))?;
+        None
+    };
 
-    let (mut mw, rx) = spawn_downloader((*uri).clone()).await?;
-    mw.init(mediafile).await?;
+    let from = resume.as_ref().map(|x| x.sequence);
+    let (mw, rx) = spawn_downloader((*uri).clone(), stop, from, opts.max_empty_polls).await?;
+    let mut mw = match &resume {
+        Some(x) => MediaPlaylistWriter::resume(x).map_err(io_err("failed to write media playlist"))?,
+        None => mw,
+    };
+    mw.init(HashWriter::new(mediafile)).await.map_err(io_err("failed to write media playlist"))?;
+    let checksums = opts.checksums.as_ref();
 
-    let mut segments = rx.skip_while(|s| 
-            future::ready( if let Some(x) = &s.title { x.starts_with("Amazon") } else { false } )
-        )
+    let (first, last_time, mut timeline) = match resume {
+        Some(x) => (x.playlist.segments.len(), x.last_time, x.timeline),
+        None => (0, None, Timeline::default()),
+    };
+
+    let skipped_ads = AtomicUsize::new(0);
+    let mut ads = AdFilter::default();
+    // the first segment after resuming does not continue the last written one
+    let mut after_ad = resumed;
+    let mut segments = rx
+        .filter_map(|(seq, mut s)| {
+            // segments after skipped ads have a larger media sequence than was resumed from
+            if let (Some(last), Some(time)) = (last_time, s.program_date_time) {
+                if time <= last {
+                    return future::ready(None);
+                }
+            }
+
+            if opts.skip_ads && ads.is_ad(&s) {
+                skipped_ads.fetch_add(1, Ordering::Relaxed);
+                after_ad = true;
+                return future::ready(None);
+            }
+
+            // players reset their decoders where the ad was cut out
+            s.discontinuity |= std::mem::take(&mut after_ad);
+            future::ready(Some((seq, s)))
+        })
         .enumerate()
-        .map(|(i, mut s)| {
+        .map(|(i, (seq, mut s))| {
+            let i = first + i;
             let uri = Arc::clone(&uri);
+            // the media sequence shows dropped segments in the file names themselves
+            let name = match opts.sequence_names {
+                true => format!("{seq:08}"),
+                false => format!("{i:05}"),
+            };
             async move {
+                let start = time::Instant::now();
+
+                if let Some(map) = &mut s.map {
+                    let uri = (*uri).join(&map.uri)?;
+                    let mut body = get_bytes(uri.clone(), &format!("request for init segment #{i}")).await?;
+                    if let Some(range) = map.byte_range.take() {
+                        let offset = range.offset.unwrap_or(0) as usize;
+                        body = body.get(offset..offset + range.length as usize)
+                            .ok_or(HlsError::SegmentIncomplete {
+                                index: i,
+                                received: body.len(),
+                                expected: offset + range.length as usize,
+                            })?
+                            .to_vec();
+                    }
+
+                    let ext = path::Path::new(uri.path())
+                        .extension()
+                        .and_then(|x| x.to_str())
+                        .unwrap_or("mp4");
+                    map.uri = format!("{stream_name}/init-{name}.{ext}");
+                    fs::create_dir_all(dest.join(stream_name))
+                        .await
+                        .map_err(io_err("failed to create segment directory"))?;
+                    if let Some(x) = checksums {
+                        x.insert_bytes(&dest.join(&map.uri), &body);
+                    }
+                    fs::write(dest.join(&map.uri), body)
+                        .await
+                        .map_err(io_err("failed to write init segment"))?;
+                }
+
                 let uri = (*uri).join(&s.uri)?;
-                let res = get(uri, &format!("request for media segment #{i}")).await?;
+                let body = get_segment(uri, i).await?;
+                let sha256 = sha256_hex(&body);
+
+                // segments are kept in memory until they can be appended in order
+                if single_file {
+                    return Ok((i, s, SegmentData::Buffered(body), sha256, start.elapsed()));
+                }
 
-                s.uri = format!("{stream_name}/{i:05}.ts");
+                s.uri = format!("{stream_name}/{name}.ts");
                 let path = dest.join(&s.uri);
+                // segments past the resumed playlist may have been written, but were never referenced
                 let mut file = fs::OpenOptions::new()
-                    .create_new(true)
+                    .create_new(!resumed)
+                    .create(resumed)
+                    .truncate(resumed)
                     .write(true)
                     .open(&path)
                     .await
-                    .context("failed to create segment file")?;
+                    .map_err(io_err("failed to create segment file"))?;
 
-                io::copy(res, &mut file)
+                file.write_all(&body)
                     .await
-                    .context("failed to write segment to file")?;
+                    .map_err(io_err("failed to write segment to file"))?;
+                let len = body.len() as u64;
 
-                file.sync_all().await.context("failed to flush segment")?;
+                file.sync_all().await.map_err(io_err("failed to flush segment"))?;
+                if let Some(x) = checksums {
+                    x.insert(&path, sha256.clone());
+                }
+                metrics::SEGMENTS_DOWNLOADED.inc();
+                metrics::BYTES_WRITTEN.add(len);
 
-                Result::<MediaSegment>::Ok(s)
+                Result::<(usize, MediaSegment, SegmentData, String, time::Duration), HlsError>::Ok((i, s, SegmentData::Written(len), sha256, start.elapsed()))
             }
-        })
-        .buffered(6);
+        });
 
-    while let Some(s) = segments.next().await {
-        mw.write_segment(s?).await?;
+    let mut concurrency = Concurrency {
+        width: opts.segment_concurrency.clamp(1, MAX_SEGMENT_CONCURRENCY),
+        adaptive: opts.adaptive_concurrency,
+    };
+    // segments complete out of order, and wait here until every earlier segment was written
+    let mut pending = FuturesUnordered::new();
+    let mut reorder = BTreeMap::new();
+    let mut received_all = false;
+    let mut received: usize = 0;
+    let mut last_received = time::Instant::now();
+    let mut written: usize = first;
+    let mut bytes: u64 = 0;
+    // the latest progress report, if it could not be sent; each report supersedes the previous ones
+    let mut unsent: Option<Progress> = None;
+    let mut flushed: usize = first;
+    let mut last_flush = time::Instant::now();
+
+    loop {
+        let fetch = !received_all
+            && pending.len() < concurrency.width
+            && reorder.len() < MAX_REORDER;
+        let next = if fetch {
+            let remaining = opts.segment_timeout.saturating_sub(last_received.elapsed());
+            let next = timeout(remaining, async {
+                if pending.is_empty() {
+                    return Either::Left(segments.next().await);
+                }
+
+                match future::select(segments.next(), pending.next()).await {
+                    Either::Left((x, _)) => Either::Left(x),
+                    Either::Right((x, _)) => Either::Right(x),
+                }
+            });
+
+            match next.await {
+                Ok(x) => x,
+                Err(_) => {
+                    log::warn!(
+                        "no segments received for {}s; finishing stream after {received} segments",
+                        opts.segment_timeout.as_secs()
+                    );
+                    received_all = true;
+                    continue;
+                }
+            }
+        } else {
+            Either::Right(pending.next().await)
+        };
+
+        match next {
+            Either::Left(Some(x)) => {
+                received += 1;
+                last_received = time::Instant::now();
+                pending.push(x);
+            }
+            Either::Left(None) => {
+                log::debug!("stream ended after {received} segments");
+                received_all = true;
+            }
+            Either::Right(Some(x)) => {
+                let (i, s, data, sha256, latency) = x?;
+                concurrency.update(latency, s.duration);
+                reorder.insert(i, (s, data, sha256));
+
+                // `written` is the index of the next segment in the playlist
+                while let Some((mut s, data, sha256)) = reorder.remove(&written) {
+                    let duration = s.duration;
+
+                    let len = match (data, &mut single) {
+                        (SegmentData::Buffered(body), Some(file)) => {
+                            file.write_all(&body)
+                                .await
+                                .map_err(io_err("failed to write segment to media file"))?;
+                            let len = body.len() as u64;
+                            metrics::SEGMENTS_DOWNLOADED.inc();
+                            metrics::BYTES_WRITTEN.add(len);
+
+                            s.uri = format!("{stream_name}.ts");
+                            timeline.push(&s, mw.offset(), len, sha256);
+                            mw.write_range(s, len).await.map_err(io_err("failed to write media playlist"))?;
+                            len
+                        }
+                        (SegmentData::Written(len), _) => {
+                            timeline.push(&s, 0, len, sha256);
+                            mw.write_segment(s).await.map_err(io_err("failed to write media playlist"))?;
+                            len
+                        }
+                        (SegmentData::Buffered(_), None) => unreachable!("segment buffered without a media file"),
+                    };
+
+                    bytes += len;
+                    if let Some(tx) = &mut progress {
+                        let p = Progress { index: written, bytes, duration };
+                        // never stall the download on a slow observer; it is sent the latest report once it catches up
+                        unsent = match tx.try_send(p) {
+                            Ok(()) => None,
+                            Err(e) if e.is_disconnected() => {
+                                progress = None;
+                                None
+                            }
+                            Err(_) => {
+                                if unsent.is_none() {
+                                    log::trace!("progress observer of {stream_name} is behind; coalescing reports");
+                                }
+                                Some(p)
+                            }
+                        };
+                    }
+                    written += 1;
+                }
+
+                if written > flushed
+                    && (written - flushed >= PLAYLIST_FLUSH_SEGMENTS || last_flush.elapsed() >= PLAYLIST_FLUSH_INTERVAL)
+                {
+                    // the media file goes first, so that the playlist never points past its end
+                    if let Some(file) = &mut single {
+                        file.flush().await.map_err(io_err("failed to flush media file"))?;
+                    }
+                    mw.flush().await.map_err(io_err("failed to flush media playlist"))?;
+                    flushed = written;
+                    last_flush = time::Instant::now();
+                }
+            }
+            Either::Right(None) => break,
+        }
+    }
+
+    if let Some(mut file) = single {
+        file.flush().await.map_err(io_err("failed to flush media file"))?;
+        file.get_ref().get_ref().sync_all().await.map_err(io_err("failed to flush media file"))?;
+        if let Some(x) = checksums {
+            let path = dest.join(format!("{stream_name}.ts"));
+            // only what was appended went through the hasher
+            let sha256 = match resumed {
+                true => hash_file(&path).await.map_err(io_err("failed to read media file"))?,
+                false => file.get_ref().sha256(),
+            };
+            x.insert(&path, sha256);
+        }
+    }
+    mw.finish().await.map_err(io_err("failed to write media playlist"))?;
+
+    if let (Some(tx), Some(p)) = (&mut progress, unsent) {
+        let _ = tx.send(p).await;
+    }
+
+    let skipped_ads = skipped_ads.load(Ordering::Relaxed);
+    if skipped_ads > 0 {
+        log::info!("skipped {skipped_ads} ad segment(s) of {stream_name}");
     }
 
-    mw.finish().await?;
+    let timeline_path = dest.join("timeline.json");
+    let timeline_sha256 = timeline
+        .write(&timeline_path)
+        .await
+        .map_err(io_err("failed to write timeline"))?;
+
+    if let Some(x) = checksums {
+        if let Some(w) = mw.get_ref() {
+            x.insert(&mediapath, w.sha256());
+        }
+        x.insert(&timeline_path, timeline_sha256);
+    }
 
     Ok(mediapath)
 }
 
-pub async fn download(
-    uri: impl AsRef<str>,
-    dest: &path::Path,
-    format: impl Iterator<Item = &str>
-) -> Result<Option<StreamData>> {
-    let master = {
-        let uri: Url = uri.as_ref().parse()?;
+/// Fetches and parses the master playlist at `uri`.
+pub async fn master_playlist(uri: impl AsRef<str>) -> Result<MasterPlaylist, HlsError> {
+    let uri: Url = uri.as_ref().parse()?;
 
-        let body = get_bytes(uri, "request for master playlist").await
-            .context("failed to recieve master playlist")?;
-
-        let (_, master) = m3u8_rs::parse_master_playlist(&body).map_err(|e| {
-            log::error!("malformed m3u8 hls master playlist: {e:?}");
-            anyhow!("malformed m3u8 hls master playlist")
-        })?;
+    let body = get_bytes(uri, "request for master playlist").await?;
 
-        master
-    };
+    let (_, master) = m3u8_rs::parse_master_playlist(&body).map_err(|e| {
+        log::error!("malformed m3u8 hls master playlist: {e:?}");
+        HlsError::MasterParse
+    })?;
 
-    let (format, alt) = {
-        let format: Vec<&str> = format.collect();
-        let Some((format, alt)) = format.iter()
-            .find_map(|&f| {
-                if f == "best" {
-                    master.alternatives.get(0)
-                } else {
-                    master.alternatives.iter()
-                        .find(|x| x.name.starts_with(f))
-                }.map(|x| (f, x))
-            }) else {
-            log::info!("no matching quality found: expected {format:?}, found {:?}", master.alternatives);
-            return Ok(None);
-        };
+    Ok(master)
+}
 
-        (format, alt)
-    };
+/// Lists each rendition of the master playlist, along with the `STREAM-INF` it is associated with.
+pub fn renditions(master: &MasterPlaylist) -> impl Iterator<Item = (&AlternativeMedia, Option<&VariantStream>)> {
+    master.alternatives.iter().map(|alt| (alt, variant_of(master, alt)))
+}
 
-    let var = master.variants.iter().find(|x| match &alt.media_type {
+/// Finds the `STREAM-INF` associated with a `MEDIA` tag.
+fn variant_of<'a>(master: &'a MasterPlaylist, alt: &AlternativeMedia) -> Option<&'a VariantStream> {
+    master.variants.iter().find(|x| match &alt.media_type {
         AlternativeMediaType::Video => x.video.as_ref() == Some(&alt.group_id),
         AlternativeMediaType::Audio => x.audio.as_ref() == Some(&alt.group_id),
         AlternativeMediaType::Subtitles => x.subtitles.as_ref() == Some(&alt.group_id),
         AlternativeMediaType::ClosedCaptions => false,
         AlternativeMediaType::Other(_) => false,
-    });
+    })
+}
+
+/// Selects a separate audio rendition, which has its own media playlist,
+/// preferring the default one.
+///
+/// Twitch lists its audio-only rendition as a video rendition instead, which this does not match.
+fn select_audio(master: &MasterPlaylist) -> Option<&AlternativeMedia> {
+    let audio = || master.alternatives.iter()
+        .filter(|x| x.media_type == AlternativeMediaType::Audio && x.uri.is_some());
+
+    audio().find(|x| x.default).or_else(|| audio().next())
+}
+
+/// Selects the rendition matching a format token.
+///
+/// Valid tokens are `best`, `worst`, `audio_only` (or `audio`), a resolution (`720p`),
+/// an upper bound on the resolution (`<=720p`), or a prefix of the rendition name.
+/// A resolution which matches no rendition height falls back to the name prefix.
+fn select<'a>(master: &'a MasterPlaylist, format: &str) -> Option<&'a AlternativeMedia> {
+    fn height(x: &str) -> Option<u64> {
+        x.strip_suffix('p')?.parse().ok()
+    }
+
+    let renditions = || master.alternatives.iter()
+        .filter_map(|alt| Some((alt, variant_of(master, alt)?)));
+    let video = || renditions()
+        .filter_map(|(alt, var)| Some((alt, var.resolution?.height, var.bandwidth)));
+
+    match format {
+        "best" => master.alternatives.get(0),
+        "worst" => video()
+            .min_by_key(|&(_, h, b)| (h, b))
+            .map(|(alt, _, _)| alt),
+        "audio" | "audio_only" => select_audio(master).or_else(|| renditions()
+            .find(|(alt, var)| alt.group_id == "audio_only" || var.resolution.is_none())
+            .map(|(alt, _)| alt)),
+        f => {
+            if let Some(max) = f.strip_prefix("<=").and_then(height) {
+                return video()
+                    .filter(|&(_, h, _)| h <= max)
+                    .max_by_key(|&(_, h, b)| (h, b))
+                    .map(|(alt, _, _)| alt);
+            }
 
-    let media_uri = if let Some(uri) = &alt.uri { uri } else {
-        let Some(var) = var else {
-            log::error!("could not find matching STREAM-INF for MEDIA tag :{}", dest.display());
-            return Err(anyhow!("url missing for format {}", format));
+            height(f)
+                .and_then(|target| video()
+                    .filter(|&(_, h, _)| h == target)
+                    .max_by_key(|&(_, _, b)| b)
+                    .map(|(alt, _, _)| alt))
+                .or_else(|| master.alternatives.iter().find(|x| x.name.starts_with(f)))
+        }
+    }
+}
+
+/// Downloads a rendition of the master playlist at `uri` for each entry of `formats`.
+///
+/// Each entry is a comma separated list of formats, tried in order.
+/// A single rendition is downloaded to `dest`, while multiple renditions are downloaded concurrently,
+/// each to a subdirectory of `dest` named after the rendition.
+pub async fn download(
+    uri: impl AsRef<str>,
+    dest: &path::Path,
+    formats: &[impl AsRef<str>],
+    opts: &DownloadOptions,
+    stop: oneshot::Receiver<()>,
+    progress: Option<mpsc::Sender<Progress>>,
+) -> Result<Vec<StreamData>, HlsError> {
+    let master = master_playlist(uri).await?;
+
+    let mut selected: Vec<(&str, &AlternativeMedia)> = Vec::new();
+    for entry in formats {
+        let entry: Vec<&str> = entry.as_ref().split(',').map(str::trim).collect();
+        let Some((format, alt)) = entry.iter()
+            .find_map(|&f| select(&master, f).map(|x| (f, x))) else {
+            log::info!("no matching quality found: expected {entry:?}, found {:?}", master.alternatives);
+            continue;
+        };
+
+        if selected.iter().any(|(_, x)| std::ptr::eq(*x, alt)) {
+            log::debug!("rendition {:?} for format {format:?} is already selected", alt.name);
+            continue;
+        }
+        selected.push((format, alt));
+    }
+
+    if selected.is_empty() {
+        return Err(HlsError::NoMatchingQuality {
+            formats: formats.iter().map(|x| x.as_ref().to_owned()).collect(),
+        });
+    }
+
+    let mut renditions = Vec::with_capacity(selected.len());
+    for (format, alt) in selected {
+        // a separate audio rendition is downloaded from its own playlist, not from a video variant using it
+        let var = match alt.media_type {
+            AlternativeMediaType::Audio if alt.uri.is_some() => None,
+            _ => variant_of(&master, alt),
         };
+        match var {
+            Some(v) => log::info!(
+                "selected rendition {:?} for format {format:?} ({}, {} bps)",
+                alt.name,
+                v.resolution.map_or("audio only".to_owned(), |r| format!("{}x{}", r.width, r.height)),
+                v.bandwidth
+            ),
+            None => log::info!("selected rendition {:?} for format {format:?}", alt.name),
+        }
+
+        let media_uri = if let Some(uri) = &alt.uri { uri } else {
+            let Some(var) = var else {
+                log::error!("could not find matching STREAM-INF for MEDIA tag :{}", dest.display());
+                return Err(HlsError::MissingUri { format: format.to_owned() });
+            };
 
-        &var.uri
+            &var.uri
+        };
+
+        renditions.push((media_uri, alt, var));
+    }
+
+    if let [(media_uri, alt, var)] = renditions[..] {
+        let mediapath = download_media(media_uri, dest, &alt.name, opts, stop, progress).await?;
+        return Ok(vec![(mediapath, alt.to_owned(), var.cloned())]);
+    }
+
+    // every rendition is stopped together
+    let (mut txs, rxs): (Vec<_>, Vec<_>) = renditions.iter().map(|_| oneshot::channel()).unzip();
+    let relay = async move {
+        // the sender being dropped without signaling does not stop the stream
+        if stop.await.is_ok() {
+            for tx in txs.drain(..) {
+                let _ = tx.send(());
+            }
+        }
+        future::pending::<core::convert::Infallible>().await
     };
 
-    let mediapath = download_media(media_uri, dest, &alt.name).await?;
+    // progress is only reported for the first rendition, so that the indices stay sequential
+    let mut progress = progress;
+    let downloads = renditions.into_iter().zip(rxs).map(|((media_uri, alt, var), stop)| {
+        let progress = progress.take();
+        let download = async move {
+            let dest = dest.join(sanitize_filename::sanitize(&alt.name));
+            fs::create_dir_all(&dest)
+                .await
+                .map_err(io_err("failed to create rendition directory"))?;
 
-    Ok(Some((mediapath, alt.to_owned(), var.cloned())))
+            let mediapath = download_media(media_uri, &dest, &alt.name, opts, stop, progress).await?;
+            Ok((mediapath, alt.to_owned(), var.cloned()))
+        };
+        download.map(|x: Result<StreamData, HlsError>| (alt.name.clone(), x))
+    });
+
+    let res = match future::select(Box::pin(future::join_all(downloads)), Box::pin(relay)).await {
+        Either::Left((x, _)) => x,
+        Either::Right((x, _)) => match x {},
+    };
+
+    // a failed rendition fails the download, after the others have finished, without discarding them
+    let mut completed = Vec::new();
+    let mut failed = Vec::new();
+    for (name, x) in res {
+        match x {
+            Ok(x) => completed.push(x),
+            Err(e) => {
+                log::error!("rendition {name:?} failed: {e}");
+                failed.push((name, e));
+            }
+        }
+    }
+    match failed.is_empty() {
+        true => Ok(completed),
+        false => Err(HlsError::RenditionsFailed { completed, failed }),
+    }
 }
@@ -1,34 +1,42 @@
-use async_std::channel;
+use async_std::{channel, sync::Mutex};
 use std::{
     collections::HashMap,
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
 };
 use twitchchat::AsyncRunner;
 
-use crate::prelude::*;
+use crate::{metrics, prelude::*};
 
 const CHANNEL_BOUND: usize = 16;
+/// Dropped messages are logged once, then every this many messages.
+const DROP_LOG_INTERVAL: usize = 1000;
+const RECONNECT_DELAY: core::time::Duration = core::time::Duration::from_secs(10);
+/// The delay between reconnection attempts doubles after each failure, up to this value.
+const MAX_RECONNECT_DELAY: core::time::Duration = core::time::Duration::from_secs(600);
 
 macro_rules! try_send {
     ($map:expr, $msg:expr) => {
-        if let Some(tx) = $map.get($msg.channel()) {
-            if let Err(e) = tx.try_send($msg.raw().into()) {
-                log::warn!(
-                    "failed to send IRC to matching handler {}: {e:?}",
-                    $msg.channel()
-                );
-            }
-        } else {
-            log::warn!(
-                "received IRC message for unknown channel {}",
-                $msg.channel()
-            );
-        }
+        try_send!($map, $msg.channel(), $msg.raw())
     };
     ($map:expr, $chname:expr, $msg:expr) => {
         if let Some(tx) = $map.get($chname) {
-            if let Err(e) = tx.try_send($msg.into()) {
-                log::warn!("failed to send IRC to matching handler {}: {e:?}", $chname);
+            match tx.try_send(IrcMessage::from($msg)) {
+                Ok(_) => (),
+                Err(channel::TrySendError::Full(_)) => {
+                    let n = tx.dropped();
+                    if n % DROP_LOG_INTERVAL == 1 {
+                        log::warn!(
+                            "chat log for {} is falling behind; {n} message(s) dropped",
+                            $chname
+                        );
+                    }
+                }
+                Err(e) => {
+                    log::warn!("failed to send IRC to matching handler {}: {e:?}", $chname);
+                }
             }
         } else {
             log::warn!("received IRC message for unknown channel {}", $chname);
@@ -36,53 +44,81 @@ macro_rules! try_send {
     };
 }
 
+/// What is done with chat messages received while a channel's chat log is behind.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Overflow {
+    /// Messages are dropped once 16 messages are waiting; dropped messages are counted.
+    #[default]
+    Drop,
+    /// Messages are buffered in memory without limit.
+    Grow,
+}
+
 pub struct IrcClientBuilder {
-    map: HashMap<Box<str>, IrcSend>,
+    login: Option<(Box<str>, Box<str>)>,
+    overflow: Overflow,
 }
 
 impl IrcClientBuilder {
     pub fn new() -> Self {
         Self {
-            map: HashMap::new(),
+            login: None,
+            overflow: Overflow::Drop,
         }
     }
 
-    //TODO: accepting channels at creation means that joining afterwords is impossible;
-    // Change to a custom TLS stream impl to handle this!
-    pub fn join(&mut self, channel: &str) -> IrcRecv {
-        let (tx, rx) = channel::bounded(CHANNEL_BOUND);
-        let is_open = Arc::new(AtomicBool::new(false));
-        self.map.insert(
-            format!("#{}", channel).into(),
-            IrcSend {
-                tx,
-                is_open: is_open.clone(),
-            },
-        );
-        IrcRecv { rx, is_open }
+    pub fn overflow(&mut self, overflow: Overflow) -> &mut Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Authenticates as the given user instead of connecting anonymously.
+    /// The token is a chat OAuth token, with or without the `oauth:` prefix.
+    pub fn login(&mut self, nick: &str, token: &str) -> &mut Self {
+        let token = format!("oauth:{}", token.trim_start_matches("oauth:"));
+        self.login = Some((nick.to_lowercase().into(), token.into()));
+        self
     }
 
-    pub fn build(self) {
+    /// Spawns the IRC handler, returning a handle to join and part channels with.
+    pub fn build(self) -> IrcClient {
         use async_std::task;
 
+        let (ctl_tx, ctl_rx) = channel::unbounded();
+        let overflow = self.overflow;
+
         log::debug!("spawning IRC handler");
         task::Builder::new()
             .name("irc".to_owned())
             .spawn(async move {
-                use core::time::Duration;
-                use twitchchat::{messages::Commands, Status};
+                use twitchchat::{
+                    messages::{Commands, HostTargetKind},
+                    Status,
+                };
 
-                async fn _connect() -> Result<AsyncRunner, twitchchat::runner::Error> {
+                async fn _connect(
+                    login: &Option<(Box<str>, Box<str>)>,
+                ) -> Result<AsyncRunner, twitchchat::runner::Error> {
                     use twitchchat::{
                         connector::async_std::ConnectorTls, twitch::Capability, UserConfig,
                     };
 
                     let conn = ConnectorTls::twitch()?;
-                    let config = UserConfig::builder()
-                        .anonymous()
-                        .capabilities(&[Capability::Tags])
-                        .build()
-                        .unwrap();
+                    let config = match login {
+                        Some((nick, token)) => UserConfig::builder()
+                            .name(nick)
+                            .token(token)
+                            .capabilities(&[
+                                Capability::Membership,
+                                Capability::Tags,
+                                Capability::Commands,
+                            ]),
+                        None => UserConfig::builder()
+                            .anonymous()
+                            .capabilities(&[Capability::Tags]),
+                    }
+                    .build()
+                    .unwrap();
 
                     let runner = AsyncRunner::connect(conn, &config).await?;
                     log::info!("connected to the IRC server");
@@ -91,12 +127,50 @@ impl IrcClientBuilder {
                     Ok(runner)
                 }
 
+                async fn _control(
+                    runner: &mut AsyncRunner,
+                    map: &mut HashMap<Box<str>, IrcSend>,
+                    ctl: Control,
+                ) -> Result<(), twitchchat::runner::Error> {
+                    match ctl {
+                        Control::Join(channel, tx) => {
+                            map.insert(format!("#{channel}").into(), tx);
+                            runner.join(&channel).await
+                        }
+                        Control::Part(channel) => {
+                            map.remove(&*format!("#{channel}"));
+                            runner.part(&channel).await
+                        }
+                    }
+                }
+
                 async fn _handle(
                     mut runner: AsyncRunner,
-                    map: &HashMap<Box<str>, IrcSend>,
+                    map: &mut HashMap<Box<str>, IrcSend>,
+                    ctl: &channel::Receiver<Control>,
                 ) -> Result<(), twitchchat::runner::Error> {
+                    use futures::future::{select, Either};
+
                     loop {
-                        let msg = runner.next_message().await?;
+                        // once every handle has been dropped, no channels can be joined anymore,
+                        // but the ones already joined are still served.
+                        let next = if ctl.is_closed() && ctl.is_empty() {
+                            Either::Left(runner.next_message().await)
+                        } else {
+                            match select(Box::pin(runner.next_message()), ctl.recv()).await {
+                                Either::Left((msg, _)) => Either::Left(msg),
+                                Either::Right((x, _)) => Either::Right(x),
+                            }
+                        };
+
+                        let msg = match next {
+                            Either::Left(msg) => msg?,
+                            Either::Right(Ok(x)) => {
+                                _control(&mut runner, map, x).await?;
+                                continue;
+                            }
+                            Either::Right(Err(_)) => continue,
+                        };
 
                         // I could probably make a macro for this... but I'm laaaaazy :P
                         match msg {
@@ -110,25 +184,44 @@ impl IrcClientBuilder {
                             }
                             Status::Message(Commands::ClearChat(x)) => try_send!(map, x),
                             Status::Message(Commands::ClearMsg(x)) => try_send!(map, x),
-                            Status::Message(Commands::HostTarget(x)) => try_send!(map, x.source(), x.raw()),
+                            Status::Message(Commands::HostTarget(x)) => {
+                                match x.host_target_kind() {
+                                    HostTargetKind::Start { target } => log::debug!(
+                                        "{} started hosting {target} ({} viewers)",
+                                        x.source(),
+                                        x.viewers().unwrap_or_default()
+                                    ),
+                                    HostTargetKind::End => {
+                                        log::debug!("{} stopped hosting", x.source())
+                                    }
+                                }
+                                try_send!(map, x.source(), x.raw())
+                            }
                             Status::Message(Commands::Join(x)) => try_send!(map, x),
                             Status::Message(Commands::Notice(x)) => try_send!(map, x),
                             Status::Message(Commands::Part(x)) => try_send!(map, x),
-                            Status::Message(Commands::Privmsg(x)) => try_send!(map, x),
+                            Status::Message(Commands::Privmsg(x)) => {
+                                try_send!(map, x.channel(), IrcMessage::privmsg(&x))
+                            }
                             Status::Message(Commands::RoomState(x)) => try_send!(map, x),
-                            Status::Message(Commands::UserNotice(x)) => try_send!(map, x),
+                            Status::Message(Commands::UserNotice(x)) => {
+                                try_send!(map, x.channel(), IrcMessage::usernotice(&x))
+                            }
                             Status::Message(Commands::UserState(x)) => try_send!(map, x),
                             Status::Message(x) => log::trace!("received IRC command: {x:?}"),
                         };
                     }
                 }
 
-                let map = self.map;
-                let mut try_count: u8 = 0;
-                while try_count <= 10 {
-                    match _connect().await {
+                let mut map: HashMap<Box<str>, IrcSend> = HashMap::new();
+                let login = self.login;
+                let mut delay = RECONNECT_DELAY;
+                // chat is never given up on; downloads continue without chat while disconnected
+                loop {
+                    match _connect(&login).await {
                         Ok(mut runner) => {
-                            try_count = 0;
+                            delay = RECONNECT_DELAY;
+                            let _connected = metrics::IRC_CONNECTED.track();
 
                             for channel in map.keys() {
                                 if let Err(e) = runner.join(&(**channel)[1..]).await {
@@ -138,76 +231,311 @@ impl IrcClientBuilder {
 
                             log::trace!("irc map: {map:?}");
 
-                            if let Err(e) = _handle(runner, &map).await {
+                            if let Err(e) = _handle(runner, &mut map, &ctl_rx).await {
                                 log::error!("error while listening to irc: {e:?}");
                             }
                         }
                         Err(e) => {
-                            if try_count < 10 {
-                                log::warn!("cannot connect to irc; retrying ({try_count}): {e}");
-                            } else {
-                                log::error!("cannot connect to irc; aborting: {e}");
-                                std::process::exit(1);
-                            }
+                            log::error!(
+                                "cannot connect to irc; retrying in {}s: {e}",
+                                delay.as_secs()
+                            );
                         }
                     }
 
-                    async_std::task::sleep(Duration::from_secs(10)).await;
-                    try_count += 1;
-                    continue;
+                    async_std::task::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
                 }
             })
             .expect("cannot spawn task");
+
+        IrcClient {
+            ctl: ctl_tx,
+            overflow,
+            joined: Default::default(),
+        }
     }
 }
 
+enum Control {
+    Join(Box<str>, IrcSend),
+    Part(Box<str>),
+}
+
+/// A handle to the running IRC handler.
+#[derive(Clone)]
+pub struct IrcClient {
+    ctl: channel::Sender<Control>,
+    overflow: Overflow,
+    joined: Arc<Mutex<Joined>>,
+}
+
+/// The receivers of the joined channels, along with the number of joins not yet parted.
+type Joined = HashMap<Box<str>, (IrcRecv, usize)>;
+
+impl IrcClient {
+    /// Joins the chat of the given channel, returning the receiving end of its messages.
+    /// Joining a channel again returns the same receiver;
+    /// the chat is only parted once every join has been parted.
+    pub async fn join(&self, channel: &str) -> Result<IrcRecv> {
+        let channel = channel.to_lowercase();
+        let mut joined = self.joined.lock().await;
+        if let Some((rx, count)) = joined.get_mut(&*channel) {
+            *count += 1;
+            return Ok(rx.clone());
+        }
+
+        let (tx, rx) = match self.overflow {
+            Overflow::Drop => channel::bounded(CHANNEL_BOUND),
+            Overflow::Grow => channel::unbounded(),
+        };
+        let is_open = Arc::new(AtomicBool::new(false));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let send = IrcSend {
+            tx,
+            is_open: is_open.clone(),
+            dropped: dropped.clone(),
+        };
+
+        self.ctl
+            .send(Control::Join(channel.as_str().into(), send))
+            .await
+            .map_err(|_| anyhow!("IRC handler has stopped"))?;
+
+        let rx = IrcRecv {
+            rx,
+            is_open,
+            dropped,
+        };
+        joined.insert(channel.into(), (rx.clone(), 1));
+        Ok(rx)
+    }
+
+    /// Parts the chat of the given channel once for every time it was joined,
+    /// closing its receiver after the last one.
+    pub async fn part(&self, channel: &str) -> Result<()> {
+        let channel = channel.to_lowercase();
+        let mut joined = self.joined.lock().await;
+        match joined.get_mut(&*channel) {
+            Some((_, count)) if *count > 1 => {
+                *count -= 1;
+                return Ok(());
+            }
+            Some(_) => {
+                joined.remove(&*channel);
+            }
+            None => (),
+        }
+
+        self.ctl
+            .send(Control::Part(channel.into()))
+            .await
+            .map_err(|_| anyhow!("IRC handler has stopped"))
+    }
+}
+
+/// The `msg-id`s of the `USERNOTICE`s recorded as events.
+const EVENT_IDS: &[&str] = &[
+    "sub",
+    "resub",
+    "subgift",
+    "submysterygift",
+    "anonsubgift",
+    "anonsubmysterygift",
+    "giftpaidupgrade",
+    "anongiftpaidupgrade",
+    "primepaidupgrade",
+    "raid",
+];
+
+/// A message forwarded to the handler of a channel.
+#[derive(Clone, Debug)]
+pub struct IrcMessage {
+    /// The raw IRC line.
+    pub raw: Box<str>,
+    /// The parsed chat message, if this is a `PRIVMSG`.
+    pub chat: Option<ChatMessage>,
+    /// The parsed event, if this is a cheer, subscription or raid.
+    pub event: Option<ChatEvent>,
+}
+
+impl IrcMessage {
+    fn privmsg(msg: &twitchchat::messages::Privmsg<'_>) -> Self {
+        let tags = msg.tags();
+        let ts = msg
+            .tmi_sent_ts()
+            .unwrap_or_else(|| chrono::Utc::now().timestamp_millis() as u64);
+
+        let event = msg.bits().filter(|x| *x > 0).map(|bits| ChatEvent {
+            ts,
+            kind: "cheer".into(),
+            user: Some(msg.name().into()),
+            display_name: msg.display_name().map(Into::into),
+            bits: Some(bits),
+            text: Some(msg.data().into()),
+            ..ChatEvent::default()
+        });
+
+        Self {
+            raw: msg.raw().into(),
+            event,
+            chat: Some(ChatMessage {
+                ts,
+                user: msg.name().into(),
+                display_name: msg.display_name().map(Into::into),
+                color: tags.get("color").filter(|x| !x.is_empty()).map(Into::into),
+                badges: tags
+                    .get("badges")
+                    .map(|x| x.split(',').filter(|x| !x.is_empty()).map(Into::into).collect())
+                    .unwrap_or_default(),
+                text: msg.data().into(),
+            }),
+        }
+    }
+
+    fn usernotice(msg: &twitchchat::messages::UserNotice<'_>) -> Self {
+        let tags = msg.tags();
+        let kind = tags.get("msg-id").filter(|x| EVENT_IDS.contains(x));
+        let num = |key: &str| tags.get(key).and_then(|x| x.parse().ok());
+        let string = |key: &str| tags.get(key).filter(|x| !x.is_empty()).map(Into::into);
+
+        let event = kind.map(|kind| ChatEvent {
+            ts: num("tmi-sent-ts").unwrap_or_else(|| chrono::Utc::now().timestamp_millis() as u64),
+            kind: kind.into(),
+            user: msg.login().map(Into::into),
+            display_name: msg.display_name().map(Into::into),
+            months: num("msg-param-cumulative-months").or_else(|| num("msg-param-months")),
+            tier: string("msg-param-sub-plan"),
+            recipient: string("msg-param-recipient-user-name"),
+            gifts: num("msg-param-mass-gift-count"),
+            viewers: num("msg-param-viewerCount"),
+            bits: None,
+            text: msg.message().map(Into::into),
+        });
+
+        Self {
+            raw: msg.raw().into(),
+            chat: None,
+            event,
+        }
+    }
+}
+
+impl From<&str> for IrcMessage {
+    fn from(raw: &str) -> Self {
+        Self {
+            raw: raw.into(),
+            chat: None,
+            event: None,
+        }
+    }
+}
+
+/// A cheer, subscription or raid, as written to `events.jsonl`.
+/// Fields which do not apply to the event are omitted.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChatEvent {
+    /// The time the event was sent, in milliseconds since the unix epoch.
+    pub ts: u64,
+    /// `cheer`, or the `msg-id` of the `USERNOTICE`; such as `sub`, `resub`, `subgift` or `raid`.
+    #[serde(rename = "type")]
+    pub kind: Box<str>,
+    pub user: Option<Box<str>>,
+    pub display_name: Option<Box<str>>,
+    /// The cumulative months subscribed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub months: Option<u64>,
+    /// The subscription plan; `Prime`, `1000`, `2000` or `3000`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tier: Option<Box<str>>,
+    /// The login of the user receiving a gifted subscription.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<Box<str>>,
+    /// The number of subscriptions gifted at once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gifts: Option<u64>,
+    /// The number of viewers brought by a raid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viewers: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bits: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<Box<str>>,
+}
+
+/// A chat message, as written to the JSON chat log.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChatMessage {
+    /// The time the message was sent, in milliseconds since the unix epoch.
+    pub ts: u64,
+    pub user: Box<str>,
+    pub display_name: Option<Box<str>>,
+    pub color: Option<Box<str>>,
+    /// The badges of the user, as `name/version`.
+    pub badges: Vec<Box<str>>,
+    pub text: Box<str>,
+}
+
 #[derive(Clone, Debug)]
 pub struct IrcRecv {
-    rx: channel::Receiver<Box<str>>,
+    rx: channel::Receiver<IrcMessage>,
     is_open: Arc<AtomicBool>,
+    dropped: Arc<AtomicUsize>,
 }
 
 impl IrcRecv {
     pub fn open(&self) -> bool {
-        use std::sync::atomic::Ordering;
-
         self.is_open
             .compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed)
             .is_ok()
     }
 
     pub fn close(&self) -> bool {
-        use std::sync::atomic::Ordering;
-
         self.is_open
             .compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed)
             .is_ok()
     }
 
-    pub fn recv(&self) -> async_std::channel::Recv<'_, Box<str>> {
+    pub fn recv(&self) -> async_std::channel::Recv<'_, IrcMessage> {
         self.rx.recv()
     }
+
+    /// The number of messages dropped for this channel since it was joined.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct IrcSend {
-    tx: channel::Sender<Box<str>>,
+    tx: channel::Sender<IrcMessage>,
     is_open: Arc<AtomicBool>,
+    dropped: Arc<AtomicUsize>,
 }
 
 impl IrcSend {
     pub fn is_open(&self) -> bool {
-        use std::sync::atomic::Ordering;
-
         self.is_open.load(Ordering::Relaxed)
     }
 
-    pub fn try_send(&self, msg: Box<str>) -> Result<bool, channel::TrySendError<Box<str>>> {
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Sends the message if the receiver is open.
+    /// A message that does not fit in the channel is dropped, and counted.
+    pub fn try_send(&self, msg: IrcMessage) -> Result<bool, channel::TrySendError<IrcMessage>> {
         if !self.is_open() {
             return Ok(false);
         }
 
-        self.tx.try_send(msg)?;
+        if let Err(e) = self.tx.try_send(msg) {
+            if e.is_full() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                metrics::CHAT_DROPPED.inc();
+            }
+            return Err(e);
+        }
         Ok(true)
     }
 }
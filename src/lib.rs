@@ -0,0 +1,28 @@
+pub mod api;
+pub mod archiver;
+pub mod checksum;
+pub mod eventsub;
+pub mod events;
+pub mod filename;
+mod fs_utils;
+pub mod helix;
+pub mod hls;
+pub mod irc;
+mod live;
+mod lock;
+pub mod logger;
+pub mod metrics;
+pub mod net;
+pub mod notify;
+pub mod prelude;
+mod rand;
+pub mod recover;
+mod retry;
+pub mod s3;
+//mod tar;
+
+pub use archiver::{Archiver, ArchiverSettings, Source, StreamUpdate};
+pub use eventsub::{EventSub, Subscription};
+pub use filename::Formatter;
+pub use helix::{HelixAuth, Stream, User};
+pub use hls::{download, HlsError};
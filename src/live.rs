@@ -1,8 +1,15 @@
 use rand::Rng;
 
-use crate::prelude::*;
+use crate::{api, net, prelude::*};
 
-async fn send_req(login: &str, auth: Option<&str>) -> surf::Result<surf::Response> {
+/// The playback a token is requested for.
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    Live { login: &'a str },
+    Vod { id: &'a str },
+}
+
+async fn send_req(target: Target<'_>, auth: Option<&str>) -> surf::Result<surf::Response> {
     #[derive(Serialize)]
     #[serde(rename_all = "camelCase")]
     struct Req<'a> {
@@ -18,7 +25,7 @@ async fn send_req(login: &str, auth: Option<&str>) -> surf::Result<surf::Respons
         login: &'a str,
         is_vod: bool,
         #[serde(rename = "vodID")]
-        vod_id: &'static str,
+        vod_id: &'a str,
         player_type: &'static str,
     }
 
@@ -43,16 +50,25 @@ async fn send_req(login: &str, auth: Option<&str>) -> surf::Result<surf::Respons
                 hash: "0828119ded1c13477966434e15800ff57ddacf13ba1911c129dc2200705b0712"
             }
         },
-        variables: ReqVar {
-            is_live: true,
-            login,
-            is_vod: false,
-            vod_id: "",
-            player_type: "embed"
+        variables: match target {
+            Target::Live { login } => ReqVar {
+                is_live: true,
+                login,
+                is_vod: false,
+                vod_id: "",
+                player_type: "embed"
+            },
+            Target::Vod { id } => ReqVar {
+                is_live: false,
+                login: "",
+                is_vod: true,
+                vod_id: id,
+                player_type: "embed"
+            },
         }
     };
 
-    let mut req = surf::post("https://gql.twitch.tv/gql")
+    let mut req = net::client().post(api::gql())
         .header("Client-ID", "kimne78kx3ncx6brgo4mv6wki5h1ko")
         .header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/86.0.4240.111 Safari/537.36");
 
@@ -65,7 +81,7 @@ async fn send_req(login: &str, auth: Option<&str>) -> surf::Result<surf::Respons
         .await
 }
 
-async fn parse_res(login: &str, mut res: surf::Response) -> surf::Result<Option<String>> {
+async fn parse_res(target: Target<'_>, mut res: surf::Response) -> surf::Result<Option<String>> {
     #[derive(Deserialize)]
     struct Res {
         data: ResData
@@ -73,8 +89,10 @@ async fn parse_res(login: &str, mut res: surf::Response) -> surf::Result<Option<
 
     #[derive(Deserialize)]
     struct ResData {
-        #[serde(rename = "streamPlaybackAccessToken")]
-        token: Option<Token>
+        #[serde(rename = "streamPlaybackAccessToken", default)]
+        stream: Option<Token>,
+        #[serde(rename = "videoPlaybackAccessToken", default)]
+        video: Option<Token>,
     }
 
     #[derive(Deserialize)]
@@ -85,18 +103,22 @@ async fn parse_res(login: &str, mut res: surf::Response) -> surf::Result<Option<
 
     let res: Res = res.body_json().await?;
 
-    if let Some(token) = res.data.token {
-        Ok(Some(format!(
-            "http://usher.ttvnw.net/api/channel/hls/{}.m3u8?player=twitchweb&&token={}&sig={}&allow_audio_only=true&allow_source=true&type=any&p={}",
-            login, token.value, token.signature, rand::thread_rng().gen_range(0..=999999)
-        )))
-    } else { Ok(None) }
-    
+    let p = rand::thread_rng().gen_range(0..=999999);
+    Ok(match (target, res.data.stream, res.data.video) {
+        (Target::Live { login }, Some(token), _) => Some(format!(
+            "{}?player=twitchweb&&token={}&sig={}&allow_audio_only=true&allow_source=true&type=any&p={}",
+            api::usher(&format!("api/channel/hls/{login}.m3u8")), token.value, token.signature, p
+        )),
+        (Target::Vod { id }, _, Some(token)) => Some(format!(
+            "{}?player=twitchweb&&nauth={}&nauthsig={}&allow_audio_only=true&allow_source=true&type=any&p={}",
+            api::usher(&format!("vod/{id}.m3u8")), token.value, token.signature, p
+        )),
+        _ => None,
+    })
 }
 
-pub async fn get_hls(login: impl AsRef<str>, auth: Option<&str>) -> anyhow::Result<Option<String>> {
-    let login = login.as_ref();
-    let mut res = send_req(login, auth).await
+async fn fetch(target: Target<'_>, auth: Option<&str>) -> anyhow::Result<Option<String>> {
+    let mut res = send_req(target, auth).await
         .map_err(surf::Error::into_inner)?;
 
     if !res.status().is_success() {
@@ -104,6 +126,52 @@ pub async fn get_hls(login: impl AsRef<str>, auth: Option<&str>) -> anyhow::Resu
         return Ok(None);
     }
 
-    parse_res(login, res).await
+    parse_res(target, res).await
+        .map_err(surf::Error::into_inner)
+}
+
+/// Fetches the master playlist url for the live stream of `login`, and checks that it can be requested.
+/// Unlike [`get_hls`], the reason the url could not be obtained is returned as the error.
+pub async fn check_hls(login: &str, auth: Option<&str>) -> anyhow::Result<String> {
+    use anyhow::Context;
+
+    let target = Target::Live { login };
+    let mut res = send_req(target, auth).await
+        .map_err(surf::Error::into_inner)
+        .context("playback token request failed")?;
+
+    if !res.status().is_success() {
+        return Err(anyhow!(
+            "playback token request returned status {}: {}",
+            res.status(),
+            res.body_string().await.unwrap_or_default()
+        ));
+    }
+
+    let url = parse_res(target, res).await
         .map_err(surf::Error::into_inner)
+        .context("playback token response is invalid")?
+        .ok_or_else(|| anyhow!("no playback token was returned; the channel may not exist"))?;
+
+    let res = net::client().get(&url).await
+        .map_err(surf::Error::into_inner)
+        .context("master playlist request failed")?;
+
+    match res.status() {
+        x if x.is_success() => Ok(url),
+        surf::StatusCode::NotFound => Err(anyhow!("master playlist was not found; the channel is offline")),
+        surf::StatusCode::Forbidden => Err(anyhow!(
+            "access to the master playlist was denied; the stream may be restricted"
+        )),
+        x => Err(anyhow!("master playlist request returned status {x}")),
+    }
+}
+
+pub async fn get_hls(login: impl AsRef<str>, auth: Option<&str>) -> anyhow::Result<Option<String>> {
+    fetch(Target::Live { login: login.as_ref() }, auth).await
+}
+
+/// Fetches the master playlist url for the VOD with the given video id.
+pub async fn get_vod_hls(id: impl AsRef<str>, auth: Option<&str>) -> anyhow::Result<Option<String>> {
+    fetch(Target::Vod { id: id.as_ref() }, auth).await
 }
@@ -0,0 +1,204 @@
+use async_std::{fs, io::{self, ReadExt, WriteExt}, path};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use std::{collections::HashSet, sync::{atomic::{AtomicUsize, Ordering}, Mutex}};
+
+use crate::{fs_utils, prelude::*};
+
+/// Locks older than this are considered stale, regardless of the owning process.
+///
+/// The owner of a lock on another host cannot be checked for liveness,
+/// so this is set well above the length of any stream, to only reclaim locks left by crashed instances.
+const STALE_LOCK_AGE: Duration = Duration::hours(48);
+
+/// Lock paths held or being acquired by this process.
+///
+/// A lock file naming this process is only live if its path is in here;
+/// otherwise it was left by an earlier process which happened to have the same pid.
+static HELD: Lazy<Mutex<HashSet<path::PathBuf>>> = Lazy::new(Default::default);
+
+/// How many times acquiring a lock is retried when it changes under us.
+const ACQUIRE_ATTEMPTS: usize = 4;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Owner {
+    pid: u32,
+    hostname: String,
+    created_at: DateTime<Utc>,
+}
+
+impl Owner {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            hostname: hostname(),
+            created_at: Utc::now(),
+        }
+    }
+
+    async fn is_stale(&self) -> bool {
+        if Utc::now() - self.created_at > STALE_LOCK_AGE {
+            return true;
+        }
+
+        // the liveness of a process can only be checked on the same host
+        if self.hostname != hostname() {
+            return false;
+        }
+
+        // locks held by this process are claimed in `HELD` before their file is ever read,
+        // so a file naming our pid cannot belong to us
+        self.pid == std::process::id()
+            || cfg!(target_os = "linux") && !path::Path::new("/proc").join(self.pid.to_string()).exists().await
+    }
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "<unknown>".to_owned())
+}
+
+/// Returns a path next to the lock at `path` which is unique to this call.
+fn unique_path(path: &path::Path, ext: &str) -> path::PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_extension(format!("lock.{}-{n}.{ext}", std::process::id()))
+}
+
+/// Reads the lock file at `path`, returning `None` if it does not exist.
+async fn read(path: &path::Path) -> io::Result<Option<Vec<u8>>> {
+    let mut file = match fs::File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+/// Removes the stale lock at `path`, provided it still holds `stale`.
+///
+/// The lock is first renamed out of the way, so that a fresh lock created by another instance
+/// in the meantime is never removed; if one was moved instead, it is linked back into place.
+async fn reclaim(path: &path::Path, stale: &[u8]) -> Result<()> {
+    let moved = unique_path(path, "stale");
+    match fs::rename(path, &moved).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+
+    if read(&moved).await?.as_deref() != Some(stale) {
+        log::warn!("lock {} changed while reclaiming it; restoring", path.display());
+        if let Err(e) = fs::hard_link(&moved, path).await {
+            log::warn!("failed to restore lock {}: {e}", path.display());
+        }
+    }
+
+    match fs::remove_file(&moved).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Creates the lock file at `path` owned by this process, returning `false` if it already exists.
+///
+/// The owner is written to a temporary file which is then linked into place,
+/// so that other instances never read a lock which is empty or partially written.
+async fn create(path: &path::Path) -> Result<bool> {
+    let temp = unique_path(path, "tmp");
+    let Some(mut file) = fs_utils::create_new_file(&temp).await? else {
+        return Err(anyhow!("temporary lock file {} already exists", temp.display()));
+    };
+
+    let written = async {
+        file.write_all(&serde_json::to_vec(&Owner::current())?).await?;
+        file.sync_all().await?;
+        drop(file);
+        Ok::<_, anyhow::Error>(fs::hard_link(&temp, path).await)
+    }
+    .await;
+    if let Err(e) = fs::remove_file(&temp).await {
+        log::warn!("failed to remove temporary lock file {}: {e}", temp.display());
+    }
+
+    match written? {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// An exclusive lock on a stream, preventing it from being archived by multiple instances.
+/// The lock is released when dropped.
+#[derive(Debug)]
+pub struct StreamLock {
+    path: path::PathBuf,
+}
+
+impl StreamLock {
+    /// Tries to acquire the lock for the stream id under `dir`.
+    /// Returns `None` if the stream is already locked by a live owner.
+    pub async fn acquire(dir: &path::Path, id: &str) -> Result<Option<Self>> {
+        fs::create_dir_all(dir).await?;
+        let path = dir.join(format!("{}.lock", fs_utils::san(id)));
+
+        if !HELD.lock().unwrap().insert(path.clone()) {
+            log::info!("stream #{id} is locked by this process");
+            return Ok(None);
+        }
+
+        let res = Self::try_acquire(&path, id).await;
+        if !matches!(res, Ok(true)) {
+            HELD.lock().unwrap().remove(&path);
+        }
+        Ok(res?.then_some(Self { path }))
+    }
+
+    async fn try_acquire(path: &path::Path, id: &str) -> Result<bool> {
+        for _ in 0..ACQUIRE_ATTEMPTS {
+            if create(path).await? {
+                return Ok(true);
+            }
+
+            // the lock was released in the meantime
+            let Some(buf) = read(path).await? else {
+                continue;
+            };
+
+            match serde_json::from_slice::<Owner>(&buf) {
+                Ok(owner) if !owner.is_stale().await => {
+                    log::info!(
+                        "stream #{id} is locked by process {} on {} since {}",
+                        owner.pid,
+                        owner.hostname,
+                        owner.created_at
+                    );
+                    return Ok(false);
+                }
+                Ok(owner) => log::warn!("reclaiming stale lock for stream #{id}: {owner:?}"),
+                Err(e) => log::warn!("reclaiming unreadable lock for stream #{id}: {e}"),
+            }
+
+            reclaim(path, &buf).await?;
+        }
+
+        log::warn!("giving up on acquiring lock for stream #{id}, which kept changing");
+        Ok(false)
+    }
+}
+
+impl Drop for StreamLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("failed to release lock {}: {e}", self.path.display());
+        }
+        HELD.lock().unwrap().remove(&self.path);
+    }
+}
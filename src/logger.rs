@@ -1,20 +1,121 @@
 use ::log::LevelFilter;
+use std::str::FromStr;
 use log4rs::{
     append::{
         console::{ConsoleAppender, Target},
-        file::FileAppender,
+        rolling_file::{
+            policy::compound::{roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy},
+            RollingFileAppender,
+        },
     },
     config::{Appender, Config, Logger, Root},
-    encode::pattern::PatternEncoder,
+    encode::{json::JsonEncoder, pattern::PatternEncoder, Encode},
     filter::threshold::ThresholdFilter,
     Handle,
 };
 
-pub fn init(file: String, level: LevelFilter, stderr: bool) -> Handle {
+/// The size a log file is rolled over at, unless set with `--log-max-size`.
+pub const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+/// The number of rolled over log files kept, unless set with `--log-max-files`.
+pub const DEFAULT_MAX_FILES: u32 = 16;
+
+/// The format log records are written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable lines.
+    Text,
+    /// Newline-delimited JSON objects, for log aggregators.
+    Json,
+}
+
+impl Format {
+    fn encoder(self, pattern: &str) -> Box<dyn Encode> {
+        match self {
+            Format::Text => Box::new(PatternEncoder::new(pattern)),
+            Format::Json => Box::new(JsonEncoder::new()),
+        }
+    }
+}
+
+const CRATE: &str = "twitch_archive";
+
+/// Per-module log levels, in the syntax of `RUST_LOG`: `hls=trace,eventsub=debug,info`.
+///
+/// A bare level sets the level of every module without a level of its own.
+#[derive(Clone, Debug, Default)]
+pub struct Directives {
+    default: Option<LevelFilter>,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for Directives {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut res = Self::default();
+        for x in s.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match x.split_once('=') {
+                Some((target, level)) => {
+                    let level = level
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid level in directive {x:?}"))?;
+                    res.targets.push((target.trim().to_owned(), level));
+                }
+                None => match x.parse() {
+                    Ok(level) => res.default = Some(level),
+                    // a target without a level enables everything below it, as in `RUST_LOG`
+                    Err(_) => res.targets.push((x.to_owned(), LevelFilter::Trace)),
+                },
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl Directives {
+    /// The loggers the directives are translated to.
+    ///
+    /// Modules of this crate may leave out the crate name, as in `hls`,
+    /// so every target without it also applies to the module of that name in this crate.
+    fn loggers(&self) -> impl Iterator<Item = (String, LevelFilter)> + '_ {
+        self.targets.iter().flat_map(|(target, level)| {
+            let own = (target != CRATE && !target.starts_with(&format!("{CRATE}::")))
+                .then(|| (format!("{CRATE}::{target}"), *level));
+            std::iter::once((target.clone(), *level)).chain(own)
+        })
+    }
+}
+
+/// Sets up logging to the console, and to `file` unless it is empty.
+///
+/// Without `directives`, this crate logs to the console at `level` and other crates only warnings,
+/// while the file receives everything.
+/// With them, the level of each module is set by the directives, and the same records go to both.
+pub fn init(
+    file: String,
+    level: LevelFilter,
+    stderr: bool,
+    max_size: u64,
+    max_files: u32,
+    format: Format,
+    directives: Option<Directives>,
+) -> Handle {
     let mut config = Config::builder();
     let mut root = Root::builder();
     let mut main = Logger::builder().additive(false);
 
+    // the levels of the loggers take over from the console thresholds
+    let (main_level, main_threshold, root_level, root_threshold) = match &directives {
+        Some(x) => (
+            x.default.unwrap_or(level),
+            LevelFilter::Trace,
+            x.default.unwrap_or(LevelFilter::Warn),
+            LevelFilter::Trace,
+        ),
+        None => (LevelFilter::Trace, level, LevelFilter::Debug, LevelFilter::Warn),
+    };
+
     {
         let stdout = ConsoleAppender::builder()
             .target(if stderr {
@@ -22,11 +123,11 @@ pub fn init(file: String, level: LevelFilter, stderr: bool) -> Handle {
             } else {
                 Target::Stdout
             })
-            .encoder(Box::new(PatternEncoder::new(
+            .encoder(format.encoder(
                 "{d(%Y-%m-%d %H:%M:%S %Z)(local)} [ {h({l}):5.5} ] {T}: {M} > {m}{n}",
-            )))
+            ))
             .build();
-        let filter = ThresholdFilter::new(level);
+        let filter = ThresholdFilter::new(main_threshold);
 
         config = config.appender(
             Appender::builder()
@@ -43,11 +144,11 @@ pub fn init(file: String, level: LevelFilter, stderr: bool) -> Handle {
             } else {
                 Target::Stdout
             })
-            .encoder(Box::new(PatternEncoder::new(
+            .encoder(format.encoder(
                 "{d(%Y-%m-%d %H:%M:%S %Z)(local)} [ {h({l}):5.5} ] {T}: {M} > {m}{n}",
-            )))
+            ))
             .build();
-        let filter = ThresholdFilter::new(LevelFilter::Warn);
+        let filter = ThresholdFilter::new(root_threshold);
 
         config = config.appender(
             Appender::builder()
@@ -58,12 +159,17 @@ pub fn init(file: String, level: LevelFilter, stderr: bool) -> Handle {
     }
 
     if !file.is_empty() {
-        let file = FileAppender::builder()
+        // rolled over files are named `<file>.0` (the most recent) to `<file>.<max_files - 1>`
+        let roller = FixedWindowRoller::builder()
+            .build(&format!("{file}.{{}}"), max_files)
+            .unwrap();
+        let policy = CompoundPolicy::new(Box::new(SizeTrigger::new(max_size)), Box::new(roller));
+        let file = RollingFileAppender::builder()
             .append(true)
-            .encoder(Box::new(PatternEncoder::new(
+            .encoder(format.encoder(
                 "{d(%Y-%m-%d %H:%M:%S %Z)(local)} [ {({l}):5.5} ] {T}: {M}:{L}> {m}{n}",
-            )))
-            .build(file)
+            ))
+            .build(file, Box::new(policy))
             .unwrap();
 
         config = config.appender(Appender::builder().build("file", Box::new(file)));
@@ -71,10 +177,11 @@ pub fn init(file: String, level: LevelFilter, stderr: bool) -> Handle {
         main = main.appender("file");
     }
 
-    let config = config
-        .logger(main.build("twitch_archive", LevelFilter::Trace))
-        .build(root.build(LevelFilter::Debug))
-        .unwrap();
+    config = config.logger(main.build(CRATE, main_level));
+    for (name, level) in directives.iter().flat_map(Directives::loggers) {
+        config = config.logger(Logger::builder().build(name, level));
+    }
+    let config = config.build(root.build(root_level)).unwrap();
 
     log4rs::init_config(config).unwrap()
 }
@@ -1,465 +1,249 @@
-use anyhow::{anyhow, Context};
-use once_cell::sync::OnceCell;
-use async_recursion::async_recursion;
-use async_std::{
-    fs,
-    io::{self, WriteExt},
-    path,
-    sync::Arc,
-    task,
+use async_std::{channel, sync::Arc, task};
+use core::{sync::atomic::{AtomicUsize, Ordering}, time};
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+    stream::FuturesUnordered,
+    StreamExt, TryStreamExt,
 };
-use core::time;
-use futures::{channel::oneshot, StreamExt, TryStreamExt};
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::{HashMap, HashSet};
 
 use args::*;
-use eventsub::event::*;
-use fs_utils::san;
-use helix::{HelixAuth, Stream, User};
-use irc::IrcRecv;
-use prelude::*;
+use twitch_archive::{
+    archiver::{self, Archiver, ArchiverSettings, Source},
+    checksum,
+    eventsub::{self, event::{self, *}},
+    events,
+    helix::{self, HelixAuth, Stream, User},
+    hls,
+    irc::{self, IrcRecv},
+    logger, metrics, net, notify, recover,
+};
 
 mod args;
-mod eventsub;
-mod filename;
-mod fs_utils;
-mod helix;
-mod hls;
-mod irc;
-mod live;
-mod logger;
-mod prelude;
-mod rand;
-mod retry;
-//mod tar;
-
-const CHAT_BUFFER: usize = 16384;
-const RAND_DIR_LEN: usize = 12;
-const ASYNC_BUF_FACTOR: usize = 64;
-
-static FORMATTER: OnceCell<(filename::Formatter, bool)> = OnceCell::new();
-static TW_STREAM_AUTH: OnceCell<Box<str>> = OnceCell::new();
-static EXTRACTOR: OnceCell<Extractor> = OnceCell::new();
-
-async fn datafile(
-    path: &path::Path,
-    stream: &Stream,
-    stream_data: Option<&hls::StreamData>,
-) -> Result<()> {
-    use chrono::SecondsFormat;
-
-    #[derive(Serialize)]
-    struct Data<'a> {
-        version: String,
-        data: StreamSer<'a>,
-        segments: Vec<Segments<'a>>,
-    }
-
-    #[derive(Serialize)]
-    struct StreamSer<'a> {
-        id: &'a str,
-        user: &'a User,
-        game: GameDes<'a>,
-        title: &'a str,
-        started_at: String,
-    }
-
-    #[derive(Serialize)]
-    struct GameDes<'a> {
-        id: &'a str,
-        name: &'a str,
-    }
-
-    #[derive(Serialize)]
-    struct Segments<'a> {
-        path: String,
-        group_id: &'a str,
-        name: &'a str,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        language: Option<&'a str>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        max_bitrate: Option<u64>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        bitrate: Option<u64>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        resolution: Option<Resolution>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        frame_rate: Option<f64>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        codecs: Option<&'a str>,
-    }
-
-    #[derive(Serialize)]
-    struct Resolution {
-        width: u64,
-        height: u64,
-    }
-
-    let datapath = path.join("info.json");
-    let mut file = fs::File::create(&datapath).await?;
-    let (segpath, alt, var);
-    let segments = if let Some(x) = stream_data {
-        (segpath, alt, var) = (&x.0, &x.1, &x.2);
-        vec![Segments {
-            path: segpath.to_string_lossy().into_owned(),
-            group_id: alt.group_id.as_str(),
-            name: alt.name.as_str(),
-            language: alt.language.as_deref(),
-            max_bitrate: var.as_ref().map(|x| x.bandwidth),
-            bitrate: var.as_ref().and_then(|x| x.average_bandwidth),
-            resolution: var.as_ref().and_then(|x| x.resolution).map(|x| Resolution {
-                width: x.width,
-                height: x.height,
-            }),
-            frame_rate: var.as_ref().and_then(|x| x.frame_rate),
-            codecs: var.as_ref().and_then(|x| x.codecs.as_deref()),
-        }]
-    } else {
-        vec![]
-    };
 
-    let data = Data {
-        version: format!("0.1/{}", args::VERSION),
-        data: StreamSer {
-            id: stream.id(),
-            user: stream.user(),
-            game: GameDes {
-                id: stream.game_id(),
-                name: stream.game_name(),
-            },
-            title: stream.title(),
-            started_at: stream
-                .started_at()
-                .to_rfc3339_opts(SecondsFormat::AutoSi, true),
-        },
-        segments,
-    };
-
-    file.write_all(&serde_json::to_vec(&data)?).await?;
-    file.sync_all().await.map_err(From::from)
+const RESUBSCRIBE_DELAY: time::Duration = time::Duration::from_secs(15);
+/// The number of failed subscriptions in a row after which the delay stops doubling,
+/// and each further failure is logged as an error.
+const MAX_RESUBSCRIBE: u32 = 6;
+/// Subscriptions lasting longer than this are not counted as consecutive failures.
+const RESUBSCRIBE_RESET: time::Duration = time::Duration::from_secs(3600);
+/// The number of polls in a row a channel must be missing from for `--offline-poll` to consider it offline,
+/// as the Get Streams api can briefly leave out a stream that is still live.
+const POLL_OFFLINE_AFTER: u32 = 2;
+/// The maximum number of users in a single Get Streams request.
+const POLL_BATCH: usize = 100;
+/// How often the subscription list file is checked for changes.
+const SUB_RELOAD_INTERVAL: time::Duration = time::Duration::from_secs(30);
+
+/// The cache of resolved channels given by `--user-cache`.
+static USER_CACHE: OnceCell<async_std::sync::Mutex<helix::UserCache>> = OnceCell::new();
+/// The number of downloads currently running.
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+/// The ids of the streams being downloaded.
+static ACTIVE_STREAMS: Lazy<std::sync::Mutex<HashSet<Box<str>>>> = Lazy::new(Default::default);
+/// Closed when the process is asked to shut down; nothing is ever sent through it.
+static SHUTDOWN: Lazy<(channel::Sender<()>, channel::Receiver<()>)> =
+    Lazy::new(|| channel::bounded(1));
+
+/// Resolves once the process is asked to shut down.
+async fn shutdown() {
+    let _ = SHUTDOWN.1.recv().await;
 }
 
-async fn chat_log(
-    rx: IrcRecv,
-    path: impl AsRef<path::Path>,
-    mut noti: futures::channel::oneshot::Receiver<()>,
-) -> Result<()> {
-    use futures::{
-        future::{select, Either},
-        io::BufWriter,
-    };
-
-    if !rx.open() {
-        return Err(anyhow!("irc channel was unexpectedly open!"));
-    }
-
-    let mut file = BufWriter::with_capacity(
-        CHAT_BUFFER,
-        fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
-            .await?,
-    );
-
-    loop {
-        let msg = match select(rx.recv(), noti).await {
-            Either::Left((msg, next_noti)) => {
-                noti = next_noti;
-                msg?
-            }
-            Either::Right(_) => {
-                file.flush().await?;
-                rx.close();
-                return Ok(());
-            }
-        };
-
-        file.write_all(msg.as_bytes()).await?;
-    }
+fn is_shutdown() -> bool {
+    SHUTDOWN.0.is_closed()
 }
 
-async fn cmd(program: &str, args: &[&str], output: bool) -> Result<Option<String>> {
-    use async_std::process::Stdio;
+/// Requests a graceful shutdown on the first SIGINT or SIGTERM,
+/// and exits immediately on the second one.
+fn spawn_signal_handler() {
+    use async_signal::{Signal, Signals};
 
-    log::trace!("running command :{program} {args:?}");
-    let out = async_std::process::Command::new(program)
-        .args(args)
-        .stdout(if output {
-            Stdio::piped()
-        } else {
-            Stdio::null()
-        })
-        .output()
-        .await?;
+    #[cfg(unix)]
+    let signals = Signals::new([Signal::Int, Signal::Term]);
+    #[cfg(not(unix))]
+    let signals = Signals::new([Signal::Int]);
 
-    if out.status.success() {
-        if output {
-            return String::from_utf8(out.stdout).map(Some).map_err(From::from);
-        } else {
-            return Ok(None);
+    let mut signals = match signals {
+        Ok(x) => x,
+        Err(e) => {
+            log::warn!("could not install signal handler: {e:?}");
+            return;
         }
     };
 
-    let (logpath, file) = fs_utils::create_dedup_file(path::Path::new(&format!(
-        "{}.{}.log",
-        task::current().name().unwrap_or("<unknown>"),
-        san(program)
-    )))
-    .await?;
-
-    log::error!(
-        "{program} exited with status {}: logging at {}",
-        out.status.code().unwrap_or(-1),
-        logpath.display()
-    );
-
-    let mut writer = futures::io::BufWriter::new(file);
-
-    writer.write_all("=== STDOUT ===\n".as_bytes()).await?;
-    writer.write_all(&out.stdout).await?;
-    writer.write_all("\n\n=== STDERR ===\n".as_bytes()).await?;
-    writer.write_all(&out.stderr).await?;
-    writer.flush().await?;
-    Err(anyhow!("program exited abnormally!"))
+    task::spawn(async move {
+        if let Some(sig) = signals.next().await {
+            log::info!("received {sig:?}; finishing in-flight downloads before shutting down");
+            SHUTDOWN.0.close();
+        }
+        if let Some(sig) = signals.next().await {
+            log::warn!("received {sig:?} again; exiting immediately");
+            std::process::exit(130);
+        }
+    });
 }
 
-async fn streamlink(login: impl AsRef<str>) -> Result<Option<String>> {
-    let link = format!("https://twitch.tv/{}", login.as_ref());
-    let mut args = vec!["--stream-url", &link];
+/// Marks a stream as being downloaded until dropped,
+/// so that a redelivered or raced `stream.online` event does not download it a second time.
+struct ActiveStream(Box<str>);
 
-    if let Some(x) = TW_STREAM_AUTH.get() {
-        args.insert(0, "--twitch-api-header");
-        args.insert(1, x);
+impl ActiveStream {
+    /// Returns `None` if the stream is already being downloaded.
+    fn claim(id: &str) -> Option<Self> {
+        ACTIVE_STREAMS
+            .lock()
+            .unwrap()
+            .insert(id.into())
+            .then(|| Self(id.into()))
     }
-
-    cmd("streamlink", &args, true).await
 }
 
-async fn download(stream: Stream, chat: IrcRecv, chn: ChannelSettings) -> Result<()> {
-    async fn _stream(
-        path: path::PathBuf,
-        stream: &Stream,
-        format: &str,
-    ) -> Result<Option<hls::StreamData>> {
-        log::debug!("download location: {}", path.display());
-
-        let mut n = 0;
-        let url = loop {
-            n += 1;
-            let url = match EXTRACTOR.get().unwrap() {
-                Extractor::Internal => live::get_hls(stream.user().login(), TW_STREAM_AUTH.get().map(AsRef::as_ref)).await,
-                Extractor::Streamlink => streamlink(stream.user().login()).await
-            }.context("failed to fetch hls playlist url")?;
-
-            if let Some(x) = url {
-                break x;
-            }
-
-            async_std::task::sleep(time::Duration::from_secs(5)).await;
-            if n >= 4 {
-                log::error!("could not find m3u8 url!");
-                return Err(anyhow!("could not find m3u8 url!"));
-            }
-        };
-
-        hls::download(url, &path, format.split(',').map(str::trim))
-            .await
-            .context("failed to download hls playlist")
-    }
-
-    async fn _dl(
-        path: path::PathBuf,
-        stream: &Stream,
-        chat: &IrcRecv,
-        chn: &ChannelSettings,
-    ) -> Result<Option<hls::StreamData>> {
-        let (tx, rx) = oneshot::channel();
-
-        let chat_handle = task::Builder::new()
-            .name(task::current().name().unwrap_or_default().to_owned())
-            .local(chat_log(chat.clone(), path.join("chat.log"), rx))
-            .context("failed to download chat")?;
-        let res = _stream(path, stream, &chn.format).await;
-
-        tx.send(())
-            .or(Err(anyhow!("notification channel dropped before send")))?;
-        chat_handle.await.and(res)
-    }
-
-    async fn move_dir(orig: &path::Path, dest: &path::Path) -> Result<Box<path::Path>> {
-        let dir = fs_utils::create_dedup_dir(dest).await?;
-        // see async-std issue#1053
-        fs::read_dir(&orig)
-            .await?
-            .map(|entry| async move {
-                let entry = entry?;
-                fs::rename(entry.path(), dest.join(entry.path().file_name().unwrap())).await
-            })
-            .buffer_unordered(ASYNC_BUF_FACTOR)
-            .try_collect()
-            .await?;
-
-        fs::remove_dir_all(orig).await?;
-
-        Ok(dir)
-    }
-
-    async fn tar(tarpath: &path::Path, path: &path::Path) -> Result<Box<path::Path>> {
-        use async_tar::Builder;
-        #[async_recursion]
-        async fn put_recursive(
-            builder: &mut Builder<fs::File>,
-            path: &path::Path,
-            base: &path::Path,
-            uc: &path::Path
-        ) -> Result<()> {
-            use fs::{DirEntry, FileType};
-
-            async fn entry_check(entry: io::Result<DirEntry>, uc: &path::Path) -> Result<(FileType, DirEntry)> {
-                let entry = entry?;
-                let canon = entry.path()
-                    .canonicalize()
-                    .await?;
-
-                if !canon.starts_with(uc) {
-                    panic!(
-                        "reached unpexpected location while creating tar: {}, bound: {}",
-                        canon.display(),
-                        uc.display()
-                    );
-                }
-
-                let file_type = entry.file_type().await?;
-                Ok((file_type, entry))
-            }
-
-            let mut dir_entry = fs::read_dir(&path)
-                .await?
-                .map(|entry| entry_check(entry, uc))
-                .buffer_unordered(ASYNC_BUF_FACTOR);
-
-            while let Some(x) = dir_entry.next().await {
-                let (file_type, entry) = x?;
-
-                if file_type.is_dir() {
-                    log::trace!(
-                        "appending directory {}: {}",
-                        entry.path().display(),
-                        entry.file_name().to_string_lossy()
-                    );
-                    builder.append_dir(base.join(entry.file_name()), &entry.path()).await?;
-
-                    put_recursive(builder, &entry.path(), &base.join(entry.file_name()), uc).await?;
-                    let _ = fs::remove_dir(entry.path()).await;
-                } else if file_type.is_file() {
-                    log::trace!(
-                        "appending file {}: {}",
-                        entry.path().display(),
-                        entry.file_name().to_string_lossy()
-                    );
-                    builder.append_path_with_name(&entry.path(), base.join(entry.file_name()))
-                        .await?;
-                    let _ = fs::remove_file(entry.path()).await;
-                } else {
-                    log::warn!("file {} was not a file or a directory", entry.path().display())
-                }
-            }
-
-            Ok(())
-        }
-
-        let (tarpath, tarfile) = fs_utils::create_dedup_file(tarpath).await?;
-        let mut tar = async_tar::Builder::new(tarfile);
-        let canon = path.canonicalize().await?;
-        
-        put_recursive(&mut tar, path, path::Path::new(""), &canon).await?;
-
-        tar.finish().await?;
-        fs::remove_dir_all(path).await?;
-
-        Ok(tarpath)
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        ACTIVE_STREAMS.lock().unwrap().remove(&self.0);
     }
+}
 
-    let (fmt, to_dir) = FORMATTER.get().unwrap();
-    let filename = fmt.format(&stream);
-    let path = if *to_dir {
-        path::Path::new(&filename).to_path_buf()
-    } else {
-        path::Path::new(&filename).with_extension("tar")
-    };
-
-    log::info!(
-        "downloading stream #{} for channel {}",
-        stream.id(),
-        stream.user()
-    );
-
-    //Create a folder as a temporary download directory
-    let dl_path = loop {
-        let new_path = path::Path::new(".download").join(rand::rand_hex(RAND_DIR_LEN));
-        if fs_utils::create_new_dir(&new_path)
-            .await
-            .context("cannot create temporary directory")?
-        {
-            break new_path;
-        }
-    };
-
-    let res = match _dl(dl_path.clone(), &stream, &chat, &chn).await {
-        Ok(Some(x)) => Ok(x),
-        Ok(None) => {
-            return fs::remove_dir_all(&dl_path)
-                .await
-                .context("failed to clean up download directory")
+/// Refreshes the app access token, and the user access token if it can be refreshed,
+/// after twitch revoked a subscription's authorization.
+async fn reauthorize(auth: &mut HelixAuth) {
+    log::info!("refreshing access tokens after authorization was revoked");
+    if let Err(e) = auth.refresh().await {
+        log::error!("could not refresh app access token: {e:?}");
+    }
+    if auth.user_token().await.is_some() {
+        if let Err(e) = auth.refresh_user().await {
+            log::warn!("could not refresh user access token; it may have to be replaced: {e:?}");
         }
-        Err(e) => Err(e),
-    };
-
-    datafile(&dl_path, &stream, res.as_ref().ok())
-        .await
-        .context("could not write datafile")?;
+    }
+}
 
-    return if *to_dir {
-        move_dir(&dl_path, &path)
-            .await
-            .map(|x| log::info!("finished downloading: {}", x.display()))
-            .context("could not move directory")
-    } else {
-        res.and(
-            tar(&path, &dl_path)
-                .await
-                .map(|x| log::info!("finished downloading: {}", x.display()))
-                .context("could not make tar archive"),
-        )
-    };
+/// Deletes the subscription `sub` of the channel `user`, if there is one.
+async fn unsubscribe<T>(events: &eventsub::EventSub, user: &User, sub: Option<eventsub::Subscription<T>>) {
+    let Some(sub) = sub else { return };
+    if let Err(e) = events.unsubscribe(sub).await {
+        log::warn!("could not delete subscription for channel {user}: {e:?}");
+    }
 }
 
 async fn listen(
-    auth: HelixAuth,
+    archiver: Arc<Archiver>,
+    mut auth: HelixAuth,
     events: Arc<eventsub::EventSub>,
     user: User,
     rx: IrcRecv,
     settings: ChannelSettings,
+    mut removed: oneshot::Receiver<()>,
 ) {
+    let mut resubscribes: u32 = 0;
+
     loop {
+        if resubscribes > 0 {
+            // the delay stops growing after `MAX_RESUBSCRIBE` attempts, but the channel is never given up on
+            let delay = RESUBSCRIBE_DELAY * 2u32.pow(resubscribes.min(MAX_RESUBSCRIBE) - 1);
+            if resubscribes > MAX_RESUBSCRIBE {
+                log::error!(
+                    "subscription for channel {user} failed {} times in a row; re-subscribing in {}s. \
+                    check that the callback url is reachable and the authorization is valid!",
+                    resubscribes - 1,
+                    delay.as_secs()
+                );
+            } else {
+                log::info!("re-subscribing in {}s ({resubscribes}/{MAX_RESUBSCRIBE})", delay.as_secs());
+            }
+
+            let stopped = future::select(&mut removed, Box::pin(shutdown()));
+            if let Either::Right(_) = future::select(Box::pin(task::sleep(delay)), stopped).await {
+                log::info!("stopped listening to channel {user}");
+                return;
+            }
+        }
+        resubscribes += 1;
+
         let sub = match events
             .subscribe::<stream::Online>(stream::OnlineCond::from_id(user.id()))
             .await
         {
             Ok(x) => x,
             Err(e) => {
-                log::error!("could not subscribe to event 'stream.online': {e:?}");
-                return;
+                log::error!("could not subscribe to event 'stream.online' for channel {user}: {e:?}");
+                continue;
             }
         };
 
         log::debug!("subscribed to event `stream.online`");
+        events::emit(&user, events::Event::Subscribed { subscription_id: sub.id(), event: "stream.online" });
+
+        let mut offline = match events
+            .subscribe::<stream::Offline>(stream::OfflineCond::from_id(user.id()))
+            .await
+        {
+            Ok(x) => {
+                log::debug!("subscribed to event `stream.offline`");
+                events::emit(&user, events::Event::Subscribed { subscription_id: x.id(), event: "stream.offline" });
+                Some(x)
+            }
+            Err(e) => {
+                log::warn!(
+                    "could not subscribe to event 'stream.offline'; \
+                    stream ends will only be detected by segment timeout: {e:?}"
+                );
+                None
+            }
+        };
+        let mut update = match events
+            .subscribe::<event::channel::Update>(event::channel::UpdateCond::from_id(user.id()))
+            .await
+        {
+            Ok(x) => {
+                log::debug!("subscribed to event `channel.update`");
+                events::emit(&user, events::Event::Subscribed { subscription_id: x.id(), event: "channel.update" });
+                Some(x)
+            }
+            Err(e) => {
+                log::warn!(
+                    "could not subscribe to event 'channel.update'; \
+                    title and category changes will not be recorded: {e:?}"
+                );
+                None
+            }
+        };
+        let subscribed_at = std::time::Instant::now();
 
         'listen: loop {
-            let msg = match sub.recv().await {
-                Ok(Some(x)) => x,
+            let stopped = future::select(&mut removed, Box::pin(shutdown()));
+            let next = match future::select(Box::pin(sub.recv()), stopped).await {
+                Either::Left((x, _)) => Some(x),
+                Either::Right(_) => None,
+            };
+
+            // the channel was removed from the subscription list, or the process is shutting down
+            let Some(next) = next else {
+                log::info!("stopped listening to channel {user}");
+                unsubscribe(&events, &user, Some(sub)).await;
+                unsubscribe(&events, &user, offline).await;
+                unsubscribe(&events, &user, update).await;
+                return;
+            };
+
+            let msg = match next {
+                Ok(Some(x)) => {
+                    resubscribes = 0;
+                    x
+                }
                 Ok(None) => {
-                    log::warn!("subscription revoked: {:?}", sub.status());
+                    let status = sub.status();
+                    log::warn!("subscription #{} for channel {user} revoked: {status:?}", sub.id());
+                    if subscribed_at.elapsed() > RESUBSCRIBE_RESET {
+                        resubscribes = 1;
+                    }
+                    // re-subscribing with the same tokens would only be revoked again
+                    if status == eventsub::SubStatus::AuthorizationRevoked {
+                        reauthorize(&mut auth).await;
+                    }
                     break;
                 }
                 Err(e) => {
@@ -471,6 +255,11 @@ async fn listen(
             };
             log::debug!("received event for stream #{}", msg.id());
 
+            let Some(active) = ActiveStream::claim(msg.id()) else {
+                log::info!("stream #{} is already being downloaded; ignoring event", msg.id());
+                continue;
+            };
+
             let stream = {
                 let mut count = 0;
                 'get_streams: loop {
@@ -508,10 +297,33 @@ async fn listen(
                 }
             };
             log::debug!("fetched stream object for stream #{}", stream.id());
+            if let Some(reason) = settings.skip_reason(&stream) {
+                log::info!("skipping stream #{} for channel {user}: {reason}", stream.id());
+                continue;
+            }
+            notify::online(&stream);
+            events::emit(stream.user(), events::Event::Online {
+                stream_id: stream.id(),
+                title: stream.title(),
+                game: stream.game_name(),
+            });
 
-            let task = match task::Builder::new()
+            // events received before the download started belong to an earlier stream
+            let started = chrono::Local::now();
+            let (stop_tx, stop_rx) = oneshot::channel();
+            let mut stop_tx = Some(stop_tx);
+            let (update_tx, update_rx) = channel::unbounded();
+            let mut task = match task::Builder::new()
                 .name(format!("#{}", stream.id()))
-                .spawn(download(stream, rx.clone(), settings.clone()))
+                .spawn({
+                    let archiver = Arc::clone(&archiver);
+                    let updates = update.is_some().then_some(update_rx);
+                    let (source, settings) = (Source::Live(rx.clone(), updates), settings.clone());
+                    async move {
+                        let _active = active;
+                        archiver.download(stream, source, settings, stop_rx).await
+                    }
+                })
             {
                 Ok(x) => x,
                 Err(e) => {
@@ -520,52 +332,944 @@ async fn listen(
                 }
             };
 
-            if let Err(e) = task.await {
+            DOWNLOADS.fetch_add(1, Ordering::Relaxed);
+            let res = loop {
+                let offline_recv = async {
+                    match &offline {
+                        Some(sub) => sub.recv_at().await,
+                        None => future::pending().await,
+                    }
+                };
+                let update_recv = async {
+                    match &update {
+                        Some(sub) => sub.recv_at().await,
+                        None => future::pending().await,
+                    }
+                };
+                let received = future::select(Box::pin(offline_recv), Box::pin(update_recv));
+                let next = future::select(received, Box::pin(shutdown()));
+
+                let next = match future::select(&mut task, next).await {
+                    Either::Left((res, _)) => break res,
+                    Either::Right((Either::Left((Either::Left((x, _)), _)), _)) => Either::Left(x),
+                    Either::Right((Either::Left((Either::Right((x, _)), _)), _)) => Either::Right(x),
+                    Either::Right((Either::Right(_), _)) => {
+                        log::info!("stopping download of channel {user} for shutdown");
+                        if let Some(tx) = stop_tx.take() {
+                            let _ = tx.send(());
+                        }
+                        break task.await;
+                    }
+                };
+
+                let msg = match next {
+                    Either::Left(x) => x,
+                    Either::Right(x) => {
+                        let Some(sub) = &update else { continue };
+                        match x {
+                            Ok(Some((at, ev))) => {
+                                if ev.user().id() != user.id() {
+                                    continue;
+                                }
+                                if at < started {
+                                    log::debug!("ignoring update event for channel {user} from before the download");
+                                    continue;
+                                }
+                                log::debug!("received update event for channel {user}");
+                                let _ = update_tx.try_send(archiver::StreamUpdate {
+                                    timestamp: at,
+                                    title: ev.title().into(),
+                                    game_id: ev.game_id().into(),
+                                    game_name: ev.game_name().into(),
+                                });
+                            }
+                            Ok(None) => {
+                                log::warn!(
+                                    "subscription #{} for channel {user} revoked: {:?}",
+                                    sub.id(),
+                                    sub.status()
+                                );
+                                unsubscribe(&events, &user, update.take()).await;
+                            }
+                            Err(e) => {
+                                log::error!(
+                                    "unexpected error while trying to recieve message from webhook: {e:?}"
+                                );
+                                unsubscribe(&events, &user, update.take()).await;
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let Some(sub) = &offline else { continue };
+
+                match msg {
+                    Ok(Some((at, ev))) => {
+                        if ev.user().id() != user.id() {
+                            continue;
+                        }
+                        if at < started {
+                            log::debug!("ignoring offline event for channel {user} from before the download");
+                            continue;
+                        }
+                        log::debug!("received offline event for channel {user}");
+                        if let Some(tx) = stop_tx.take() {
+                            let _ = tx.send(());
+                        }
+                    }
+                    Ok(None) => {
+                        log::warn!(
+                            "subscription #{} for channel {user} revoked: {:?}",
+                            sub.id(),
+                            sub.status()
+                        );
+                        unsubscribe(&events, &user, offline.take()).await;
+                    }
+                    Err(e) => {
+                        log::error!(
+                            "unexpected error while trying to recieve message from webhook: {e:?}"
+                        );
+                        unsubscribe(&events, &user, offline.take()).await;
+                    }
+                }
+            };
+            DOWNLOADS.fetch_sub(1, Ordering::Relaxed);
+
+            if let Err(e) = res {
                 log::error!("download failed: {e:?}");
             }
         }
+
+        // every subscription is created again, so the old ones would only deliver duplicates
+        unsubscribe(&events, &user, Some(sub)).await;
+        unsubscribe(&events, &user, offline).await;
+        unsubscribe(&events, &user, update).await;
     }
 }
 
-async fn archive(
-    auth: HelixAuth,
-    port: u16,
-    public_url: &url::Url,
-    channels: impl IntoIterator<Item = (User, IrcRecv, ChannelSettings)>,
-) {
+fn webhook(auth: &HelixAuth, port: u16, public_url: &url::Url, debug: bool) -> eventsub::EventSub {
     use async_std::net::{IpAddr, Ipv4Addr, SocketAddr};
-    use futures::future::join_all;
 
-    let events = eventsub::EventSub::new(
+    eventsub::EventSub::new(
         SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
         public_url,
         auth.clone(),
-    );
+        debug,
+    )
+}
+
+/// The subscription list file, which is reloaded on change.
+struct SubList {
+    path: String,
+    single_file: bool,
+}
+
+/// The channels currently being listened to.
+struct Channels {
+    archiver: Arc<Archiver>,
+    auth: HelixAuth,
+    events: Arc<eventsub::EventSub>,
+    irc: irc::IrcClient,
+    /// Stops listening to the channel with the given user id.
+    active: HashMap<Box<str>, oneshot::Sender<()>>,
+    tasks: FuturesUnordered<task::JoinHandle<()>>,
+    /// Streams left incomplete by a previous run, keyed by user id,
+    /// which are continued once their channel is added.
+    resumable: HashMap<Box<str>, Stream>,
+}
+
+impl Channels {
+    async fn add(&mut self, user: User, settings: ChannelSettings) {
+        let rx = match self.irc.join(user.login()).await {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("could not join chat of channel {user}: {e:?}");
+                return;
+            }
+        };
+
+        // no `stream.online` event is sent for a stream that is already live
+        if let Some(stream) = self.resumable.remove(user.id()) {
+            self.resume(stream, rx.clone(), settings.clone());
+        }
+
+        let (tx, removed) = oneshot::channel();
+        let archiver = Arc::clone(&self.archiver);
+        let (auth, events, irc) = (self.auth.clone(), Arc::clone(&self.events), self.irc.clone());
+        let id: Box<str> = user.id().into();
+
+        let handle = task::Builder::new()
+            .name(format!("user-{id}"))
+            .local(async move {
+                let login = user.login().to_owned();
+                listen(archiver, auth, events, user, rx, settings, removed).await;
+                if let Err(e) = irc.part(&login).await {
+                    log::warn!("could not part chat of channel {login}: {e:?}");
+                }
+            })
+            .unwrap();
+
+        self.active.insert(id, tx);
+        self.tasks.push(handle);
+    }
+
+    /// Continues the download of a stream left incomplete by a previous run.
+    fn resume(&mut self, stream: Stream, rx: IrcRecv, settings: ChannelSettings) {
+        let Some(active) = ActiveStream::claim(stream.id()) else { return };
+        let archiver = Arc::clone(&self.archiver);
+
+        let handle = task::Builder::new()
+            .name(format!("#{}", stream.id()))
+            .spawn(async move {
+                let _active = active;
+                DOWNLOADS.fetch_add(1, Ordering::Relaxed);
+                download_once(archiver, stream, Source::Live(rx, None), settings).await;
+                DOWNLOADS.fetch_sub(1, Ordering::Relaxed);
+            });
+        match handle {
+            Ok(x) => self.tasks.push(x),
+            Err(e) => log::error!("failed to spawn task: {e:?}"),
+        }
+    }
+
+    /// Stops listening to the channel; a download already running is allowed to finish.
+    fn remove(&mut self, id: &str) {
+        if let Some(tx) = self.active.remove(id) {
+            let _ = tx.send(());
+        }
+    }
+
+    async fn reload(&mut self, channels: Vec<(User, ChannelSettings)>, complete: bool) {
+        let ids: HashSet<&str> = channels.iter().map(|(user, _)| user.id()).collect();
+
+        // channels that could not be resolved are kept, rather than being treated as removed
+        let removed: Vec<Box<str>> = if complete {
+            self.active.keys().filter(|id| !ids.contains(&***id)).cloned().collect()
+        } else {
+            log::warn!("some channels could not be resolved; not removing any channels");
+            Vec::new()
+        };
+
+        for id in &removed {
+            self.remove(id);
+        }
+
+        // the settings of a channel already listened to are not changed
+        let mut added = 0;
+        for (user, settings) in channels {
+            if !self.active.contains_key(user.id()) {
+                self.add(user, settings).await;
+                added += 1;
+            }
+        }
+
+        log::info!(
+            "reloaded subscription list: {added} channel(s) added, {} removed",
+            removed.len()
+        );
+    }
+}
+
+/// Resolves the given credentials into users, retrieving the users in batches.
+/// Users found in the user cache are not retrieved.
+/// Returns whether every channel could be resolved.
+async fn resolve_users(
+    auth: &HelixAuth,
+    channels: Vec<(UserCredentials, ChannelSettings)>,
+) -> (Vec<(User, ChannelSettings)>, bool) {
+    let mut cache = match USER_CACHE.get() {
+        Some(x) => Some(x.lock().await),
+        None => None,
+    };
+
+    let mut users: Vec<User> = Vec::new();
+    let mut creds: Vec<helix::UserCredentials> = Vec::new();
+    for (cred, _) in &channels {
+        let cached = match (cred, &cache) {
+            (UserCredentials::Id { id }, Some(c)) => c.get_id(id),
+            (UserCredentials::Login { login }, Some(c)) => c.get_login(login),
+            _ => None,
+        };
+
+        match (cred, cached) {
+            (_, Some(x)) => users.push(x),
+            (UserCredentials::Full { .. }, None) => {}
+            (UserCredentials::Id { id }, None) => creds.push(helix::UserCredentials::Id(id)),
+            (UserCredentials::Login { login }, None) => {
+                creds.push(helix::UserCredentials::Login(login))
+            }
+        }
+    }
+    log::debug!("{} user(s) found in cache, resolving {}", users.len(), creds.len());
+
+    if !creds.is_empty() {
+        match helix::get_users(auth, &creds).await {
+            Ok(x) => {
+                if let Some(c) = &mut cache {
+                    x.iter().for_each(|user| c.insert(user));
+                    if let Err(e) = c.save().await {
+                        log::warn!("{e:?}");
+                    }
+                }
+                users.extend(x);
+            }
+            Err(e) => log::error!("could not retrieve users: {e:?}"),
+        }
+    }
+
+    let mut complete = true;
+    let channels = channels
+        .into_iter()
+        .filter_map(|(cred, settings)| {
+            let user = match &cred {
+                UserCredentials::Full { id, login, name } => Some(User::new(id, login, name)),
+                UserCredentials::Id { id } => users.iter().find(|x| x.id() == id).cloned(),
+                UserCredentials::Login { login } => users
+                    .iter()
+                    .find(|x| x.login().eq_ignore_ascii_case(login))
+                    .cloned(),
+            };
+
+            if user.is_none() {
+                log::error!("could not retrieve user {cred:?}");
+                complete = false;
+            }
+            user.map(|x| (x, settings))
+        })
+        .collect();
+
+    (channels, complete)
+}
+
+/// Yields whenever the subscription list should be reloaded;
+/// when the modification time of the file changes, or when SIGHUP is received.
+fn reload_signals(path: &str) -> futures::stream::LocalBoxStream<'static, ()> {
+    use futures::stream;
+
+    fn modified(path: &str) -> Option<std::time::SystemTime> {
+        std::fs::metadata(path).and_then(|x| x.modified()).ok()
+    }
+
+    let last = modified(path);
+    let poll = stream::unfold((path.to_owned(), last), |(path, last)| async move {
+        loop {
+            task::sleep(SUB_RELOAD_INTERVAL).await;
+            let m = modified(&path);
+            if m != last {
+                return Some(((), (path, m)));
+            }
+        }
+    });
+
+    #[cfg(unix)]
+    match async_signal::Signals::new([async_signal::Signal::Hup]) {
+        Ok(x) => return Box::pin(stream::select(poll, x.map(|_| ()))),
+        Err(e) => log::warn!("could not listen for SIGHUP: {e:?}"),
+    }
+
+    Box::pin(poll)
+}
+
+async fn archive(
+    archiver: Arc<Archiver>,
+    auth: HelixAuth,
+    events: eventsub::EventSub,
+    verify_callback: bool,
+    irc: irc::IrcClient,
+    channels: Vec<(User, ChannelSettings)>,
+    sub_list: Option<SubList>,
+    resumable: Vec<Stream>,
+) {
     let shared = Arc::new(events);
 
     async_std::task::yield_now().await;
-    join_all(channels.into_iter().map(|(user, rx, settings)| {
-        task::Builder::new()
-            .name(format!("user-{}", user.id()))
-            .local(listen(
-                auth.clone(),
-                Arc::clone(&shared),
+
+    if verify_callback {
+        match shared.verify_callback().await {
+            Ok(_) => log::info!("callback self-test succeeded"),
+            Err(e) => {
+                log::error!("callback self-test failed:\n\t{e:?}");
+                return;
+            }
+        }
+    }
+
+    let mut active = Channels {
+        archiver,
+        auth,
+        events: shared,
+        irc,
+        active: HashMap::new(),
+        tasks: FuturesUnordered::new(),
+        resumable: resumable.into_iter().map(|x| (x.user().id().into(), x)).collect(),
+    };
+    for (user, settings) in channels {
+        active.add(user, settings).await;
+    }
+
+    let mut reload = sub_list.as_ref().map(|x| reload_signals(&x.path));
+
+    loop {
+        if is_shutdown() {
+            let n = active.tasks.len();
+            log::info!(
+                "waiting for {n} channel(s) and {} download(s) to finish",
+                DOWNLOADS.load(Ordering::Relaxed)
+            );
+            active.tasks.collect::<Vec<_>>().await;
+            log::info!("awaited {n} task(s) during shutdown");
+            return;
+        }
+
+        let next = match &mut reload {
+            None if active.tasks.is_empty() => return,
+            None => Either::Left(active.tasks.next().await),
+            Some(rl) if active.tasks.is_empty() => Either::Right(rl.next().await),
+            Some(rl) => {
+                let rl = future::select(rl.next(), Box::pin(shutdown()));
+                match future::select(active.tasks.next(), rl).await {
+                    Either::Left((x, _)) => Either::Left(x),
+                    Either::Right((Either::Left((x, _)), _)) => Either::Right(x),
+                    Either::Right((Either::Right(_), _)) => continue,
+                }
+            }
+        };
+
+        match next {
+            // a listener stopped on its own; forget it so that it can be added again
+            Either::Left(_) => active.active.retain(|_, tx| !tx.is_canceled()),
+            Either::Right(Some(())) => {
+                let SubList { path, single_file } = sub_list.as_ref().unwrap();
+                log::info!("reloading subscription list {path}");
+
+                let channels = match args::read_channels(path, *single_file) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        log::error!("could not reload subscription list: {e:?}");
+                        continue;
+                    }
+                };
+                let (channels, complete) = resolve_users(&active.auth, channels).await;
+                active.reload(channels, complete).await;
+            }
+            Either::Right(None) => reload = None,
+        }
+    }
+}
+
+/// A channel watched by [`poll`].
+struct Polled {
+    user: User,
+    settings: ChannelSettings,
+    rx: IrcRecv,
+    /// The number of polls in a row the channel was not live for.
+    missed: u32,
+    /// Stops the running download of the channel.
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Polled {
+    fn is_online(&self) -> bool {
+        self.missed < POLL_OFFLINE_AFTER
+    }
+}
+
+/// Retrieves the live streams of the given users, keyed by user id.
+async fn live_streams(auth: &HelixAuth, users: &[&User]) -> anyhow::Result<HashMap<Box<str>, Stream>> {
+    let mut res = HashMap::new();
+    for chunk in users.chunks(POLL_BATCH) {
+        let filter = chunk.iter().map(|x| helix::StreamFilter::User(x));
+        let streams: Vec<Stream> = helix::get_streams(auth.clone(), filter).try_collect().await?;
+        res.extend(streams.into_iter().map(|x| (x.user().id().into(), x)));
+    }
+    Ok(res)
+}
+
+/// Finds the streams left incomplete by a previous run which are still live,
+/// so that their downloads can be continued.
+async fn resumable_streams(archiver: &Archiver, auth: &HelixAuth) -> Vec<Stream> {
+    let incomplete = match recover::incomplete_streams(archiver).await {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("could not list incomplete downloads: {e:?}");
+            return Vec::new();
+        }
+    };
+    if incomplete.is_empty() {
+        return Vec::new();
+    }
+
+    let users: Vec<&User> = incomplete.iter().map(Stream::user).collect();
+    let mut live = match live_streams(auth, &users).await {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("could not check whether incomplete downloads are still live: {e:?}");
+            return Vec::new();
+        }
+    };
+
+    incomplete
+        .iter()
+        .filter_map(|x| live.remove(x.user().id()).filter(|live| live.id() == x.id()))
+        .collect()
+}
+
+/// Watches the channels by polling their streams every `interval`, instead of subscribing to events,
+/// and downloads each stream that comes online.
+async fn poll(
+    archiver: Arc<Archiver>,
+    auth: HelixAuth,
+    irc: irc::IrcClient,
+    channels: Vec<(User, ChannelSettings)>,
+    sub_list: Option<SubList>,
+    interval: time::Duration,
+) {
+    async fn join(irc: &irc::IrcClient, user: User, settings: ChannelSettings) -> Option<Polled> {
+        match irc.join(user.login()).await {
+            Ok(rx) => Some(Polled {
                 user,
-                rx,
                 settings,
-            ))
-            .unwrap()
-    }))
-    .await;
+                rx,
+                missed: POLL_OFFLINE_AFTER,
+                stop: None,
+            }),
+            Err(e) => {
+                log::error!("could not join chat of channel {user}: {e:?}");
+                None
+            }
+        }
+    }
+
+    let mut polled: HashMap<Box<str>, Polled> = HashMap::new();
+    for (user, settings) in channels {
+        let id: Box<str> = user.id().into();
+        if let Some(x) = join(&irc, user, settings).await {
+            polled.insert(id, x);
+        }
+    }
+    log::info!("polling {} channel(s) every {}s", polled.len(), interval.as_secs());
+
+    let mut reload = sub_list.as_ref().map(|x| reload_signals(&x.path));
+    let mut downloads = FuturesUnordered::new();
+
+    loop {
+        let users: Vec<&User> = polled.values().map(|x| &x.user).collect();
+        match live_streams(&auth, &users).await {
+            Ok(mut live) => {
+                for channel in polled.values_mut() {
+                    let Some(stream) = live.remove(channel.user.id()) else {
+                        channel.missed = channel.missed.saturating_add(1);
+                        if channel.missed == POLL_OFFLINE_AFTER {
+                            log::info!("channel {} went offline", channel.user);
+                            if let Some(tx) = channel.stop.take() {
+                                let _ = tx.send(());
+                            }
+                        }
+                        continue;
+                    };
+
+                    if channel.is_online() {
+                        channel.missed = 0;
+                        continue;
+                    }
+
+                    // a skipped stream leaves the channel offline, so that it is checked again on the next poll,
+                    // as its title or game may change
+                    if let Some(reason) = channel.settings.skip_reason(&stream) {
+                        log::debug!("skipping stream #{} for channel {}: {reason}", stream.id(), channel.user);
+                        continue;
+                    }
+                    channel.missed = 0;
+
+                    let Some(active) = ActiveStream::claim(stream.id()) else {
+                        log::info!("stream #{} is already being downloaded", stream.id());
+                        continue;
+                    };
+                    log::info!("channel {} came online with stream #{}", channel.user, stream.id());
+                    notify::online(&stream);
+                    events::emit(stream.user(), events::Event::Online {
+                        stream_id: stream.id(),
+                        title: stream.title(),
+                        game: stream.game_name(),
+                    });
+
+                    // the download holds its own join, so that removing the channel does not part its chat
+                    let login = channel.user.login().to_owned();
+                    let chat = match irc.join(&login).await {
+                        Ok(x) => Some(x),
+                        Err(e) => {
+                            log::warn!("could not join chat of channel {} for the download: {e:?}", channel.user);
+                            None
+                        }
+                    };
+
+                    let (stop_tx, stop_rx) = oneshot::channel();
+                    let (archiver, irc) = (Arc::clone(&archiver), irc.clone());
+                    let id: Box<str> = channel.user.id().into();
+                    let rx = chat.clone().unwrap_or_else(|| channel.rx.clone());
+                    let (source, settings) = (Source::Live(rx, None), channel.settings.clone());
+                    let handle = task::Builder::new()
+                        .name(format!("#{}", stream.id()))
+                        .spawn(async move {
+                            let _active = active;
+                            DOWNLOADS.fetch_add(1, Ordering::Relaxed);
+                            let res = archiver.download(stream, source, settings, stop_rx).await;
+                            DOWNLOADS.fetch_sub(1, Ordering::Relaxed);
+                            if let Err(e) = &res {
+                                log::error!("download failed: {e:?}");
+                            }
+                            if chat.is_some() {
+                                if let Err(e) = irc.part(&login).await {
+                                    log::warn!("could not part chat of channel {login}: {e:?}");
+                                }
+                            }
+                            (id, res.is_ok())
+                        });
+                    match handle {
+                        Ok(x) => {
+                            channel.stop = Some(stop_tx);
+                            downloads.push(x);
+                        }
+                        Err(e) => log::error!("failed to spawn task: {e:?}"),
+                    }
+                }
+            }
+            // the channels keep their state until the next successful poll
+            Err(e) => log::error!("could not poll streams: {e:?}"),
+        }
+
+        // waits for the next poll, collecting finished downloads in the meantime;
+        // gives whether the subscription list is to be reloaded, or `None` on shutdown
+        let tick = task::sleep(interval);
+        futures::pin_mut!(tick);
+        let reloaded = loop {
+            let finished = async {
+                match downloads.next().await {
+                    Some(x) => x,
+                    None => future::pending().await,
+                }
+            };
+            let reloaded = async {
+                match &mut reload {
+                    Some(x) => x.next().await,
+                    None => future::pending().await,
+                }
+            };
+            let wake = future::select(Box::pin(finished), Box::pin(reloaded));
+            let wake = future::select(wake, Box::pin(shutdown()));
+
+            match future::select(&mut tick, wake).await {
+                Either::Left(_) => break Some(false),
+                Either::Right((Either::Left((Either::Left(((id, ok), _)), _)), _)) => {
+                    // a channel still live after a failed download is downloaded again on the next poll
+                    if let Some(channel) = polled.get_mut(&id).filter(|_| !ok) {
+                        channel.missed = POLL_OFFLINE_AFTER;
+                        channel.stop = None;
+                    }
+                }
+                Either::Right((Either::Left((Either::Right((Some(()), _)), _)), _)) => break Some(true),
+                Either::Right((Either::Left((Either::Right((None, _)), _)), _)) => reload = None,
+                Either::Right((Either::Right(_), _)) => break None,
+            }
+        };
+
+        let Some(reloaded) = reloaded else {
+            log::info!(
+                "stopping {} download(s) for shutdown",
+                DOWNLOADS.load(Ordering::Relaxed)
+            );
+            for tx in polled.values_mut().filter_map(|x| x.stop.take()) {
+                let _ = tx.send(());
+            }
+            downloads.collect::<Vec<_>>().await;
+            for login in polled.values().map(|x| x.user.login()) {
+                if let Err(e) = irc.part(login).await {
+                    log::warn!("could not part chat of channel {login}: {e:?}");
+                }
+            }
+            return;
+        };
+        if !reloaded {
+            continue;
+        }
+
+        let SubList { path, single_file } = sub_list.as_ref().unwrap();
+        log::info!("reloading subscription list {path}");
+
+        let channels = match args::read_channels(path, *single_file) {
+            Ok(x) => x,
+            Err(e) => {
+                log::error!("could not reload subscription list: {e:?}");
+                continue;
+            }
+        };
+        let (channels, complete) = resolve_users(&auth, channels).await;
+
+        // a download already running is allowed to finish
+        let ids: HashSet<&str> = channels.iter().map(|(user, _)| user.id()).collect();
+        let removed: Vec<Box<str>> = if complete {
+            polled.keys().filter(|id| !ids.contains(&***id)).cloned().collect()
+        } else {
+            log::warn!("some channels could not be resolved; not removing any channels");
+            Vec::new()
+        };
+        for id in &removed {
+            if let Some(x) = polled.remove(id) {
+                if let Err(e) = irc.part(x.user.login()).await {
+                    log::warn!("could not part chat of channel {}: {e:?}", x.user);
+                }
+            }
+        }
+
+        // the settings of a channel already polled are not changed
+        let mut added = 0;
+        for (user, settings) in channels {
+            if polled.contains_key(user.id()) {
+                continue;
+            }
+            let id: Box<str> = user.id().into();
+            if let Some(x) = join(&irc, user, settings).await {
+                polled.insert(id, x);
+                added += 1;
+            }
+        }
+
+        log::info!(
+            "reloaded subscription list: {added} channel(s) added, {} removed",
+            removed.len()
+        );
+    }
 }
 
-async fn run(argv: Argv) {
-    use futures::future::join_all;
+/// Downloads a single published VOD.
+async fn archive_vod(archiver: Arc<Archiver>, auth: HelixAuth, id: &str) {
+    let video = match helix::get_videos(auth, std::iter::once(helix::VideoFilter::Id(id)))
+        .try_next()
+        .await
+    {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            log::error!("video #{id} was not found");
+            return;
+        }
+        Err(e) => {
+            log::error!("could not fetch video #{id}: {e:?}");
+            return;
+        }
+    };
+    log::debug!("fetched video #{} ({})", video.id(), video.duration());
+
+    download_once(archiver, Stream::from(&video), Source::Vod, ChannelSettings::default()).await;
+}
+
+/// Downloads the stream of a channel that is live right now, without subscribing to events.
+async fn archive_now(archiver: Arc<Archiver>, auth: HelixAuth, irc: irc::IrcClient, login: &str) {
+    let user = match helix::get_users(&auth, &[helix::UserCredentials::Login(login)]).await {
+        Ok(x) => match x.into_iter().next() {
+            Some(x) => x,
+            None => {
+                log::error!("user {login} was not found");
+                return;
+            }
+        },
+        Err(e) => {
+            log::error!("could not retrieve user {login}: {e:?}");
+            return;
+        }
+    };
 
-    logger::init(argv.log_output, argv.log_level, argv.log_stderr);
+    let stream = match helix::get_streams(auth, std::iter::once(helix::StreamFilter::User(&user)))
+        .try_next()
+        .await
+    {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            log::error!("channel {user} is not live");
+            return;
+        }
+        Err(e) => {
+            log::error!("could not fetch stream object from endpoint: {e:?}");
+            return;
+        }
+    };
+    log::debug!("fetched stream object for stream #{}", stream.id());
+
+    let chat = match irc.join(user.login()).await {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("could not join chat for channel {user}: {e:?}");
+            return;
+        }
+    };
+
+    download_once(archiver, stream, Source::Live(chat, None), ChannelSettings::default()).await;
+}
+
+/// Prints the master playlist url of the channel's live stream, or the reason it could not be obtained.
+async fn print_hls(archiver: &Archiver, login: &str) {
+    match archiver.check_hls(login).await {
+        Ok(x) => println!("internal: {x}"),
+        Err(e) => println!("internal: could not obtain the playlist: {e:#}"),
+    }
+
+    if let Extractor::Streamlink = archiver.settings().extractor {
+        match archiver.streamlink(login).await {
+            Ok(Some(x)) => println!("streamlink: {}", x.trim_end()),
+            Ok(None) => println!("streamlink: no url was returned"),
+            Err(e) => println!("streamlink: could not obtain the playlist: {e:#}"),
+        }
+    }
+}
+
+/// Prints the renditions of the live stream of `login`, as accepted by the `format` setting.
+async fn list_formats(archiver: &Archiver, login: &str) {
+    let master = match archiver.check_hls(login).await {
+        Ok(x) => hls::master_playlist(x).await.map_err(anyhow::Error::from),
+        Err(e) => Err(e),
+    };
+    let master = match master {
+        Ok(x) => x,
+        Err(e) => {
+            println!("could not obtain the master playlist: {e:#}");
+            return;
+        }
+    };
+
+    println!("{:<16} {:<16} {:<11} {:>10} {:>6}", "name", "group_id", "resolution", "bandwidth", "fps");
+    for (alt, var) in hls::renditions(&master) {
+        let resolution = var
+            .and_then(|x| x.resolution)
+            .map_or("audio only".to_owned(), |x| format!("{}x{}", x.width, x.height));
+        let bandwidth = var.map_or("-".to_owned(), |x| x.bandwidth.to_string());
+        let fps = var
+            .and_then(|x| x.frame_rate)
+            .map_or("-".to_owned(), |x| format!("{x:.3}"));
+        println!("{:<16} {:<16} {:<11} {:>10} {:>6}", alt.name, alt.group_id, resolution, bandwidth, fps);
+    }
+}
+
+/// Checks an archive against its checksum file, printing each file which failed.
+/// Returns whether every file passed.
+async fn verify(archive: &str) -> bool {
+    let res = match checksum::verify(async_std::path::Path::new(archive)).await {
+        Ok(x) => x,
+        Err(e) => {
+            println!("could not verify {archive}: {e:#}");
+            return false;
+        }
+    };
+
+    for x in &res.mismatched {
+        println!("{x}: FAILED");
+    }
+    for x in &res.missing {
+        println!("{x}: MISSING");
+    }
+    println!(
+        "{}: {} ok, {} failed, {} missing",
+        archive,
+        res.ok,
+        res.mismatched.len(),
+        res.missing.len()
+    );
+
+    res.passed()
+}
+
+/// Archives the download directory `dir` to a tar archive, printing where it was written.
+/// Returns whether it was archived.
+async fn tar_existing(dir: &str, compress: archiver::Compression) -> bool {
+    match archiver::tar_dir(async_std::path::Path::new(dir), compress).await {
+        Ok(x) => {
+            println!("archived {dir} to {}", x.display());
+            true
+        }
+        Err(e) => {
+            eprintln!("could not archive {dir}: {e:#}");
+            false
+        }
+    }
+}
+
+/// Downloads a single stream, stopping it on shutdown.
+async fn download_once(archiver: Arc<Archiver>, stream: Stream, source: Source, settings: ChannelSettings) {
+    let (stop, stop_rx) = oneshot::channel();
+    let mut task = Box::pin(archiver.download(stream, source, settings, stop_rx));
+
+    let res = match future::select(&mut task, Box::pin(shutdown())).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => {
+            let _ = stop.send(());
+            task.await
+        }
+    };
+
+    if let Err(e) = res {
+        log::error!("download failed: {e:?}");
+    }
+}
+
+async fn run(argv: Argv) {
+    logger::init(
+        argv.log_output,
+        argv.log_level,
+        argv.log_stderr,
+        argv.log_max_size,
+        argv.log_max_files,
+        argv.log_format,
+        argv.log_filter,
+    );
 
     log::info!("twitch-archive version {} © 2023. riveroon", args::VERSION);
 
+    spawn_signal_handler();
+
+    if argv.proxy.is_some() {
+        net::set_proxy(argv.proxy);
+    }
+
+    // the shared client must not give up before a playlist or segment request does
+    net::set_timeout(time::Duration::from_secs(argv.hls_timeout).max(net::REQUEST_TIMEOUT));
+
+    hls::set_request_options(hls::RequestOptions {
+        retry: argv.hls_retry,
+        timeout: time::Duration::from_secs(argv.hls_timeout),
+    });
+
+    let archiver = Arc::new(Archiver::new(ArchiverSettings {
+        formatter: argv.fmt,
+        temp_dir: argv.temp_dir.into(),
+        min_free_bytes: argv.min_free_bytes,
+        save_to_dir: argv.save_to_dir,
+        compression: argv.compress,
+        metadata: argv.metadata,
+        extractor: argv.use_extractor,
+        stream_auth: argv.twitch_auth_header,
+        upload: argv.upload,
+        twitch_oauth: argv.twitch_oauth.map(|x| x.trim_start_matches("oauth:").to_owned()),
+        user_token: argv.user_token.as_ref().map(|x| x.trim_start_matches("oauth:").to_owned()),
+        remux: argv.remux,
+        max_concurrent: argv.max_concurrent,
+        max_concurrent_finalize: argv.max_concurrent_finalize,
+        adaptive_concurrency: argv.adaptive_concurrency,
+        post_hook: argv.post_hook,
+        keep_chat: argv.keep_chat,
+        resume: argv.resume,
+        unique_names: argv.unique_names,
+        sequence_names: argv.sequence_names,
+    }));
+
+    // inspecting the playlist only needs the playback token, so credentials are not checked for these
+    if let Some(login) = argv.print_hls {
+        print_hls(&archiver, &login).await;
+        return;
+    }
+
+    if let Some(login) = argv.list_formats {
+        list_formats(&archiver, &login).await;
+        return;
+    }
+
     let auth = match HelixAuth::new(argv.client_id, argv.client_secret).await {
         Ok(x) => x,
         Err(e) => {
@@ -574,50 +1278,111 @@ async fn run(argv: Argv) {
         }
     };
 
-    FORMATTER.set((argv.fmt, argv.save_to_dir)).unwrap();
+    if let Some(x) = &argv.user_token {
+        if let Err(e) = auth.set_user_token(x).await {
+            log::error!("error while validating user access token:\n\t{e:?}");
+            return;
+        }
+    } else if argv.device_login {
+        let cache = std::path::Path::new(&argv.user_token_cache);
+        if let Err(e) = auth.login_device(Some(cache)).await {
+            log::error!("error while obtaining user access token:\n\t{e:?}");
+            return;
+        }
+    }
+
+    if let Some(x) = argv.metrics_file {
+        metrics::spawn_file_writer(
+            x.into(),
+            time::Duration::from_secs(argv.metrics_interval),
+        );
+    }
+
+    if let Some(x) = argv.metrics_port {
+        use async_std::net::{IpAddr, Ipv4Addr, SocketAddr};
+        metrics::spawn_server(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), x));
+    }
+
+    if let Some(x) = argv.notify_url {
+        notify::init(x);
+    }
+
+    if argv.events_json {
+        events::init();
+    }
+
+    let resumable = match argv.resume {
+        true => resumable_streams(&archiver, &auth).await,
+        false => Vec::new(),
+    };
+    let ids: HashSet<Box<str>> = resumable.iter().map(|x| x.id().into()).collect();
+    if let Err(e) = recover::recover_incomplete(&archiver, argv.discard_incomplete, &ids).await {
+        log::error!("could not recover incomplete downloads: {e:?}");
+    }
 
-    EXTRACTOR.set(argv.use_extractor).unwrap();
+    if let Some(id) = argv.vod {
+        archive_vod(archiver, auth, &id).await;
+        archiver::wait_hooks().await;
+        return;
+    }
 
     let mut irc = irc::IrcClientBuilder::new();
-    let mut v: Vec<(User, IrcRecv, ChannelSettings)> = Vec::new();
+    irc.overflow(argv.chat_overflow);
+    if let (Some(nick), Some(token)) = (&argv.irc_nick, &argv.irc_token) {
+        irc.login(nick, token);
+    }
+    let irc = irc.build();
 
-    let channels: Vec<Result<(User, ChannelSettings), ()>> =
-        join_all(argv.channels.into_iter().map(|(cred, settings)| async {
-            let user = match cred {
-                UserCredentials::Full { id, login, name } => User::new(id, login, name),
-                UserCredentials::Id { id } => User::from_id(&id, &auth)
-                    .await
-                    .map_err(|e| log::error!("could not retrieve user with id {id:?}: {e:?}"))?,
-                UserCredentials::Login { login } => {
-                    User::from_login(&login, &auth).await.map_err(|e| {
-                        log::error!("could not retrieve user with login {login:?}: {e:?}")
-                    })?
-                }
-            };
-            Ok((user, settings))
-        }))
-        .await;
+    if let Some(login) = argv.now {
+        archive_now(archiver, auth, irc, &login).await;
+        archiver::wait_hooks().await;
+        return;
+    }
 
-    for (user, settings) in channels.into_iter().flatten() {
-        let rx = irc.join(user.login());
-        v.push((user, rx, settings));
+    if let Some(path) = argv.user_cache {
+        let ttl = time::Duration::from_secs(argv.user_cache_ttl);
+        let _ = USER_CACHE.set(async_std::sync::Mutex::new(helix::UserCache::load(path, ttl).await));
     }
-    irc.build();
 
-    if let Some(x) = argv.twitch_auth_header {
-        TW_STREAM_AUTH.set(x.into()).unwrap();
+    let (v, _) = resolve_users(&auth, argv.channels).await;
+    let sub_list = argv.sub_data.map(|path| SubList {
+        path,
+        single_file: argv.single_file,
+    });
+
+    if argv.offline_poll {
+        let interval = time::Duration::from_secs(argv.poll_interval);
+        poll(archiver, auth, irc, v, sub_list, interval).await;
+        log::info!("shutting down...");
+        archiver::wait_hooks().await;
+        return;
     }
 
     eventsub::wipe(&auth)
         .await
         .expect("error while wiping leftover subscriptions");
 
-    match argv.tunnel {
-        Tunnel::Provided(addr) => {
+    match (argv.transport, argv.tunnel) {
+        (TransportMode::Websocket, _) => {
+            let events = eventsub::EventSub::websocket(auth.clone());
+            archive(archiver, auth, events, false, irc, v, sub_list, resumable).await;
+        }
+        (TransportMode::Webhook, Tunnel::Provided(addr)) => {
             let public_url = addr.parse().expect("provided server address is not valid!");
-            archive(auth, argv.server_port, &public_url, v).await;
+            let events = webhook(&auth, argv.server_port, &public_url, argv.debug_callback);
+            archive(
+                archiver,
+                auth,
+                events,
+                argv.verify_callback.unwrap_or(false),
+                irc,
+                v,
+                sub_list,
+                resumable,
+            )
+            .await;
         }
-        Tunnel::Wrapper => {
+        (TransportMode::Webhook, Tunnel::Wrapper) => {
             let tunnel = ngrok::builder()
                 .https()
                 .port(argv.server_port)
@@ -628,7 +1393,18 @@ async fn run(argv: Argv) {
             let public_url = tunnel.public_url().await.unwrap();
             log::info!("ngrok tunnel started at: {public_url}");
 
-            archive(auth, argv.server_port, public_url, v).await;
+            let events = webhook(&auth, argv.server_port, public_url, argv.debug_callback);
+            archive(
+                archiver,
+                auth,
+                events,
+                argv.verify_callback.unwrap_or(true),
+                irc,
+                v,
+                sub_list,
+                resumable,
+            )
+            .await;
         }
         // Using ngrok-rs failed b/c a tunnel established with ngrok-rs
         // doesn't return the response for the first unknown requests
@@ -663,9 +1439,22 @@ async fn run(argv: Argv) {
         */
     };
     log::info!("shutting down...");
+    archiver::wait_hooks().await;
 }
 
 fn main() {
     let argv = parse_args();
+
+    // checking an archive or archiving a directory only prints its result,
+    // so these run before the logger is set up
+    if let Some(archive) = &argv.verify {
+        let passed = async_std::task::block_on(verify(archive));
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    if let Some(dir) = &argv.tar_existing {
+        let ok = async_std::task::block_on(tar_existing(dir, argv.compress));
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
     async_std::task::block_on(run(argv));
 }
@@ -0,0 +1,210 @@
+use async_std::{path, task};
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time,
+};
+
+use crate::{fs_utils, prelude::*};
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    value: AtomicU64,
+}
+
+impl Metric {
+    const fn new(name: &'static str, help: &'static str, kind: Kind) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, n: u64) {
+        self.value.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Increments the gauge, and decrements it when the returned guard is dropped.
+    pub fn track(&'static self) -> GaugeGuard {
+        self.inc();
+        GaugeGuard(self)
+    }
+}
+
+pub struct GaugeGuard(&'static Metric);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub static ACTIVE_DOWNLOADS: Metric = Metric::new(
+    "twitch_archive_active_downloads",
+    "Number of downloads currently in progress.",
+    Kind::Gauge,
+);
+pub static SEGMENTS_DOWNLOADED: Metric = Metric::new(
+    "twitch_archive_segments_downloaded_total",
+    "Number of media segments written to disk.",
+    Kind::Counter,
+);
+pub static BYTES_WRITTEN: Metric = Metric::new(
+    "twitch_archive_bytes_written_total",
+    "Number of media segment bytes written to disk.",
+    Kind::Counter,
+);
+pub static NOTIFICATIONS: Metric = Metric::new(
+    "twitch_archive_eventsub_notifications_total",
+    "Number of eventsub notifications received.",
+    Kind::Counter,
+);
+pub static AUTH_REFRESHES: Metric = Metric::new(
+    "twitch_archive_auth_refreshes_total",
+    "Number of helix authorization refreshes.",
+    Kind::Counter,
+);
+pub static EVENTSUB_SUBSCRIPTIONS: Metric = Metric::new(
+    "twitch_archive_eventsub_subscriptions",
+    "Number of eventsub subscriptions currently registered.",
+    Kind::Gauge,
+);
+pub static IRC_CONNECTED: Metric = Metric::new(
+    "twitch_archive_irc_connected",
+    "Whether the IRC connection is currently up.",
+    Kind::Gauge,
+);
+pub static CHAT_DROPPED: Metric = Metric::new(
+    "twitch_archive_chat_messages_dropped_total",
+    "Number of chat messages dropped because a channel's chat log fell behind.",
+    Kind::Counter,
+);
+
+static METRICS: [&Metric; 8] = [
+    &ACTIVE_DOWNLOADS,
+    &SEGMENTS_DOWNLOADED,
+    &BYTES_WRITTEN,
+    &NOTIFICATIONS,
+    &AUTH_REFRESHES,
+    &EVENTSUB_SUBSCRIPTIONS,
+    &IRC_CONNECTED,
+    &CHAT_DROPPED,
+];
+
+/// Renders all metrics in the prometheus text exposition format.
+pub fn render_text() -> String {
+    let mut out = String::new();
+
+    for m in METRICS {
+        let _ = writeln!(out, "# HELP {} {}", m.name, m.help);
+        let _ = writeln!(out, "# TYPE {} {}", m.name, m.kind.as_str());
+        let _ = writeln!(out, "{} {}", m.name, m.get());
+    }
+
+    out
+}
+
+pub fn render_json() -> serde_json::Result<String> {
+    #[derive(Serialize)]
+    struct MetricSer {
+        name: &'static str,
+        kind: Kind,
+        value: u64,
+    }
+
+    serde_json::to_string(
+        &METRICS
+            .iter()
+            .map(|m| MetricSer {
+                name: m.name,
+                kind: m.kind,
+                value: m.get(),
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Writes a snapshot of the metrics to `path`, replacing the previous one atomically.
+/// Paths ending in `.json` are written in json; otherwise, the prometheus text format is used.
+pub async fn write_file(path: &path::Path) -> Result<()> {
+    let body = if path.extension().is_some_and(|x| x == "json") {
+        render_json()?
+    } else {
+        render_text()
+    };
+
+    fs_utils::write_atomic(path, body.as_bytes()).await?;
+    Ok(())
+}
+
+/// Serves the metrics at `/metrics` on `addr`, in the prometheus text format.
+pub fn spawn_server(addr: std::net::SocketAddr) {
+    let mut serve = tide::new();
+    serve.at("/metrics").get(|_| async {
+        Ok(tide::Response::builder(200)
+            .content_type("text/plain; version=0.0.4")
+            .body(render_text())
+            .build())
+    });
+
+    task::Builder::new()
+        .name("metrics-server".to_owned())
+        .spawn(async move {
+            if let Err(e) = serve.listen(addr).await {
+                log::error!("metrics server at {addr:?} stopped: {e:?}");
+            }
+        })
+        .expect("cannot spawn future");
+    log::info!("serving metrics at {addr:?}");
+}
+
+pub fn spawn_file_writer(path: path::PathBuf, interval: time::Duration) {
+    task::Builder::new()
+        .name("metrics".to_owned())
+        .spawn(async move {
+            loop {
+                if let Err(e) = write_file(&path).await {
+                    log::warn!("failed to write metrics to {}: {e:?}", path.display());
+                }
+                task::sleep(interval).await;
+            }
+        })
+        .expect("cannot spawn task");
+}
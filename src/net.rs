@@ -0,0 +1,398 @@
+use async_lock::{Semaphore, SemaphoreGuardArc};
+use async_std::{
+    future::timeout,
+    io::{self, ReadExt, WriteExt},
+    net::TcpStream,
+};
+use dashmap::DashMap;
+use futures::{AsyncRead, AsyncWrite};
+use once_cell::sync::{Lazy, OnceCell};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use surf::{
+    http,
+    middleware::{Middleware, Next},
+    Client, HttpClient, Request, Response, Url,
+};
+
+/// The maximum length of the response to a `CONNECT` request.
+const MAX_CONNECT_RESPONSE: usize = 8192;
+/// The schemes of the proxies requests can be tunneled through.
+pub const PROXY_SCHEMES: &[&str] = &["http", "https"];
+/// The default timeout of a request sent through the shared client.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The number of pooled connections kept per host.
+/// Segment downloads can use up to `MAX_SEGMENT_CONCURRENCY` connections to a single CDN host.
+const MAX_CONNECTIONS_PER_HOST: usize = crate::hls::MAX_SEGMENT_CONCURRENCY * 2;
+/// The timeout of a request sent through the upload client.
+/// A request carrying a part of an upload takes far longer than other requests.
+pub const UPLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+const USER_AGENT: &str = concat!("twitch-archive/", env!("CARGO_PKG_VERSION"));
+
+static PROXY: OnceCell<Option<Url>> = OnceCell::new();
+static TIMEOUT: OnceCell<Duration> = OnceCell::new();
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::try_from(config(Some(*TIMEOUT.get_or_init(|| REQUEST_TIMEOUT))))
+        .unwrap()
+        .with(DefaultHeaders)
+});
+static UPLOAD_CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::try_from(config(Some(UPLOAD_TIMEOUT)))
+        .unwrap()
+        .with(DefaultHeaders)
+});
+
+/// Sets the proxy every request is tunneled through.
+/// Has no effect once a request has been made.
+///
+/// If not set, the `HTTPS_PROXY` environment variable is used.
+/// `NO_PROXY` is not supported; every request goes through the proxy.
+pub fn set_proxy(url: Option<Url>) {
+    let _ = PROXY.set(url);
+}
+
+/// Sets the timeout of every request sent through the shared client.
+/// Has no effect once a request has been made.
+pub fn set_timeout(timeout: Duration) {
+    let _ = TIMEOUT.set(timeout);
+}
+
+fn proxy() -> Option<&'static Url> {
+    PROXY
+        .get_or_init(|| {
+            let env = std::env::var("HTTPS_PROXY")
+                .or_else(|_| std::env::var("https_proxy"))
+                .ok()?;
+            match env.parse::<Url>() {
+                Ok(x) if PROXY_SCHEMES.contains(&x.scheme()) => Some(x),
+                Ok(x) => {
+                    log::warn!("ignoring HTTPS_PROXY {env:?} with unsupported scheme {}", x.scheme());
+                    None
+                }
+                Err(e) => {
+                    log::warn!("ignoring invalid HTTPS_PROXY {env:?}: {e}");
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// The client shared by every outbound request,
+/// so that connections are pooled across the helix api, the playlists and the segments.
+pub fn client() -> &'static Client {
+    &CLIENT
+}
+
+/// The client used for uploads, with a timeout of [`UPLOAD_TIMEOUT`] instead of the shared one.
+pub fn upload_client() -> &'static Client {
+    &UPLOAD_CLIENT
+}
+
+/// Returns a client config routed through the proxy, if one is set.
+fn config(request_timeout: Option<Duration>) -> surf::Config {
+    let config = surf::Config::new()
+        .set_timeout(request_timeout)
+        .set_max_connections_per_host(MAX_CONNECTIONS_PER_HOST);
+
+    match proxy() {
+        Some(x) => {
+            log::debug!("routing requests through proxy {x}");
+            config.set_http_client(ProxyClient {
+                proxy: x.clone(),
+                timeout: request_timeout,
+                pools: DashMap::new(),
+            })
+        }
+        None => config,
+    }
+}
+
+/// Adds the headers every request should carry, unless the request sets its own.
+#[derive(Debug)]
+struct DefaultHeaders;
+
+#[surf::utils::async_trait]
+impl Middleware for DefaultHeaders {
+    async fn handle(&self, mut req: Request, client: Client, next: Next<'_>) -> surf::Result<Response> {
+        if req.header(http::headers::USER_AGENT).is_none() {
+            req.insert_header(http::headers::USER_AGENT, USER_AGENT);
+        }
+        next.run(req, client).await
+    }
+}
+
+/// Sends each request over a `CONNECT` tunnel through an HTTP proxy.
+/// Tunnels are kept open and reused, at most [`MAX_CONNECTIONS_PER_HOST`] at once to a single host.
+#[derive(Debug)]
+struct ProxyClient {
+    proxy: Url,
+    timeout: Option<Duration>,
+    /// The tunnels to each host, keyed by `scheme://host:port`.
+    pools: DashMap<String, Arc<HostPool>>,
+}
+
+/// A connection to a host through the proxy, or to the proxy itself.
+enum Tunnel {
+    Tcp(TcpStream),
+    /// TLS to the host over a tunnel, or to an `https` proxy.
+    Tls(Box<async_tls::client::TlsStream<Tunnel>>),
+}
+
+impl Tunnel {
+    /// Whether the tunnel can be used for another request.
+    /// A closed tunnel reads the end of the stream, and one left with an unread response reads data.
+    fn is_idle(&mut self) -> bool {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        matches!(Pin::new(self).poll_read(&mut cx, &mut [0; 1]), Poll::Pending)
+    }
+}
+
+impl AsyncRead for Tunnel {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(x) => Pin::new(x).poll_read(cx, buf),
+            Self::Tls(x) => Pin::new(&mut **x).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Tunnel {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(x) => Pin::new(x).poll_write(cx, buf),
+            Self::Tls(x) => Pin::new(&mut **x).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(x) => Pin::new(x).poll_flush(cx),
+            Self::Tls(x) => Pin::new(&mut **x).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(x) => Pin::new(x).poll_close(cx),
+            Self::Tls(x) => Pin::new(&mut **x).poll_close(cx),
+        }
+    }
+}
+
+/// The tunnels to a single host.
+struct HostPool {
+    /// Limits the number of tunnels in use at once.
+    permits: Arc<Semaphore>,
+    idle: Mutex<Vec<Tunnel>>,
+}
+
+impl std::fmt::Debug for HostPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostPool")
+            .field("idle", &self.idle.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A tunnel in use by a request, returned to its pool once the response has been read.
+struct Pooled {
+    tunnel: Option<Tunnel>,
+    pool: Arc<HostPool>,
+    /// Set once reading or writing failed, or the tunnel was closed.
+    broken: bool,
+    _permit: SemaphoreGuardArc,
+}
+
+impl Pooled {
+    fn tunnel(&mut self) -> Pin<&mut Tunnel> {
+        Pin::new(self.tunnel.as_mut().expect("tunnel is only taken on drop"))
+    }
+
+    fn check<T>(&mut self, res: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if let Poll::Ready(Err(_)) = res {
+            self.broken = true;
+        }
+        res
+    }
+}
+
+impl AsyncRead for Pooled {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let res = self.tunnel().poll_read(cx, buf);
+        if let Poll::Ready(Ok(0)) = res {
+            self.broken |= !buf.is_empty();
+        }
+        self.check(res)
+    }
+}
+
+impl AsyncWrite for Pooled {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = self.tunnel().poll_write(cx, buf);
+        self.check(res)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = self.tunnel().poll_flush(cx);
+        self.check(res)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.broken = true;
+        self.tunnel().poll_close(cx)
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        let Some(tunnel) = self.tunnel.take() else { return };
+        if self.broken {
+            return;
+        }
+        let mut idle = self.pool.idle.lock().unwrap();
+        if idle.len() < MAX_CONNECTIONS_PER_HOST {
+            idle.push(tunnel);
+        }
+    }
+}
+
+impl ProxyClient {
+    /// Opens a tunnel to the host of `url`.
+    async fn tunnel(&self, url: &Url) -> http::Result<Tunnel> {
+        let proxy_addr = self
+            .proxy
+            .socket_addrs(|| Some(8080))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "proxy address not found"))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "url has no host"))?;
+        let port = url.port_or_known_default().unwrap_or(443);
+
+        let stream = TcpStream::connect(proxy_addr).await?;
+        let mut stream = match self.proxy.scheme() {
+            "https" => {
+                let proxy_host = self.proxy.host_str().unwrap_or_default();
+                let stream = async_tls::TlsConnector::default().connect(proxy_host, Tunnel::Tcp(stream)).await?;
+                Tunnel::Tls(Box::new(stream))
+            }
+            _ => Tunnel::Tcp(stream),
+        };
+
+        let mut req = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if !self.proxy.username().is_empty() {
+            let credentials = format!(
+                "{}:{}",
+                self.proxy.username(),
+                self.proxy.password().unwrap_or_default()
+            );
+            req += &format!("Proxy-Authorization: Basic {}\r\n", base64::encode(credentials));
+        }
+        req += "\r\n";
+        stream.write_all(req.as_bytes()).await?;
+
+        // read byte by byte, so that nothing past the response header is consumed
+        let mut res = Vec::new();
+        let mut byte = [0];
+        while !res.ends_with(b"\r\n\r\n") {
+            if res.len() >= MAX_CONNECT_RESPONSE || stream.read(&mut byte).await? == 0 {
+                return Err(http::Error::from_str(
+                    http::StatusCode::BadGateway,
+                    "proxy closed the connection during CONNECT",
+                ));
+            }
+            res.push(byte[0]);
+        }
+
+        let status = String::from_utf8_lossy(&res);
+        let status = status.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(http::Error::from_str(
+                http::StatusCode::BadGateway,
+                format!("proxy refused CONNECT to {host}:{port}: {status}"),
+            ));
+        }
+
+        Ok(stream)
+    }
+
+    /// Opens a tunnel to the host of `url`, with TLS if the url is `https`.
+    async fn connect(&self, url: &Url) -> http::Result<Tunnel> {
+        match url.scheme() {
+            "https" => {
+                let stream = self.tunnel(url).await?;
+                let host = url.host_str().unwrap_or_default();
+                let stream = async_tls::TlsConnector::default().connect(host, stream).await?;
+                Ok(Tunnel::Tls(Box::new(stream)))
+            }
+            "http" => self.tunnel(url).await,
+            x => Err(http::Error::from_str(
+                http::StatusCode::BadRequest,
+                format!("unsupported url scheme {x}"),
+            )),
+        }
+    }
+
+    /// Takes an idle tunnel to the host of `url`, or opens a new one.
+    async fn get(&self, url: &Url) -> http::Result<Pooled> {
+        let key = format!(
+            "{}://{}:{}",
+            url.scheme(),
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        let pool = self
+            .pools
+            .entry(key)
+            .or_insert_with(|| {
+                Arc::new(HostPool {
+                    permits: Arc::new(Semaphore::new(MAX_CONNECTIONS_PER_HOST)),
+                    idle: Mutex::new(Vec::new()),
+                })
+            })
+            .clone();
+        let permit = pool.permits.acquire_arc().await;
+
+        let idle = loop {
+            let Some(mut x) = pool.idle.lock().unwrap().pop() else { break None };
+            if x.is_idle() {
+                break Some(x);
+            }
+        };
+        let tunnel = match idle {
+            Some(x) => x,
+            None => self.connect(url).await?,
+        };
+
+        Ok(Pooled {
+            tunnel: Some(tunnel),
+            pool,
+            broken: false,
+            _permit: permit,
+        })
+    }
+
+    async fn _send(&self, req: http::Request) -> http::Result<http::Response> {
+        let url = req.url().clone();
+        let stream = self.get(&url).await?;
+        async_h1::connect(stream, req).await
+    }
+}
+
+#[async_trait::async_trait]
+impl HttpClient for ProxyClient {
+    async fn send(&self, req: http::Request) -> http::Result<http::Response> {
+        match self.timeout {
+            Some(x) => timeout(x, self._send(req)).await.map_err(|_| {
+                http::Error::from_str(http::StatusCode::RequestTimeout, "request timed out")
+            })?,
+            None => self._send(req).await,
+        }
+    }
+}
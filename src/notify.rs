@@ -0,0 +1,134 @@
+use async_std::{fs, path, task};
+use chrono::SecondsFormat;
+use futures::StreamExt;
+use once_cell::sync::OnceCell;
+use std::time::Duration;
+
+use crate::{helix::Stream, net, prelude::*, retry};
+
+const NOTIFY_RETRY: usize = 3;
+const NOTIFY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+static NOTIFY_URL: OnceCell<url::Url> = OnceCell::new();
+
+/// Sets the url given by `--notify-url`; notifications are not sent until this is called.
+pub fn init(url: url::Url) {
+    let _ = NOTIFY_URL.set(url);
+}
+
+#[derive(Serialize)]
+struct Notification {
+    event: &'static str,
+    channel: String,
+    stream_id: String,
+    title: String,
+    game: String,
+    started_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    success: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+impl Notification {
+    fn new(event: &'static str, stream: &Stream) -> Self {
+        Self {
+            event,
+            channel: stream.user().login().to_owned(),
+            stream_id: stream.id().to_owned(),
+            title: stream.title().to_owned(),
+            game: stream.game_name().to_owned(),
+            started_at: stream
+                .started_at()
+                .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            success: None,
+            path: None,
+            size: None,
+        }
+    }
+}
+
+/// Notifies that the stream went online.
+pub fn online(stream: &Stream) {
+    if NOTIFY_URL.get().is_none() {
+        return;
+    }
+
+    let body = Notification::new("online", stream);
+    task::spawn(send(body));
+}
+
+/// Notifies that the stream was archived to `path`.
+pub fn finished(stream: &Stream, path: &path::Path, success: bool) {
+    if NOTIFY_URL.get().is_none() {
+        return;
+    }
+
+    let mut body = Notification::new("finished", stream);
+    let path = path.to_path_buf();
+    task::spawn(async move {
+        body.success = Some(success);
+        body.size = size(&path)
+            .await
+            .map_err(|e| log::warn!("could not get the size of {}: {e}", path.display()))
+            .ok();
+        body.path = Some(path.to_string_lossy().into_owned());
+        send(body).await
+    });
+}
+
+async fn send(body: Notification) {
+    let Some(url) = NOTIFY_URL.get() else {
+        return;
+    };
+
+    let body = match serde_json::to_vec(&body) {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("could not serialize notification: {e:?}");
+            return;
+        }
+    };
+
+    let _ = retry::retry(
+        || async {
+            let res = net::client().post(url.as_str())
+                .body(surf::Body::from_bytes(body.clone()))
+                .content_type(surf::http::mime::JSON)
+                .await
+                .map_err(|e| e.into_inner())?;
+
+            if !res.status().is_success() {
+                return Err(anyhow!("notification was rejected with status {}", res.status()));
+            }
+            Ok(())
+        },
+        NOTIFY_RETRY_DELAY,
+        NOTIFY_RETRY,
+        "sending notification",
+    )
+    .await;
+}
+
+/// The size of the file, or the total size of the files in the directory.
+async fn size(path: &path::Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    let mut dirs = vec![path.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let meta = fs::metadata(&dir).await?;
+        if !meta.is_dir() {
+            total += meta.len();
+            continue;
+        }
+
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next().await {
+            dirs.push(entry?.path());
+        }
+    }
+
+    Ok(total)
+}
@@ -1,4 +1,5 @@
 use rand::{self, distributions::Slice, Rng};
+use std::time::Duration;
 
 pub fn rand_hex(len: usize) -> String {
     const HEX: [char; 16] = [
@@ -10,3 +11,8 @@ pub fn rand_hex(len: usize) -> String {
         .take(len)
         .collect();
 }
+
+/// Returns a random duration between zero and `max`.
+pub fn jitter(max: Duration) -> Duration {
+    max.mul_f64(rand::thread_rng().gen())
+}
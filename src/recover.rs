@@ -0,0 +1,188 @@
+use anyhow::Context;
+use async_std::{fs, path};
+use std::collections::HashSet;
+use chrono::{DateTime, Local};
+use futures::StreamExt;
+
+use crate::{
+    archiver::Archiver,
+    helix::{Stream, User},
+    lock::StreamLock,
+    prelude::*,
+};
+
+pub(crate) const LOCK_DIR: &str = "locks";
+
+/// Locks the download directory `name` in `temp_dir`,
+/// preventing it from being recovered while it is in use.
+pub async fn lock_dir(temp_dir: &path::Path, name: &str) -> Result<Option<StreamLock>> {
+    StreamLock::acquire(&temp_dir.join(LOCK_DIR), &format!("dir-{name}"))
+        .await
+        .context("cannot acquire download directory lock")
+}
+
+#[derive(Deserialize)]
+struct Info {
+    data: StreamInfo,
+}
+
+#[derive(Deserialize)]
+struct StreamInfo {
+    id: Box<str>,
+    user: UserInfo,
+    game: GameInfo,
+    title: Box<str>,
+    started_at: DateTime<Local>,
+    /// Not written by earlier versions.
+    #[serde(default)]
+    language: Box<str>,
+    #[serde(default)]
+    tags: Vec<Box<str>>,
+    #[serde(default)]
+    is_mature: bool,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    id: Box<str>,
+    login: Box<str>,
+    name: Box<str>,
+}
+
+#[derive(Deserialize)]
+struct GameInfo {
+    id: Box<str>,
+    name: Box<str>,
+}
+
+impl From<StreamInfo> for Stream {
+    fn from(value: StreamInfo) -> Self {
+        let user = User::new(value.user.id, value.user.login, value.user.name);
+        Stream::new(
+            value.id,
+            user,
+            value.game.id,
+            value.game.name,
+            value.title,
+            value.started_at,
+        )
+        .with_details(value.language, value.tags, value.is_mature)
+    }
+}
+
+/// Reads the stream a download directory belongs to from its datafile.
+async fn read_stream(dl_path: &path::Path) -> Result<Stream> {
+    let info = fs::read(dl_path.join("info.json"))
+        .await
+        .context("download directory has no datafile")?;
+    let info: Info = serde_json::from_slice(&info).context("datafile is invalid")?;
+    Ok(Stream::from(info.data))
+}
+
+/// Calls `f` with each download directory in `temp_dir` not in use, while it is locked,
+/// until `f` returns a value.
+async fn find_unlocked<T, F, Fut>(temp_dir: &path::Path, mut f: F) -> Result<Option<T>>
+where
+    F: FnMut(path::PathBuf, StreamLock) -> Fut,
+    Fut: std::future::Future<Output = Result<Option<T>>>,
+{
+    if !temp_dir.is_dir().await {
+        return Ok(None);
+    }
+
+    let mut entries = fs::read_dir(temp_dir).await?;
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == LOCK_DIR || !entry.file_type().await?.is_dir() {
+            continue;
+        }
+
+        let Some(lock) = lock_dir(temp_dir, &name).await? else {
+            log::debug!("download directory {name} is in use; skipping");
+            continue;
+        };
+
+        if let Some(x) = f(entry.path(), lock).await? {
+            return Ok(Some(x));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Lists the streams whose download directories were left behind by a previous run.
+pub async fn incomplete_streams(archiver: &Archiver) -> Result<Vec<Stream>> {
+    let streams = std::cell::RefCell::new(Vec::new());
+    find_unlocked(&archiver.settings().temp_dir, |path, _lock| {
+        let streams = &streams;
+        async move {
+            match read_stream(&path).await {
+                Ok(x) => streams.borrow_mut().push(x),
+                Err(e) => log::debug!("skipping download directory {}: {e:?}", path.display()),
+            }
+            Ok(None::<()>)
+        }
+    })
+    .await?;
+    Ok(streams.into_inner())
+}
+
+/// Finds the download directory of the stream `id` left behind by a previous run, locking it.
+pub(crate) async fn find_dir(temp_dir: &path::Path, id: &str) -> Result<Option<(path::PathBuf, StreamLock)>> {
+    find_unlocked(temp_dir, |path, lock| async move {
+        Ok(match read_stream(&path).await {
+            Ok(x) if x.id() == id => Some((path, lock)),
+            _ => None,
+        })
+    })
+    .await
+}
+
+/// Finalizes the temporary download directories left behind by a previous run,
+/// archiving them the same way a finished download is.
+/// If `discard` is set, the directories are deleted instead.
+///
+/// Directories locked by a running instance are left untouched,
+/// as are those of the streams in `resumable`, which are continued when the stream is downloaded again.
+pub async fn recover_incomplete(archiver: &Archiver, discard: bool, resumable: &HashSet<Box<str>>) -> Result<()> {
+    find_unlocked(&archiver.settings().temp_dir, |path, _lock| async move {
+        if let Ok(x) = read_stream(&path).await {
+            if resumable.contains(x.id()) {
+                log::info!("keeping download of stream #{} in {} to resume it", x.id(), path.display());
+                return Ok(None);
+            }
+        }
+
+        if discard {
+            log::info!("discarding incomplete download {}", path.display());
+            fs::remove_dir_all(&path).await?;
+            return Ok(None);
+        }
+
+        if let Err(e) = recover(archiver, &path).await {
+            log::error!("could not recover incomplete download {}: {e:?}", path.display());
+        }
+        Ok(None::<()>)
+    })
+    .await
+    .map(drop)
+}
+
+async fn recover(archiver: &Archiver, dl_path: &path::Path) -> Result<()> {
+    let stream = read_stream(dl_path).await?;
+
+    log::info!(
+        "recovering incomplete download of stream #{} for channel {}",
+        stream.id(),
+        stream.user()
+    );
+
+    // the channel settings are not known anymore, so the defaults are used
+    let settings = archiver.settings();
+    let filename = settings.filename(&settings.formatter, &stream);
+    archiver
+        .archive_dir(dl_path, path::Path::new(&filename), archiver.settings().save_to_dir)
+        .await
+        .map(|x| log::info!("recovered incomplete download: {}", x.display()))
+}
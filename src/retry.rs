@@ -1,5 +1,7 @@
 use futures::Future;
 
+use crate::rand;
+
 use std::{
   fmt::Debug,
   time::Duration
@@ -30,3 +32,41 @@ where
     }
     res
 }
+
+/// Retries like [`retry`], but doubles the delay after each attempt, up to `max`.
+/// Each delay is randomized between half and the full delay,
+/// so that many callers failing at once do not retry at the same time.
+pub async fn retry_backoff<F, Fut, T, E> (
+    mut f: F,
+    base: Duration,
+    max: Duration,
+    count: usize,
+    context: &str
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Debug
+{
+    assert!(count > 0);
+    let count = count - 1;
+    let mut delay = base.min(max);
+
+    for i in 0..count {
+        match f().await {
+            Ok(x) => return Ok(x),
+            Err(e) => {
+                let sleep = delay / 2 + rand::jitter(delay / 2);
+                log::debug!("{context} failed - retrying in {}ms ({i}): {e:?}", sleep.as_millis());
+                async_std::task::sleep(sleep).await;
+                delay = (delay * 2).min(max);
+            }
+        }
+    }
+
+    let res = f().await;
+    if let Err(ref e) = res {
+        log::error!("{context} failed - aborting: {e:?}");
+    }
+    res
+}
@@ -0,0 +1,403 @@
+//! Uploads archives to an S3-compatible bucket, signing requests with AWS Signature Version 4.
+
+use anyhow::Context;
+use async_std::{fs, io, path};
+use chrono::Utc;
+use futures::{
+    channel::mpsc, future, AsyncWrite, SinkExt, StreamExt, TryStreamExt,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+use surf::{http::Method, Url};
+
+use crate::{checksum::sha256_hex, net, prelude::*, retry::retry_backoff};
+
+/// The size of each part of a multipart upload.
+/// Every part but the last must be at least 5 MiB.
+const PART_SIZE: usize = 16 * 1024 * 1024;
+/// The number of parts buffered while an earlier part is being uploaded.
+const PART_BUFFER: usize = 1;
+/// The number of attempts made to upload each part.
+const PART_RETRY: usize = 3;
+const PART_RETRY_WAIT: Duration = Duration::from_secs(1);
+const PART_RETRY_MAX_WAIT: Duration = Duration::from_secs(10);
+
+/// What a [`PartWriter`] sends to its upload.
+enum Part {
+    Data(Vec<u8>),
+    /// Everything was written; the upload is only completed after this is received.
+    End,
+}
+
+/// An S3-compatible bucket, addressed in path style (`<endpoint>/<bucket>/<key>`).
+pub struct Bucket {
+    endpoint: Url,
+    name: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+/// Where archives are uploaded to.
+#[derive(Debug)]
+pub struct Destination {
+    pub bucket: Bucket,
+    /// Prepended to the key of every uploaded object.
+    pub prefix: String,
+    /// Archives are also kept on local disk, and uploaded once written.
+    pub keep_local: bool,
+}
+
+impl std::fmt::Debug for Bucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the keys are left out, so that they are never logged
+        f.debug_struct("Bucket")
+            .field("endpoint", &self.endpoint.as_str())
+            .field("name", &self.name)
+            .field("region", &self.region)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Bucket {
+    pub fn new(endpoint: Url, name: String, region: String, access_key: String, secret_key: String) -> Self {
+        Self {
+            endpoint,
+            name,
+            region,
+            access_key,
+            secret_key,
+        }
+    }
+
+    /// The url of the object at `key`.
+    pub fn url(&self, key: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.endpoint.as_str().trim_end_matches('/'),
+            self.name,
+            key
+        )
+    }
+
+    /// Sends a signed request for the object at `key`, returning the response if it was successful.
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<surf::Response> {
+        let path = format!("/{}/{}", uri_encode(&self.name, true), uri_encode(key, false));
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&format!("{}{path}", url.path().trim_end_matches('/')));
+        url.set_query((!query.is_empty()).then_some(&*query));
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_owned(),
+        };
+        let now = Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload = sha256_hex(&body);
+
+        let canonical = format!(
+            "{method}\n{}\n{query}\nhost:{host}\nx-amz-content-sha256:{payload}\nx-amz-date:{timestamp}\n\n\
+            host;x-amz-content-sha256;x-amz-date\n{payload}",
+            url.path()
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            sha256_hex(canonical.as_bytes())
+        );
+
+        let signing_key = [date.as_str(), &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_key).into_bytes(), |k, x| hmac(&k, x.as_bytes()));
+        let signature: String = hmac(&signing_key, string_to_sign.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        let req = surf::RequestBuilder::new(method, url)
+            .header("x-amz-content-sha256", &payload)
+            .header("x-amz-date", &timestamp)
+            .header(
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, \
+                    SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+                    self.access_key
+                ),
+            )
+            .body(body)
+            .build();
+
+        let mut res = net::upload_client()
+            .send(req)
+            .await
+            .map_err(surf::Error::into_inner)
+            .with_context(|| format!("{method} request for object {key} failed"))?;
+
+        if !res.status().is_success() {
+            let body = res.body_string().await.unwrap_or_default();
+            return Err(anyhow!(
+                "{method} request for object {key} returned status {}: {body}",
+                res.status()
+            ));
+        }
+
+        Ok(res)
+    }
+
+    /// Uploads `body` as the object at `key`, or as a part of it if `query` names a multipart upload,
+    /// retrying failed attempts.
+    async fn put(&self, key: &str, query: &[(&str, &str)], body: Vec<u8>) -> Result<surf::Response> {
+        retry_backoff(
+            || self.send(Method::Put, key, query, body.clone()),
+            PART_RETRY_WAIT,
+            PART_RETRY_MAX_WAIT,
+            PART_RETRY,
+            &format!("upload to object {key}"),
+        )
+        .await
+    }
+
+    /// Uploads the parts received from `rx` as the object at `key`.
+    /// An object of a single part is uploaded in one request.
+    ///
+    /// Fails without uploading anything if `rx` closes before [`Part::End`] is received,
+    /// as the writer was dropped before all of the data was written.
+    async fn upload(&self, key: &str, mut rx: mpsc::Receiver<Part>) -> Result<()> {
+        let Some(first) = next_part(&mut rx, key).await? else {
+            self.put(key, &[], Vec::new()).await?;
+            return Ok(());
+        };
+        let Some(second) = next_part(&mut rx, key).await? else {
+            self.put(key, &[], first).await?;
+            return Ok(());
+        };
+
+        let res = self
+            .send(Method::Post, key, &[("uploads", "")], Vec::new())
+            .await?
+            .body_string()
+            .await
+            .map_err(surf::Error::into_inner)?;
+        let upload_id = xml_value(&res, "UploadId")
+            .ok_or_else(|| anyhow!("multipart upload response has no UploadId: {res}"))?
+            .to_owned();
+
+        let parts = async {
+            let mut etags = Vec::new();
+            let mut pending = vec![second, first];
+            loop {
+                let part = match pending.pop() {
+                    Some(x) => x,
+                    None => match next_part(&mut rx, key).await? {
+                        Some(x) => x,
+                        None => break,
+                    },
+                };
+                let n = (etags.len() + 1).to_string();
+                let res = self
+                    .put(key, &[("partNumber", &n), ("uploadId", &upload_id)], part)
+                    .await?;
+                let etag = res
+                    .header("ETag")
+                    .ok_or_else(|| anyhow!("part #{n} of object {key} has no ETag"))?
+                    .as_str()
+                    .to_owned();
+                etags.push(etag);
+            }
+            Result::<_>::Ok(etags)
+        };
+
+        let etags = match parts.await {
+            Ok(x) => x,
+            Err(e) => {
+                if let Err(e) = self.send(Method::Delete, key, &[("uploadId", &upload_id)], Vec::new()).await {
+                    log::warn!("could not abort multipart upload of object {key}: {e:?}");
+                }
+                return Err(e);
+            }
+        };
+
+        let mut body = String::from("<CompleteMultipartUpload>");
+        for (i, etag) in etags.iter().enumerate() {
+            body += &format!("<Part><PartNumber>{}</PartNumber><ETag>{etag}</ETag></Part>", i + 1);
+        }
+        body += "</CompleteMultipartUpload>";
+
+        let res = self
+            .send(Method::Post, key, &[("uploadId", &upload_id)], body.into_bytes())
+            .await?
+            .body_string()
+            .await
+            .map_err(surf::Error::into_inner)?;
+        // the request can fail after a successful status
+        if res.contains("<Error>") {
+            return Err(anyhow!("could not complete multipart upload of object {key}: {res}"));
+        }
+
+        Ok(())
+    }
+
+    /// Returns a writer whose contents are uploaded as the object at `key`,
+    /// along with the upload, which finishes after the writer is closed.
+    pub fn writer<'a>(&'a self, key: &'a str) -> (PartWriter, impl futures::Future<Output = Result<()>> + 'a) {
+        let (tx, rx) = mpsc::channel(PART_BUFFER);
+        let writer = PartWriter {
+            tx,
+            buf: Vec::with_capacity(PART_SIZE),
+            finished: false,
+        };
+        (writer, self.upload(key, rx))
+    }
+
+    /// Uploads the file at `path` as the object at `key`.
+    pub async fn put_file(&self, path: &path::Path, key: &str) -> Result<()> {
+        let file = fs::File::open(path)
+            .await
+            .with_context(|| format!("could not open {}", path.display()))?;
+        let (mut writer, upload) = self.writer(key);
+        let copy = async move {
+            futures::io::copy(file, &mut writer).await?;
+            futures::AsyncWriteExt::close(&mut writer).await?;
+            Result::<()>::Ok(())
+        };
+        future::try_join(copy, upload).await.map(drop)
+    }
+
+    /// Uploads every file below the directory `dir`, keyed by their path relative to it, after `key`.
+    pub async fn put_dir(&self, dir: &path::Path, key: &str) -> Result<()> {
+        let mut stack = vec![dir.to_path_buf()];
+        while let Some(x) = stack.pop() {
+            let mut entries = fs::read_dir(&x).await?;
+            while let Some(entry) = entries.try_next().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    stack.push(path);
+                    continue;
+                }
+
+                let rel: Vec<_> = path
+                    .strip_prefix(dir)?
+                    .iter()
+                    .map(|x| x.to_string_lossy())
+                    .collect();
+                self.put_file(&path, &format!("{key}/{}", rel.join("/"))).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receives the next part, or `None` once the writer has finished.
+async fn next_part(rx: &mut mpsc::Receiver<Part>, key: &str) -> Result<Option<Vec<u8>>> {
+    match rx.next().await {
+        Some(Part::Data(x)) => Ok(Some(x)),
+        Some(Part::End) => Ok(None),
+        None => Err(anyhow!("upload of object {key} stopped before its writer was closed")),
+    }
+}
+
+/// Splits everything written to it into parts of [`PART_SIZE`], sent to an upload.
+/// The upload is only completed once the writer is closed.
+pub struct PartWriter {
+    tx: mpsc::Sender<Part>,
+    buf: Vec<u8>,
+    /// The end of the data was sent to the upload.
+    finished: bool,
+}
+
+fn upload_stopped(_: mpsc::SendError) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "upload stopped")
+}
+
+impl PartWriter {
+    fn poll_send(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        futures::ready!(self.tx.poll_ready(cx)).map_err(upload_stopped)?;
+        let part = std::mem::replace(&mut self.buf, Vec::with_capacity(PART_SIZE));
+        self.tx.start_send(Part::Data(part)).map_err(upload_stopped)?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_finish(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        futures::ready!(self.tx.poll_ready(cx)).map_err(upload_stopped)?;
+        self.tx.start_send(Part::End).map_err(upload_stopped)?;
+        self.finished = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PartWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        if self.buf.len() >= PART_SIZE {
+            futures::ready!(self.poll_send(cx))?;
+        }
+
+        let n = data.len().min(PART_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    /// Parts are only sent once full, as every part but the last has a minimum size.
+    fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        if !self.buf.is_empty() {
+            futures::ready!(self.poll_send(cx))?;
+        }
+        if !self.finished {
+            futures::ready!(self.poll_finish(cx))?;
+        }
+        self.tx.poll_close_unpin(cx).map_err(upload_stopped)
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything but the unreserved characters, as required for signing.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b'/' if !encode_slash => out.push('/'),
+            b => out += &format!("%{b:02X}"),
+        }
+    }
+    out
+}
+
+/// Finds the text of the first `<tag>` element.
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let len = xml[start..].find(&format!("</{tag}>"))?;
+    Some(&xml[start..start + len])
+}
@@ -0,0 +1,167 @@
+//! Delivers webhook notifications to the callback server, subscribed against a local mock of the twitch api.
+
+use async_std::task;
+use hmac::{Hmac, Mac};
+use once_cell::sync::OnceCell;
+use serde_json::json;
+use sha2::Sha256;
+use std::sync::Mutex;
+use tide::{Request, Response, StatusCode};
+
+use twitch_archive::{
+    api::{self, Endpoints},
+    eventsub::event::stream::{Online, OnlineCond},
+    EventSub, HelixAuth, Subscription,
+};
+
+const CLIENT_ID: &str = "mock-client";
+const CLIENT_SECRET: &str = "mock-secret";
+
+/// The secret of the last subscription created on the mock.
+static SECRET: Mutex<Option<String>> = Mutex::new(None);
+
+/// Starts the mock server once, pointing every test at it.
+fn mock() {
+    static STARTED: OnceCell<()> = OnceCell::new();
+    STARTED.get_or_init(|| {
+        let mut app = tide::new();
+
+        app.at("/oauth2/token").post(|_: Request<()>| async move {
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(json!({
+                "access_token": "mock-token",
+                "expires_in": 3600,
+                "token_type": "bearer"
+            }));
+            Ok(res)
+        });
+
+        app.at("/helix/eventsub/subscriptions").post(|mut req: Request<()>| async move {
+            let body: serde_json::Value = req.body_json().await?;
+            *SECRET.lock().unwrap() = body["transport"]["secret"].as_str().map(str::to_owned);
+
+            let mut res = Response::new(StatusCode::Accepted);
+            res.set_body(json!({
+                "data": [{
+                    "id": "sub-1",
+                    "status": "enabled",
+                    "condition": body["condition"],
+                    "created_at": "2024-01-01T12:00:00Z",
+                    "cost": 1
+                }],
+                "total_cost": 1,
+                "max_total_cost": 10000
+            }));
+            Ok(res)
+        });
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(app.listen(listener));
+
+        api::set_endpoints(Endpoints {
+            helix: format!("http://{addr}/helix"),
+            id: format!("http://{addr}/oauth2"),
+            ..Endpoints::default()
+        });
+    });
+}
+
+/// Starts a callback server subscribed to `stream.online`,
+/// returning the subscription, the callback url and the secret the subscription was created with.
+async fn subscribe() -> (EventSub, Subscription<Online>, String, String) {
+    let auth = HelixAuth::new(CLIENT_ID.to_owned(), CLIENT_SECRET.to_owned())
+        .await
+        .unwrap();
+
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let url: url::Url = format!("http://{addr}/").parse().unwrap();
+    let events = EventSub::new(addr, &url, auth, false);
+
+    let sub = events.subscribe::<Online>(OnlineCond::from_id("1234")).await.unwrap();
+    let secret = SECRET.lock().unwrap().clone().unwrap();
+    (events, sub, format!("{url}callback"), secret)
+}
+
+fn sign(secret: &str, id: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(id.as_bytes());
+    mac.update(timestamp.as_bytes());
+    mac.update(body);
+    let sig: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={sig}")
+}
+
+/// Sends a `stream.online` notification to `callback`, returning the response status.
+async fn notify(callback: &str, id: &str, timestamp: &str, signature: impl FnOnce(&[u8]) -> String) -> StatusCode {
+    let body = json!({
+        "subscription": { "id": "sub-1" },
+        "event": {
+            "id": "9001",
+            "broadcaster_user_id": "1234",
+            "broadcaster_user_login": "mock",
+            "broadcaster_user_name": "Mock",
+            "type": "live",
+            "started_at": "2024-01-01T12:00:00Z"
+        }
+    })
+    .to_string();
+    let signature = signature(body.as_bytes());
+
+    // the server is started in the background, so it may not be listening yet
+    let mut n = 0;
+    loop {
+        let req = surf::post(callback)
+            .header("Twitch-Eventsub-Message-Id", id)
+            .header("Twitch-Eventsub-Message-Type", "notification")
+            .header("Twitch-Eventsub-Message-Timestamp", timestamp)
+            .header("Twitch-Eventsub-Message-Signature", &signature)
+            .body_string(body.clone());
+        match req.await {
+            Ok(x) => return x.status(),
+            Err(_) if n < 50 => {
+                n += 1;
+                task::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            Err(e) => panic!("could not reach the callback server: {e}"),
+        }
+    }
+}
+
+// the mock records the secret of a single subscription, so the cases share one test
+#[test]
+fn notifications() {
+    mock();
+    task::block_on(async {
+        let (_events, sub, callback, secret) = subscribe().await;
+        let now = chrono::Utc::now().to_rfc3339();
+
+        // a bad signature
+        let status = notify(&callback, "msg-1", &now, |_| "sha256=00".to_owned()).await;
+        assert_eq!(status, StatusCode::Unauthorized);
+
+        // a signature made with another secret
+        let status = notify(&callback, "msg-2", &now, |body| sign("wrong", "msg-2", &now, body)).await;
+        assert_eq!(status, StatusCode::Unauthorized);
+
+        // a correctly signed replay of an old message
+        let stale = (chrono::Utc::now() - chrono::Duration::minutes(30)).to_rfc3339();
+        let status = notify(&callback, "msg-3", &stale, |body| sign(&secret, "msg-3", &stale, body)).await;
+        assert_eq!(status, StatusCode::BadRequest);
+
+        // a valid message is delivered
+        let status = notify(&callback, "msg-4", &now, |body| sign(&secret, "msg-4", &now, body)).await;
+        assert_eq!(status, StatusCode::Ok);
+        let event = sub.recv().await.unwrap().unwrap();
+        assert_eq!(event.id(), "9001");
+        assert_eq!(event.user().login(), "mock");
+    });
+}
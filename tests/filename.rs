@@ -0,0 +1,47 @@
+//! Parses and formats file name formats.
+
+use chrono::{Local, TimeZone};
+
+use twitch_archive::{filename::FormatParseError, Formatter, Stream, User};
+
+fn stream() -> Stream {
+    Stream::new(
+        "40001",
+        User::new("1234", "mock", "Mock"),
+        "509658",
+        "Just Chatting",
+        "hello",
+        Local.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap(),
+    )
+}
+
+#[test]
+fn escaped_percent() {
+    let fmt = Formatter::new("%Sl 100%% %si%%").unwrap();
+    assert_eq!(fmt.format(&stream()), "mock 100% 40001%");
+}
+
+#[test]
+fn trailing_percent() {
+    assert_eq!(
+        Formatter::new("%Sl %").unwrap_err(),
+        [FormatParseError::Incomplete { offset: 4 }]
+    );
+    // a single symbol character is incomplete as well
+    assert_eq!(
+        Formatter::new("%Sl %S").unwrap_err(),
+        [FormatParseError::Incomplete { offset: 4 }]
+    );
+}
+
+#[test]
+fn unknown_symbols() {
+    // every unknown symbol is reported, not only the first
+    assert_eq!(
+        Formatter::new("%xx %Sl %Qq").unwrap_err(),
+        [
+            FormatParseError::UnknownSymbol { offset: 0, symbol: "xx".to_owned() },
+            FormatParseError::UnknownSymbol { offset: 8, symbol: "Qq".to_owned() },
+        ]
+    );
+}
@@ -0,0 +1,286 @@
+//! Runs the helix client against a local mock of the twitch api.
+
+use async_std::task;
+use futures::TryStreamExt;
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde_json::json;
+use tide::{Request, Response, StatusCode};
+
+use twitch_archive::{
+    api::{self, Endpoints},
+    helix::{get_streams, StreamFilter},
+    HelixAuth, User,
+};
+
+const CLIENT_ID: &str = "mock-client";
+const CLIENT_SECRET: &str = "mock-secret";
+const USER_TOKEN: &str = "mock-user-token";
+
+/// The body sent by the subscription tests, which the mock echoes back.
+fn subscription_body() -> serde_json::Value {
+    json!({
+        "type": "stream.online",
+        "version": "1",
+        "condition": { "broadcaster_user_id": "1234" },
+        "transport": { "method": "webhook", "callback": "https://example.com/callback", "secret": "s3cr3t!!s3cr3t!!" }
+    })
+}
+
+fn user_json(id: &str, login: &str) -> serde_json::Value {
+    json!({
+        "id": id,
+        "login": login,
+        "display_name": login.to_uppercase(),
+        "type": "",
+        "broadcaster_type": "partner",
+        "description": "",
+        "profile_image_url": "",
+        "offline_image_url": "",
+        "created_at": "2016-12-14T20:32:28Z"
+    })
+}
+
+fn authorized(req: &Request<()>) -> bool {
+    req.header("Authorization").map(|x| x.as_str()) == Some("Bearer mock-token")
+        && req.header("Client-Id").map(|x| x.as_str()) == Some(CLIENT_ID)
+}
+
+/// Starts the mock server once, pointing every test at it.
+fn mock() {
+    static STARTED: OnceCell<()> = OnceCell::new();
+    STARTED.get_or_init(|| {
+        let mut app = tide::new();
+
+        app.at("/oauth2/token").post(|mut req: Request<()>| async move {
+            let body = req.body_string().await?;
+            if !body.contains(&format!("client_secret={CLIENT_SECRET}")) {
+                let mut res = Response::new(StatusCode::Forbidden);
+                res.set_body(json!({ "status": 403, "message": "invalid client secret" }));
+                return Ok(res);
+            }
+
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(json!({
+                "access_token": "mock-token",
+                "expires_in": 3600,
+                "token_type": "bearer"
+            }));
+            Ok(res)
+        });
+
+        app.at("/oauth2/validate").get(|req: Request<()>| async move {
+            if req.header("Authorization").map(|x| x.as_str()) != Some(&*format!("OAuth {USER_TOKEN}")) {
+                return Ok(Response::new(StatusCode::Unauthorized));
+            }
+
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(json!({ "client_id": CLIENT_ID, "login": "mock", "scopes": ["chat:read"] }));
+            Ok(res)
+        });
+
+        // echoes the request body, to check that it was sent
+        async fn echo(mut req: Request<()>) -> tide::Result {
+            let body: serde_json::Value = req.body_json().await?;
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(body);
+            Ok(res)
+        }
+
+        app.at("/helix/eventsub/subscriptions").post(|req: Request<()>| async move {
+            if !authorized(&req) {
+                return Ok(Response::new(StatusCode::Unauthorized));
+            }
+            echo(req).await
+        });
+
+        // rejects the first request, so that it is sent again with a refreshed token
+        app.at("/helix/eventsub/retry").post(|req: Request<()>| async move {
+            static REJECTED: AtomicBool = AtomicBool::new(false);
+            if !authorized(&req) || !REJECTED.swap(true, Ordering::Relaxed) {
+                return Ok(Response::new(StatusCode::Unauthorized));
+            }
+            echo(req).await
+        });
+
+        app.at("/helix/eventsub/user").post(|req: Request<()>| async move {
+            if req.header("Authorization").map(|x| x.as_str()) != Some(&*format!("Bearer {USER_TOKEN}")) {
+                return Ok(Response::new(StatusCode::Unauthorized));
+            }
+            echo(req).await
+        });
+
+        app.at("/helix/users").get(|req: Request<()>| async move {
+            if !authorized(&req) {
+                return Ok(Response::new(StatusCode::Unauthorized));
+            }
+
+            let data: Vec<_> = req
+                .url()
+                .query_pairs()
+                .filter(|(k, _)| k == "login")
+                .map(|(_, login)| user_json("1234", &login))
+                .collect();
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(json!({ "data": data }));
+            Ok(res)
+        });
+
+        app.at("/helix/streams").get(|req: Request<()>| async move {
+            if !authorized(&req) {
+                return Ok(Response::new(StatusCode::Unauthorized));
+            }
+
+            let query = |key: &str| {
+                req.url()
+                    .query_pairs()
+                    .find(|(k, _)| k == key)
+                    .map(|(_, x)| x.into_owned())
+            };
+            // every page is requested with the same size
+            if query("first").as_deref() != Some("100") {
+                return Ok(Response::new(StatusCode::BadRequest));
+            }
+
+            let after = query("after");
+            // two pages, to exercise the pagination
+            let (id, cursor) = match after.as_deref() {
+                None => ("1", "page-2"),
+                Some("page-2") => ("2", ""),
+                Some(_) => return Ok(Response::new(StatusCode::BadRequest)),
+            };
+
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(json!({
+                "data": [{
+                    "id": id,
+                    "user_id": "1234",
+                    "user_login": "mock",
+                    "user_name": "Mock",
+                    "game_id": "509658",
+                    "game_name": "Just Chatting",
+                    "type": "live",
+                    "title": format!("stream #{id}"),
+                    "started_at": "2024-01-01T12:00:00Z",
+                    "is_mature": false
+                }],
+                "pagination": { "cursor": cursor }
+            }));
+            Ok(res)
+        });
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(app.listen(listener));
+
+        api::set_endpoints(Endpoints {
+            helix: format!("http://{addr}/helix"),
+            id: format!("http://{addr}/oauth2"),
+            ..Endpoints::default()
+        });
+    });
+}
+
+#[test]
+fn auth_token() {
+    mock();
+    task::block_on(async {
+        let auth = HelixAuth::new(CLIENT_ID.to_owned(), CLIENT_SECRET.to_owned())
+            .await
+            .unwrap();
+        assert_eq!(auth.auth().await, "Bearer mock-token");
+        assert_eq!(auth.client_id().await, CLIENT_ID);
+    });
+}
+
+#[test]
+fn auth_rejected() {
+    mock();
+    task::block_on(async {
+        let res = HelixAuth::new(CLIENT_ID.to_owned(), "wrong".to_owned()).await;
+        assert!(res.is_err());
+    });
+}
+
+#[test]
+fn user_from_login() {
+    mock();
+    task::block_on(async {
+        let auth = HelixAuth::new(CLIENT_ID.to_owned(), CLIENT_SECRET.to_owned())
+            .await
+            .unwrap();
+        let user = User::from_login("mock", &auth).await.unwrap();
+        assert_eq!(user.id(), "1234");
+        assert_eq!(user.login(), "mock");
+        assert_eq!(user.name(), "MOCK");
+    });
+}
+
+#[test]
+fn streams_are_paginated() {
+    mock();
+    task::block_on(async {
+        let auth = HelixAuth::new(CLIENT_ID.to_owned(), CLIENT_SECRET.to_owned())
+            .await
+            .unwrap();
+        let user = User::new("1234", "mock", "Mock");
+        let streams: Vec<_> = get_streams(auth, [StreamFilter::User(&user)])
+            .try_collect()
+            .await
+            .unwrap();
+
+        let ids: Vec<_> = streams.iter().map(|x| x.id()).collect();
+        assert_eq!(ids, ["1", "2"]);
+        assert_eq!(streams[0].title(), "stream #1");
+        assert_eq!(streams[0].user().login(), "mock");
+    });
+}
+
+async fn post_subscription(auth: &HelixAuth, path: &str, user: bool) -> serde_json::Value {
+    let req = surf::post(format!("{}/{path}", api::endpoints().helix))
+        .body_json(&subscription_body())
+        .unwrap()
+        .build();
+    let res = match user {
+        true => auth.send_user_req(req).await,
+        false => auth.send_req(req).await,
+    };
+    res.unwrap().body_json().await.unwrap()
+}
+
+#[test]
+fn request_body_is_sent() {
+    mock();
+    task::block_on(async {
+        let auth = HelixAuth::new(CLIENT_ID.to_owned(), CLIENT_SECRET.to_owned())
+            .await
+            .unwrap();
+        let body = post_subscription(&auth, "eventsub/subscriptions", false).await;
+        assert_eq!(body, subscription_body());
+    });
+}
+
+#[test]
+fn request_body_is_sent_again_on_retry() {
+    mock();
+    task::block_on(async {
+        let auth = HelixAuth::new(CLIENT_ID.to_owned(), CLIENT_SECRET.to_owned())
+            .await
+            .unwrap();
+        let body = post_subscription(&auth, "eventsub/retry", false).await;
+        assert_eq!(body, subscription_body());
+    });
+}
+
+#[test]
+fn user_request_body_is_sent() {
+    mock();
+    task::block_on(async {
+        let auth = HelixAuth::new(CLIENT_ID.to_owned(), CLIENT_SECRET.to_owned())
+            .await
+            .unwrap();
+        auth.set_user_token(USER_TOKEN).await.unwrap();
+        let body = post_subscription(&auth, "eventsub/user", true).await;
+        assert_eq!(body, subscription_body());
+    });
+}
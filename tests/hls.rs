@@ -0,0 +1,224 @@
+//! Downloads playlists served by a local mock of the CDN.
+
+use async_std::{fs, path::PathBuf, task};
+use futures::{channel::{mpsc, oneshot}, future, StreamExt};
+use once_cell::sync::OnceCell;
+use tide::{Request, Response, StatusCode};
+
+use twitch_archive::hls::{self, DownloadOptions};
+
+/// Starts the mock server once, returning its base url.
+fn mock() -> &'static str {
+    static ADDR: OnceCell<String> = OnceCell::new();
+    ADDR.get_or_init(|| {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+
+        let mut app = tide::with_state(base.clone());
+
+        // a video rendition, and a separate audio rendition with its own playlist
+        app.at("/master.m3u8").get(|req: Request<String>| async move {
+            let base = req.state();
+            Ok(playlist(format!(
+                "#EXTM3U\n\
+                 #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"chunked\",NAME=\"1080p60\",AUTOSELECT=YES,DEFAULT=YES\n\
+                 #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",NAME=\"English\",AUTOSELECT=YES,DEFAULT=YES,URI=\"{base}/audio.m3u8\"\n\
+                 #EXT-X-STREAM-INF:BANDWIDTH=6000000,RESOLUTION=1920x1080,VIDEO=\"chunked\",AUDIO=\"aac\"\n\
+                 {base}/video.m3u8\n"
+            )))
+        });
+
+        app.at("/video.m3u8").get(|_: Request<String>| async move {
+            Ok(playlist(
+                "#EXTM3U\n\
+                 #EXT-X-TARGETDURATION:2\n\
+                 #EXT-X-MEDIA-SEQUENCE:0\n\
+                 #EXT-X-MAP:URI=\"init.mp4\"\n\
+                 #EXTINF:2.000,\n\
+                 v0.mp4\n\
+                 #EXTINF:2.000,\n\
+                 v1.mp4\n\
+                 #EXT-X-ENDLIST\n",
+            ))
+        });
+
+        app.at("/audio.m3u8").get(|_: Request<String>| async move {
+            Ok(playlist(
+                "#EXTM3U\n\
+                 #EXT-X-TARGETDURATION:2\n\
+                 #EXT-X-MEDIA-SEQUENCE:0\n\
+                 #EXTINF:2.000,\n\
+                 a0.aac\n\
+                 #EXTINF:2.000,\n\
+                 a1.aac\n\
+                 #EXT-X-ENDLIST\n",
+            ))
+        });
+
+        // a stream which is still live, so the download only ends when stopped
+        app.at("/live.m3u8").get(|_: Request<String>| async move {
+            Ok(playlist(
+                "#EXTM3U\n\
+                 #EXT-X-TARGETDURATION:2\n\
+                 #EXT-X-MEDIA-SEQUENCE:0\n\
+                 #EXTINF:2.000,\n\
+                 l0.ts\n\
+                 #EXTINF:2.000,\n\
+                 l1.ts\n",
+            ))
+        });
+
+        app.at("/:segment").get(|req: Request<String>| async move {
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(format!("segment {}", req.param("segment")?).into_bytes());
+            Ok(res)
+        });
+
+        task::spawn(app.listen(listener));
+        base
+    })
+}
+
+fn playlist(body: impl Into<String>) -> Response {
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(body.into());
+    res.set_content_type("application/vnd.apple.mpegurl");
+    res
+}
+
+/// Creates an empty directory for the output of a test.
+fn dest(name: &str) -> PathBuf {
+    let dest = std::env::temp_dir().join(format!("twitch-archive-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dest);
+    std::fs::create_dir_all(&dest).unwrap();
+    dest.into()
+}
+
+#[test]
+fn init_segment_map_is_written() {
+    let base = mock();
+    let dest = dest("map");
+    task::block_on(async {
+        let (_tx, stop) = oneshot::channel();
+        let path = hls::download_media(
+            format!("{base}/video.m3u8"),
+            &dest,
+            "video",
+            &DownloadOptions::default(),
+            stop,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let playlist = fs::read_to_string(path).await.unwrap();
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"video/init-00000.mp4\""), "{playlist}");
+        assert_eq!(fs::read(dest.join("video/init-00000.mp4")).await.unwrap(), b"segment init.mp4");
+    });
+}
+
+#[test]
+fn stopped_download_ends_playlist() {
+    let base = mock();
+    let dest = dest("stop");
+    task::block_on(async {
+        let (tx, stop) = oneshot::channel();
+        tx.send(()).unwrap();
+        let path = hls::download_media(
+            format!("{base}/live.m3u8"),
+            &dest,
+            "live",
+            &DownloadOptions::default(),
+            stop,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let playlist = fs::read_to_string(path).await.unwrap();
+        assert!(playlist.trim_end().ends_with("#EXT-X-ENDLIST"), "{playlist}");
+    });
+}
+
+#[test]
+fn separate_audio_rendition() {
+    let base = mock();
+    let dest = dest("audio");
+    task::block_on(async {
+        let (_tx, stop) = oneshot::channel();
+        let streams = hls::download(
+            format!("{base}/master.m3u8"),
+            &dest,
+            &["best", "audio"],
+            &DownloadOptions::default(),
+            stop,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let names: Vec<&str> = streams.iter().map(|(_, alt, _)| alt.name.as_str()).collect();
+        assert_eq!(names, ["1080p60", "English"]);
+
+        // the audio rendition is downloaded from its own playlist, not from the video variant
+        let (path, _, var) = &streams[1];
+        assert!(var.is_none());
+        assert_eq!(path, &dest.join("English/English.m3u8"));
+        assert_eq!(fs::read(dest.join("English/English/00000.ts")).await.unwrap(), b"segment a0.aac");
+    });
+}
+
+#[test]
+fn remuxed_timeline_points_at_output() {
+    let base = mock();
+    let dest = dest("remux");
+    task::block_on(async {
+        let (_tx, stop) = oneshot::channel();
+        hls::download_media(
+            format!("{base}/video.m3u8"),
+            &dest,
+            "video",
+            &DownloadOptions::default(),
+            stop,
+            None,
+        )
+        .await
+        .unwrap();
+
+        hls::remux_timeline(&dest, "video.mp4").await.unwrap();
+
+        let timeline: serde_json::Value =
+            serde_json::from_slice(&fs::read(dest.join("timeline.json")).await.unwrap()).unwrap();
+        let segments = timeline["segments"].as_array().unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1]["path"], "video.mp4");
+        assert_eq!(segments[1]["start"], 2.0);
+        assert!(segments[1].get("offset").is_none());
+    });
+}
+
+#[test]
+fn progress_ends_with_latest_report() {
+    let base = mock();
+    let dest = dest("progress");
+    task::block_on(async {
+        let (_tx, stop) = oneshot::channel();
+        // reports which do not fit are coalesced, but the last one is always delivered
+        let (tx, rx) = mpsc::channel(0);
+        let opts = DownloadOptions::default();
+        let download = hls::download_media(
+            format!("{base}/video.m3u8"),
+            &dest,
+            "video",
+            &opts,
+            stop,
+            Some(tx),
+        );
+        let (res, reports) = future::join(download, rx.collect::<Vec<_>>()).await;
+        res.unwrap();
+
+        let last = reports.last().unwrap();
+        assert_eq!(last.index, 1);
+        assert_eq!(last.bytes, (b"segment v0.mp4".len() * 2) as u64);
+    });
+}